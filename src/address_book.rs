@@ -0,0 +1,263 @@
+use crate::playback::unix_millis;
+use crate::store::write_atomic;
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Peers kept in the book; the lowest ranked are forgotten first.
+const MAX_PEERS: usize = 256;
+/// Addresses kept per peer.
+const MAX_ADDRESSES: usize = 8;
+/// Peers not seen for this long are forgotten.
+const MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// The peers we have been connected to and their addresses, kept in the home
+/// directory so a node can rejoin the network without a bootnode.
+///
+/// Only addresses we dialed successfully are kept: what peers report through
+/// identify is unverified and goes to Kademlia alone, so a bogus address is
+/// never dialed again on every start. Peers are ranked by how often dialing
+/// them succeeded and by when we last saw them.
+pub struct AddressBook {
+  path: PathBuf,
+  peers: HashMap<PeerId, PeerEntry>,
+  /// Whether there are changes not written to disk yet.
+  dirty: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct PeerEntry {
+  /// Addresses we dialed the peer on, most recently confirmed first.
+  pub addresses: Vec<Multiaddr>,
+  /// Milliseconds since the Unix epoch.
+  pub last_seen: u64,
+  pub successes: u32,
+  pub failures: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredPeer {
+  peer_id: Vec<u8>,
+  addresses: Vec<Vec<u8>>,
+  last_seen: u64,
+  successes: u32,
+  failures: u32,
+}
+
+impl PeerEntry {
+  /// Share of the attempts to reach the peer that succeeded, starting at one half.
+  pub fn success_rate(&self) -> f64 {
+    f64::from(self.successes + 1) / f64::from(self.successes + self.failures + 2)
+  }
+
+  fn cmp_rank(&self, other: &PeerEntry) -> Ordering {
+    other
+      .success_rate()
+      .partial_cmp(&self.success_rate())
+      .unwrap_or(Ordering::Equal)
+      .then(other.last_seen.cmp(&self.last_seen))
+  }
+}
+
+impl AddressBook {
+  /// Opens the address book of the node in `home_path`.
+  ///
+  /// An unreadable book is replaced by an empty one rather than keeping the node from starting.
+  pub fn open(home_path: &Path) -> Self {
+    let path = home_path.join("peers");
+    let peers = match fs::read(&path) {
+      Ok(data) => decode(&data).unwrap_or_else(|| {
+        println!("Cannot decode the address book {}, starting afresh", path.display());
+        HashMap::new()
+      }),
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+      Err(e) => {
+        println!("Cannot read the address book {}: {}", path.display(), e);
+        HashMap::new()
+      }
+    };
+    let mut book = AddressBook {
+      path,
+      peers,
+      dirty: false,
+    };
+    book.prune();
+    book
+  }
+
+  /// Returns the peers with their addresses, best first.
+  pub fn ranked(&self) -> Vec<(&PeerId, &PeerEntry)> {
+    let mut peers: Vec<_> = self.peers.iter().collect();
+    peers.sort_by(|(_, a), (_, b)| a.cmp_rank(b));
+    peers
+  }
+
+  pub fn addresses_of_peer(&self, peer_id: &PeerId) -> Vec<Multiaddr> {
+    self
+      .peers
+      .get(peer_id)
+      .map(|entry| entry.addresses.clone())
+      .unwrap_or_default()
+  }
+
+  /// Records that we are connected to `peer_id`, through `address` if we dialed it.
+  ///
+  /// Only a connection we dialed counts as a success and verifies the address;
+  /// an inbound one just tells us a peer we know is still around.
+  pub fn connected(&mut self, peer_id: &PeerId, address: Option<&Multiaddr>) {
+    let address = match address {
+      Some(address) => address,
+      None => {
+        if self.peers.contains_key(peer_id) {
+          self.entry(peer_id);
+        }
+        return;
+      }
+    };
+    let entry = self.entry(peer_id);
+    entry.successes = entry.successes.saturating_add(1);
+    entry.addresses.retain(|a| a != address);
+    entry.addresses.insert(0, address.clone());
+    entry.addresses.truncate(MAX_ADDRESSES);
+  }
+
+  /// Records that none of the addresses of a peer we know could be dialed.
+  pub fn dial_failed(&mut self, peer_id: &PeerId) {
+    if let Some(entry) = self.peers.get_mut(peer_id) {
+      entry.failures = entry.failures.saturating_add(1);
+      self.dirty = true;
+    }
+  }
+
+  fn entry(&mut self, peer_id: &PeerId) -> &mut PeerEntry {
+    self.dirty = true;
+    let entry = self.peers.entry(peer_id.clone()).or_insert_with(|| PeerEntry {
+      addresses: Vec::new(),
+      last_seen: 0,
+      successes: 0,
+      failures: 0,
+    });
+    entry.last_seen = unix_millis(SystemTime::now());
+    entry
+  }
+
+  /// Forgets the peers without addresses, those not seen for too long and the
+  /// lowest ranked beyond `MAX_PEERS`.
+  fn prune(&mut self) {
+    let oldest = unix_millis(SystemTime::now()).saturating_sub(MAX_AGE.as_millis() as u64);
+    let before = self.peers.len();
+    self
+      .peers
+      .retain(|_, entry| !entry.addresses.is_empty() && entry.last_seen >= oldest);
+    if self.peers.len() > MAX_PEERS {
+      let dropped: Vec<PeerId> = self.ranked()[MAX_PEERS..]
+        .iter()
+        .map(|(peer_id, _)| (*peer_id).clone())
+        .collect();
+      for peer_id in dropped {
+        self.peers.remove(&peer_id);
+      }
+    }
+    self.dirty |= self.peers.len() != before;
+  }
+
+  /// Writes the book to disk if it changed.
+  pub fn save(&mut self) {
+    if !self.dirty {
+      return;
+    }
+    self.prune();
+    match write_atomic(&self.path, &encode(&self.peers)) {
+      Ok(()) => self.dirty = false,
+      Err(e) => println!("Cannot save the address book {}: {}", self.path.display(), e),
+    }
+  }
+}
+
+fn encode(peers: &HashMap<PeerId, PeerEntry>) -> Vec<u8> {
+  let stored: Vec<StoredPeer> = peers
+    .iter()
+    .map(|(peer_id, entry)| StoredPeer {
+      peer_id: peer_id.as_bytes().to_vec(),
+      addresses: entry.addresses.iter().map(|a| a.to_vec()).collect(),
+      last_seen: entry.last_seen,
+      successes: entry.successes,
+      failures: entry.failures,
+    })
+    .collect();
+  bincode::serialize(&stored).expect("The address book can always be serialized")
+}
+
+/// Decodes the book, leaving out the peers and addresses that do not parse.
+fn decode(data: &[u8]) -> Option<HashMap<PeerId, PeerEntry>> {
+  let stored: Vec<StoredPeer> = bincode::deserialize(data).ok()?;
+  let peers = stored
+    .into_iter()
+    .filter_map(|peer| {
+      let peer_id = PeerId::from_bytes(peer.peer_id).ok()?;
+      let entry = PeerEntry {
+        addresses: peer
+          .addresses
+          .into_iter()
+          .filter_map(|a| Multiaddr::try_from(a).ok())
+          .collect(),
+        last_seen: peer.last_seen,
+        successes: peer.successes,
+        failures: peer.failures,
+      };
+      Some((peer_id, entry))
+    })
+    .collect();
+  Some(peers)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn book(name: &str) -> (AddressBook, PathBuf) {
+    let home = std::env::temp_dir().join(format!("radiopeer-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&home).unwrap();
+    let _ = fs::remove_file(home.join("peers"));
+    (AddressBook::open(&home), home)
+  }
+
+  #[test]
+  fn inbound_connections_are_not_successes() {
+    let (mut book, home) = book("inbound");
+    let peer_id = PeerId::random();
+    book.connected(&peer_id, None);
+    assert!(book.ranked().is_empty());
+    let address: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+    book.connected(&peer_id, Some(&address));
+    book.connected(&peer_id, None);
+    let ranked = book.ranked();
+    assert_eq!(ranked.len(), 1);
+    assert_eq!(ranked[0].1.successes, 1);
+    assert_eq!(ranked[0].1.addresses, vec![address]);
+    let _ = fs::remove_dir_all(home);
+  }
+
+  #[test]
+  fn only_dialed_addresses_are_kept() {
+    let (mut book, home) = book("dialed");
+    let dialed = PeerId::random();
+    let inbound = PeerId::random();
+    let address: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+    book.connected(&dialed, Some(&address));
+    book.connected(&inbound, None);
+    book.dial_failed(&inbound);
+    book.save();
+    let book = AddressBook::open(&home);
+    assert_eq!(book.addresses_of_peer(&dialed), vec![address]);
+    assert!(book.addresses_of_peer(&inbound).is_empty());
+    assert_eq!(book.ranked().len(), 1);
+    let _ = fs::remove_dir_all(home);
+  }
+}
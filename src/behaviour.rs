@@ -1,19 +1,44 @@
+use crate::address_book::AddressBook;
+use crate::broadcast::{
+  station_topic, station_topic_hash, BroadcastError, BroadcastMessage, ControlMessage,
+  ReplayGuard,
+};
+use crate::exchange::{BlockExchange, ExchangeEvent};
+use crate::history::History;
+use crate::liveness::{Liveness, LivenessEvent, PeerLiveness};
+use crate::manifest::{Manifest, ManifestError, SignedManifest, SongHash, Stations};
+use crate::playback::{unix_millis, PlaybackPosition};
+use crate::query::{PendingQueries, RecordConfig, RecordOptions, ValueQuery};
+use crate::records::DiskStore;
+use crate::reputation::{Ban, Misbehaviour, Reputation};
+use crate::store::ChunkStore;
+use crate::successor::{SignedSuccessor, SuccessorError};
+use crate::timesync::{ClockEstimate, TimeSync, TimeSyncEvent};
 use futures::prelude::*;
 use futures03::{compat::Compat, TryFutureExt as _};
 use futures_timer::Delay;
-use libp2p::kad::record::{self, store::MemoryStore};
-use libp2p::kad::{GetClosestPeersError, KademliaConfig};
+use libp2p::kad::handler::{KademliaHandlerEvent, KademliaHandlerQueryErr};
+use libp2p::kad::record;
+use libp2p::kad::{GetClosestPeersError, GetProvidersError, GetRecordError, KademliaConfig};
 use libp2p::kad::{Kademlia, KademliaEvent, Quorum, Record};
 use libp2p::{
-  core::{either::EitherOutput, ConnectedPoint, PeerId, PublicKey},
+  core::{either::EitherOutput, identity, ConnectedPoint, PeerId},
+  floodsub::{Floodsub, FloodsubEvent, TopicHash},
   identify::{Identify, IdentifyEvent, IdentifyInfo},
+  mdns::{Mdns, MdnsEvent},
   swarm::{IntoProtocolsHandler, IntoProtocolsHandlerSelect, PollParameters, ProtocolsHandler},
-  swarm::{NetworkBehaviour, NetworkBehaviourAction},
+  swarm::{toggle::Toggle, NetworkBehaviour, NetworkBehaviourAction, ProtocolsHandlerUpgrErr},
   tokio_io::{AsyncRead, AsyncWrite},
   Multiaddr,
 };
 use std::cmp;
-use std::time::Duration;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Peers of the address book added to the routing table on start-up.
+const MAX_REJOIN_PEERS: usize = 64;
 
 pub struct Behaviour<TSubstream> {
   next_kad_random_query: Compat<Delay>,
@@ -21,11 +46,41 @@ pub struct Behaviour<TSubstream> {
   num_connections: u64,
   /// Periodically identifies the remote and responds to incoming requests.
   identify: Identify<TSubstream>,
-  kademlia: Kademlia<TSubstream, MemoryStore>,
+  /// Signs the messages we publish on live channels.
+  local_key: identity::Keypair,
+  kademlia: Kademlia<TSubstream, DiskStore>,
+  /// Exchanges song chunks with other peers.
+  exchange: BlockExchange<TSubstream>,
+  /// Live channels of the stations we listen to or broadcast on.
+  floodsub: Floodsub<TSubstream>,
+  /// Station of every floodsub topic we are subscribed to.
+  channels: HashMap<TopicHash, PeerId>,
+  /// Orders the live channel messages we send and drops those replayed to us.
+  replay_guard: ReplayGuard,
+  /// Latest verified manifests of the stations we follow.
+  stations: Stations,
+  /// Log of every manifest accepted, kept across restarts.
+  history: History,
+  /// Estimates the clock offset to our peers, so listeners agree on the playback position.
+  timesync: TimeSync<TSubstream>,
+  /// Handles returned by `get_value` that wait for their lookup to finish.
+  queries: PendingQueries,
+  records: RecordConfig,
+  /// Peers we have been connected to, kept across restarts.
+  address_book: AddressBook,
+  /// Finds peers on the local network, if enabled.
+  mdns: Toggle<Mdns<TSubstream>>,
+  /// Pings the connected peers and disconnects those we do not use.
+  liveness: Liveness<TSubstream>,
+  /// Scores the peers and keeps the ones that misbehave away.
+  reputation: Reputation,
+  /// Events to emit before polling the sub-behaviours again.
+  pending_events: VecDeque<AllEvents>,
 }
 
 /// Event that can be emitted by the behaviour.
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum AllEvents {
   /// We have obtained debug information from a peer, including the addresses it is listening on.
   Identified {
@@ -35,6 +90,15 @@ pub enum AllEvents {
     info: IdentifyInfo,
   },
   DiscoveryOut(DiscoveryOutT),
+  Exchange(ExchangeEvent),
+  /// A message was received on the live channel of a station.
+  Broadcast {
+    station: PeerId,
+    /// Admin that signed the message.
+    source: PeerId,
+    message: BroadcastMessage,
+  },
+  Liveness(LivenessEvent),
 }
 
 #[derive(Debug)]
@@ -62,25 +126,109 @@ pub enum DiscoveryOutT {
 
   /// Inserting a value into the DHT failed.
   ValuePutFailed(record::Key),
+
+  /// A newer manifest of a followed station was fetched and verified.
+  ManifestFound(PeerId, Manifest),
+
+  /// A manifest fetched for a followed station was rejected.
+  ManifestRejected(PeerId, ManifestError),
+
+  /// A proposed update of a station was fetched and verified, with the number
+  /// of admins that signed it and the number that must.
+  ProposalFound(PeerId, usize, usize),
+
+  /// No acceptable proposal was found for a station.
+  ProposalNotFound(PeerId),
+
+  /// A key we watch was found to be rotated to a new key, which is trusted in its place.
+  SuccessorFound(PeerId, PeerId),
+
+  /// No valid successor record was found for a key we watch.
+  SuccessorNotFound(PeerId),
+
+  /// The DHT yielded the peers that announced they can serve a song.
+  ///
+  /// The list may be empty or partial if the lookup timed out.
+  ProvidersFound(SongHash, Vec<PeerId>),
 }
 
 impl<TSubstream> Behaviour<TSubstream> {
-  pub fn new(user_agent: String, local_public_key: PublicKey) -> Self {
+  #[allow(clippy::too_many_arguments)]
+  pub fn new(
+    user_agent: String,
+    local_key: identity::Keypair,
+    chunk_store: Arc<ChunkStore>,
+    record_store: DiskStore,
+    records: RecordConfig,
+    address_book: AddressBook,
+    reputation: Reputation,
+    history: History,
+    enable_mdns: bool,
+    idle_timeout: Option<Duration>,
+  ) -> Self {
     let identify = {
       let proto_version = "/radiopeer/0.1.0".to_string();
-      Identify::new(proto_version, user_agent, local_public_key.clone())
+      Identify::new(proto_version, user_agent, local_key.public())
+    };
+    let local_peer_id = local_key.public().into_peer_id();
+    let mdns = if enable_mdns {
+      match Mdns::new() {
+        Ok(mdns) => Some(mdns),
+        Err(e) => {
+          println!("Cannot start mDNS discovery: {}", e);
+          None
+        }
+      }
+    } else {
+      None
     };
-    let local_peer_id = local_public_key.clone().into_peer_id();
     let mut cfg = KademliaConfig::default();
     cfg.set_query_timeout(Duration::from_secs(5 * 60));
-    let store = MemoryStore::new(local_peer_id.clone());
-    Behaviour {
+    let mut behaviour = Behaviour {
       next_kad_random_query: Delay::new(Duration::new(0, 0)).compat(),
       duration_to_next_kad: Duration::from_secs(1),
       num_connections: 0,
       identify,
-      kademlia: Kademlia::with_config(local_peer_id.clone(), store, cfg),
+      local_key,
+      kademlia: Kademlia::with_config(local_peer_id.clone(), record_store, cfg),
+      exchange: BlockExchange::new(chunk_store),
+      floodsub: Floodsub::new(local_peer_id.clone()),
+      channels: HashMap::new(),
+      replay_guard: ReplayGuard::default(),
+      stations: Stations::default(),
+      history,
+      timesync: TimeSync::new(),
+      queries: PendingQueries::default(),
+      records,
+      address_book,
+      mdns: Toggle::from(mdns),
+      liveness: Liveness::new(idle_timeout),
+      reputation,
+      pending_events: VecDeque::new(),
+    };
+    // Rejoin the network through the peers we knew, best first.
+    let known: Vec<(PeerId, Vec<Multiaddr>)> = behaviour
+      .address_book
+      .ranked()
+      .into_iter()
+      .take(MAX_REJOIN_PEERS)
+      .map(|(peer_id, entry)| (peer_id.clone(), entry.addresses.clone()))
+      .collect();
+    for (peer_id, addresses) in known {
+      for addr in addresses {
+        behaviour.kademlia.add_address(&peer_id, addr);
+      }
+    }
+    // Announce the songs we can already serve in full.
+    match behaviour.chunk_store().complete_songs() {
+      Ok(songs) => {
+        for song in songs {
+          behaviour.start_providing(&song);
+        }
+      }
+      Err(e) => println!("Cannot list the songs in the chunk store: {}", e),
     }
+    behaviour
   }
 
   /// Returns the list of nodes that we know exist in the network.
@@ -95,14 +243,464 @@ impl<TSubstream> Behaviour<TSubstream> {
         "Adding peer: {:?} at address: {:?} to kademlia",
         peer_id, addr
       );
-      self.add_self_reported_address(&peer_id, addr.clone());
+      self.add_self_reported_address(peer_id, addr.clone());
     }
+    self.floodsub.add_node_to_partial_view(peer_id.clone());
   }
 
+  /// Stores a value in the DHT with the options of `RecordConfig::value`.
+  ///
+  /// The result is delivered as `DiscoveryOutT::ValuePut` or `ValuePutFailed`.
   pub fn put_value(&mut self, key: record::Key, value: Vec<u8>) {
+    let options = self.records.value.clone();
+    self.put_value_with(key, value, &options);
+  }
+
+  /// Stores a value in the DHT with the given quorum and time to live.
+  pub fn put_value_with(&mut self, key: record::Key, value: Vec<u8>, options: &RecordOptions) {
+    let mut record = Record::new(key, value);
+    record.expires = options.ttl.map(|ttl| Instant::now() + ttl);
+    self.kademlia.put_record(record, options.put_quorum);
+  }
+
+  pub fn record_config(&self) -> &RecordConfig {
+    &self.records
+  }
+
+  /// Starts a DHT lookup for `key`, finishing once `quorum` records are found.
+  ///
+  /// The returned handle resolves to the records found. The result is also
+  /// delivered as `DiscoveryOutT::ValueFound` or `ValueNotFound`.
+  ///
+  /// If `key` is being looked up already, the handle resolves with that lookup.
+  pub fn get_value(&mut self, key: &record::Key, quorum: Quorum) -> ValueQuery {
+    self.get_record(key, quorum);
+    self.queries.value(key.clone())
+  }
+
+  /// Starts a DHT lookup for `key`, unless one is running already.
+  fn get_record(&mut self, key: &record::Key, quorum: Quorum) {
+    if self.queries.start(key) {
+      self.kademlia.get_record(key, quorum);
+    }
+  }
+
+  /// Publishes a signed manifest of `station` to the DHT.
+  ///
+  /// The manifest is also kept as the latest version we hold, so it has to pass
+  /// the same checks as a manifest fetched from the network.
+  pub fn publish_manifest(
+    &mut self,
+    station: &PeerId,
+    signed: &SignedManifest,
+  ) -> Result<(), ManifestError> {
+    let key = self.stations.follow(station.clone());
+    self.stations.accept(station, signed)?;
+    self.record_history(station, signed);
+    let options = self.records.manifest.clone();
+    self.put_value_with(key, signed.to_bytes(), &options);
+    Ok(())
+  }
+
+  /// Adds the signatures of `signed` to the update proposed for `station`, and
+  /// publishes the proposal to the DHT for the other admins to sign.
+  ///
+  /// The admins listening on the live channel of the station are told to fetch
+  /// it. Returns the number of admins that signed the proposal and the number
+  /// that must, after which it can be published with `publish_manifest`.
+  pub fn propose_manifest(
+    &mut self,
+    station: &PeerId,
+    signed: &SignedManifest,
+  ) -> Result<(usize, usize), ManifestError> {
+    let key = self.stations.watch_proposal(station.clone());
+    let res = self.stations.propose(station, signed)?;
+    let proposal = self.stations.proposal(station).expect("just proposed");
+    let (_, manifest) = proposal.open()?;
+    let value = proposal.to_bytes();
+    let options = self.records.manifest.clone();
+    self.put_value_with(key, value, &options);
+    let message = BroadcastMessage::Control(ControlMessage::ProposalUpdated {
+      version: manifest.version,
+    });
+    if let Err(e) = self.broadcast(station, &message) {
+      println!("Cannot announce the proposal: {}", e);
+    }
+    Ok(res)
+  }
+
+  /// Starts a DHT lookup for the update proposed for `station`.
+  ///
+  /// The result is delivered as `DiscoveryOutT::ProposalFound` or `ProposalNotFound`.
+  pub fn fetch_proposal(&mut self, station: &PeerId) {
+    let key = self.stations.watch_proposal(station.clone());
+    self.get_record(&key, self.records.manifest.get_quorum);
+  }
+
+  /// Returns the update proposed for `station` with the signatures we collected.
+  pub fn proposal(&self, station: &PeerId) -> Option<&SignedManifest> {
+    self.stations.proposal(station)
+  }
+
+  /// Starts a DHT lookup for the manifest of `station`.
+  ///
+  /// The result is delivered as `DiscoveryOutT::ManifestFound` or `ManifestRejected`.
+  pub fn fetch_manifest(&mut self, station: &PeerId) {
+    let key = self.stations.follow(station.clone());
+    self.get_record(&key, self.records.manifest.get_quorum);
+  }
+
+  /// Publishes the record of one of our key rotations to the DHT, with the
+  /// options of the manifests.
+  ///
+  /// The rotation is also trusted locally, so a manifest signed with the new key
+  /// is accepted for a station created with the old one.
+  pub fn publish_successor(&mut self, signed: &SignedSuccessor) -> Result<(), SuccessorError> {
+    let (old, new) = signed.verify()?;
+    let key = self.stations.watch_successor(old.clone());
+    self.stations.add_successor(old, new);
+    let options = self.records.manifest.clone();
+    self.put_value_with(key, signed.to_bytes(), &options);
+    Ok(())
+  }
+
+  /// Starts a DHT lookup for the key `peer_id` was rotated to.
+  ///
+  /// The result is delivered as `DiscoveryOutT::SuccessorFound` or `SuccessorNotFound`.
+  pub fn fetch_successor(&mut self, peer_id: &PeerId) {
+    let key = self.stations.watch_successor(peer_id.clone());
+    self.get_record(&key, self.records.manifest.get_quorum);
+  }
+
+  /// Returns the latest verified manifest we hold for `station`.
+  pub fn manifest(&self, station: &PeerId) -> Option<&Manifest> {
+    self.stations.get(station)
+  }
+
+  /// Returns the track and offset `station` is playing right now.
+  ///
+  /// Every listener holding the same manifest version gets the same answer, as
+  /// long as it agrees with the others on the time; see `network_time`.
+  pub fn playback_position(&self, station: &PeerId) -> Option<PlaybackPosition> {
+    self.stations.get(station)?.position_at(self.network_time())
+  }
+
+  /// Returns our local clock corrected by the median offset to the connected peers.
+  pub fn network_time(&self) -> SystemTime {
+    self.timesync.network_time()
+  }
+
+  /// Returns the estimated offset and round-trip time to the clock of `peer_id`.
+  pub fn clock_estimate(&self, peer_id: &PeerId) -> Option<&ClockEstimate> {
+    self.timesync.estimate(peer_id)
+  }
+
+  /// Returns the median round-trip time to `peer_id`, measured with ping.
+  pub fn rtt(&self, peer_id: &PeerId) -> Option<Duration> {
+    self.liveness.rtt(peer_id)
+  }
+
+  /// Returns the round-trip times and idle time of a connected peer.
+  pub fn liveness(&self, peer_id: &PeerId) -> Option<&PeerLiveness> {
+    self.liveness.peer(peer_id)
+  }
+
+  /// Sorts `peers` from the lowest round-trip time to the highest, the peers
+  /// never pinged last.
+  pub fn sort_by_rtt(&self, peers: &mut [PeerId]) {
+    self.liveness.sort_by_rtt(peers)
+  }
+
+  /// Closes the connection to `peer_id`, if we are connected.
+  pub fn disconnect(&mut self, peer_id: &PeerId) {
+    self.liveness.disconnect(peer_id)
+  }
+
+  /// Returns the reputation of `peer_id`: zero in good standing, negative otherwise.
+  pub fn score(&self, peer_id: &PeerId) -> f64 {
+    self.reputation.score(peer_id)
+  }
+
+  /// Lowers the score of `peer_id`, disconnecting it if that gets it banned.
+  pub fn report(&mut self, peer_id: &PeerId, misbehaviour: Misbehaviour) {
+    if self.reputation.report(peer_id, misbehaviour) {
+      println!("Banning {} after {:?}", peer_id, misbehaviour);
+      self.liveness.disconnect(peer_id);
+    }
+  }
+
+  /// Bans `peer_id` until `unban_peer` is called, and disconnects it.
+  ///
+  /// The ban is kept in the home directory. Returns false if it was already banned by hand.
+  pub fn ban_peer(&mut self, peer_id: &PeerId) -> io::Result<bool> {
+    let banned = self.reputation.ban_manually(peer_id)?;
+    self.liveness.disconnect(peer_id);
+    Ok(banned)
+  }
+
+  /// Lifts any ban on `peer_id`, returning false if it was not banned.
+  pub fn unban_peer(&mut self, peer_id: &PeerId) -> io::Result<bool> {
+    self.reputation.unban(peer_id)
+  }
+
+  /// Returns the banned peers, by hand and for misbehaving.
+  pub fn banned_peers(&self) -> Vec<(PeerId, Ban)> {
+    self.reputation.banned()
+  }
+
+  /// Penalizes the peer that sent a Kademlia message for what we can tell is wrong with it.
+  ///
+  /// The publisher of a record is not authenticated, so bad manifests are held
+  /// against the peer that handed them to us.
+  fn check_kademlia_event<T>(&mut self, peer_id: &PeerId, event: &KademliaHandlerEvent<T>) {
+    let misbehaviour = match event {
+      KademliaHandlerEvent::GetRecordRes {
+        record: Some(record),
+        ..
+      }
+      | KademliaHandlerEvent::PutRecord { record, .. } => {
+        if self.stations.rotated_of(&record.key).is_some() {
+          match SignedSuccessor::from_bytes(&record.value).and_then(|signed| signed.verify()) {
+            Err(SuccessorError::Io(_)) | Err(SuccessorError::Signing(_)) | Ok(_) => return,
+            Err(_) => Misbehaviour::InvalidSignature,
+          }
+        } else if self.stations.proposal_of(&record.key).is_some() {
+          match SignedManifest::from_bytes(&record.value).and_then(|signed| signed.signers()) {
+            Err(ManifestError::Decode(_))
+            | Err(ManifestError::InvalidSigner)
+            | Err(ManifestError::InvalidSignature) => Misbehaviour::InvalidSignature,
+            _ => return,
+          }
+        } else if self.stations.station_of(&record.key).is_some() {
+          match SignedManifest::from_bytes(&record.value).and_then(|signed| signed.verify(None)) {
+            Err(ManifestError::Decode(_))
+            | Err(ManifestError::InvalidSigner)
+            | Err(ManifestError::InvalidSignature) => Misbehaviour::InvalidSignature,
+            // Admins change, so a manifest signed by a former one is not malicious.
+            _ => return,
+          }
+        } else {
+          return;
+        }
+      }
+      KademliaHandlerEvent::QueryError { error, .. } => match error {
+        KademliaHandlerQueryErr::Upgrade(ProtocolsHandlerUpgrErr::Timeout) => Misbehaviour::Timeout,
+        KademliaHandlerQueryErr::Upgrade(_) | KademliaHandlerQueryErr::Io(_) => return,
+        KademliaHandlerQueryErr::UnexpectedMessage => Misbehaviour::ProtocolError,
+      },
+      _ => return,
+    };
+    self.report(peer_id, misbehaviour);
+  }
+
+  /// Verifies the records found for a station key, keeping the newest acceptable manifest.
+  fn handle_manifest_records(&mut self, station: PeerId, values: Vec<Vec<u8>>) -> DiscoveryOutT {
+    let mut accepted = false;
+    let mut last_err = None;
+    for value in values {
+      let res = SignedManifest::from_bytes(&value)
+        .and_then(|signed| self.stations.accept(&station, &signed).map(|_| signed));
+      match res {
+        Ok(signed) => {
+          self.record_history(&station, &signed);
+          accepted = true
+        }
+        Err(e) => last_err = Some(e),
+      }
+    }
+    match (accepted, last_err) {
+      (false, Some(e)) => {
+        if let ManifestError::NotAnAdmin(_) = e {
+          self.fetch_admin_successors(&station);
+        }
+        DiscoveryOutT::ManifestRejected(station, e)
+      }
+      _ => {
+        let manifest = self.stations.get(&station).cloned().unwrap_or_default();
+        DiscoveryOutT::ManifestFound(station, manifest)
+      }
+    }
+  }
+
+  /// Appends a manifest accepted for `station` to the history log.
+  fn record_history(&mut self, station: &PeerId, signed: &SignedManifest) {
+    if let Err(e) = self.history.record(station, signed) {
+      println!("Cannot log the manifest of {}: {}", station, e);
+    }
+  }
+
+  /// Merges the proposals found for `station` into the one we hold.
+  fn handle_proposal_records(&mut self, station: PeerId, values: Vec<Vec<u8>>) -> DiscoveryOutT {
+    let mut found = None;
+    for value in values {
+      let res = SignedManifest::from_bytes(&value)
+        .and_then(|signed| self.stations.propose(&station, &signed));
+      match res {
+        Ok(approvals) => found = Some(approvals),
+        Err(e) => println!("Dropping proposal for {}: {}", station, e),
+      }
+    }
+    match found {
+      Some((approvals, threshold)) => DiscoveryOutT::ProposalFound(station, approvals, threshold),
+      None => DiscoveryOutT::ProposalNotFound(station),
+    }
+  }
+
+  /// Looks for the successors of the latest keys of the admins of `station`,
+  /// in case the manifest was signed by an admin that rotated its key.
+  fn fetch_admin_successors(&mut self, station: &PeerId) {
+    let admins: Vec<PeerId> = match self.stations.get(station) {
+      Some(manifest) => manifest
+        .admins
+        .iter()
+        .filter_map(|admin| PeerId::from_bytes(admin.clone()).ok())
+        .collect(),
+      None => vec![station.clone()],
+    };
+    for admin in admins {
+      let latest = self.stations.rotations(&admin).pop().expect("never empty");
+      self.fetch_successor(&latest);
+    }
+  }
+
+  /// Verifies the records found for the successor of `old`.
+  ///
+  /// A new successor is looked up in turn, for keys rotated more than once, and
+  /// the stations it may now sign for are fetched again.
+  fn handle_successor_records(&mut self, old: PeerId, values: Vec<Vec<u8>>) -> DiscoveryOutT {
+    let found = values.iter().find_map(|value| {
+      match SignedSuccessor::from_bytes(value).and_then(|signed| signed.verify()) {
+        Ok((signer, new)) if signer == old => Some(new),
+        Ok(_) => None,
+        Err(e) => {
+          println!("Dropping successor record of {}: {}", old, e);
+          None
+        }
+      }
+    });
+    let new = match found {
+      Some(new) => new,
+      None => return DiscoveryOutT::SuccessorNotFound(old),
+    };
+    if self.stations.add_successor(old.clone(), new.clone()) {
+      self.fetch_successor(&new);
+      let stations: Vec<PeerId> = self
+        .stations
+        .followed()
+        .filter(|station| match self.stations.get(station) {
+          Some(manifest) => manifest.is_admin(&old) || self.stations.rotations(station).contains(&old),
+          None => self.stations.rotations(station).contains(&old),
+        })
+        .cloned()
+        .collect();
+      for station in stations {
+        self.fetch_manifest(&station);
+      }
+    }
+    DiscoveryOutT::SuccessorFound(old, new)
+  }
+
+  /// Downloads the chunks of `song` from `providers` into the local chunk store.
+  ///
+  /// The providers with the lowest round-trip time are asked first, and keep
+  /// being preferred as the round-trip times change. Progress is reported
+  /// through `AllEvents::Exchange`.
+  pub fn fetch_song(&mut self, song: SongHash, mut providers: Vec<PeerId>) {
+    providers.retain(|p| !self.reputation.is_banned(p));
+    self.liveness.sort_by_rtt(&mut providers);
+    self.exchange.fetch(song, providers);
+  }
+
+  /// Stops downloading `song` and cancels the outstanding requests.
+  pub fn cancel_song(&mut self, song: &[u8]) {
+    self.exchange.cancel(song);
+  }
+
+  /// Announces in the DHT that this node can serve the chunks of `song`.
+  ///
+  /// Songs are announced automatically once they are complete in the chunk store.
+  pub fn start_providing(&mut self, song: &[u8]) {
+    self.kademlia.start_providing(record::Key::new(&song));
+  }
+
+  /// Stops announcing `song`; other peers forget us once the provider records expire.
+  pub fn stop_providing(&mut self, song: &[u8]) {
+    self.kademlia.stop_providing(&record::Key::new(&song));
+  }
+
+  /// Starts a DHT lookup for the peers that can serve `song`.
+  ///
+  /// The result is delivered as `DiscoveryOutT::ProvidersFound`.
+  pub fn find_providers(&mut self, song: &[u8]) {
+    self.kademlia.get_providers(record::Key::new(&song));
+  }
+
+  /// Starts listening to the live channel of `station`.
+  ///
+  /// Returns false if we were already subscribed.
+  pub fn subscribe_station(&mut self, station: &PeerId) -> bool {
     self
-      .kademlia
-      .put_record(Record::new(key, value), Quorum::All);
+      .channels
+      .insert(station_topic_hash(station), station.clone());
+    self.floodsub.subscribe(station_topic(station))
+  }
+
+  pub fn unsubscribe_station(&mut self, station: &PeerId) -> bool {
+    let topic = station_topic(station);
+    self.channels.remove(topic.hash());
+    self.floodsub.unsubscribe(topic)
+  }
+
+  /// Publishes a message on the live channel of `station`.
+  ///
+  /// The message is signed with the local key; listeners drop messages that
+  /// are not signed by one of the station admins.
+  pub fn broadcast(
+    &mut self,
+    station: &PeerId,
+    message: &BroadcastMessage,
+  ) -> Result<(), BroadcastError> {
+    let sent_at = self.replay_guard.next_sent_at(unix_millis(SystemTime::now()));
+    let data = message.sign(station, sent_at, &self.local_key)?;
+    self.floodsub.publish(station_topic_hash(station), data);
+    Ok(())
+  }
+
+  /// Turns a floodsub message into a broadcast event if it is signed by an admin of the station.
+  ///
+  /// `source` is only the unsigned sender claimed by floodsub, used in logs.
+  fn handle_broadcast(
+    &mut self,
+    source: PeerId,
+    data: &[u8],
+    topics: &[TopicHash],
+  ) -> Option<AllEvents> {
+    let station = topics.iter().find_map(|t| self.channels.get(t))?.clone();
+    let (signer, sent_at, message) = match BroadcastMessage::open(&station, data) {
+      Ok(opened) => opened,
+      Err(e) => {
+        println!("Dropping broadcast for {} from {}: {}", station, source, e);
+        return None;
+      }
+    };
+    if !self.stations.authorizes(&station, &signer) {
+      println!("Dropping broadcast for {} signed by non-admin {}", station, signer);
+      return None;
+    }
+    let now = unix_millis(SystemTime::now());
+    if let Err(e) = self.replay_guard.check(&station, &signer, sent_at, now) {
+      println!("Dropping broadcast for {} from {}: {}", station, signer, e);
+      return None;
+    }
+    Some(AllEvents::Broadcast {
+      station,
+      source: signer,
+      message,
+    })
+  }
+
+  pub fn chunk_store(&self) -> &Arc<ChunkStore> {
+    self.exchange.store()
   }
 
   pub fn add_self_reported_address(&mut self, peer_id: &PeerId, addr: Multiaddr) {
@@ -115,40 +713,141 @@ where
   TSubstream: AsyncRead + AsyncWrite,
 {
   type ProtocolsHandler = IntoProtocolsHandlerSelect<
-    <Kademlia<TSubstream, MemoryStore> as NetworkBehaviour>::ProtocolsHandler,
-    <Identify<TSubstream> as NetworkBehaviour>::ProtocolsHandler,
+    IntoProtocolsHandlerSelect<
+      IntoProtocolsHandlerSelect<
+        IntoProtocolsHandlerSelect<
+          IntoProtocolsHandlerSelect<
+            IntoProtocolsHandlerSelect<
+              <Kademlia<TSubstream, DiskStore> as NetworkBehaviour>::ProtocolsHandler,
+              <Identify<TSubstream> as NetworkBehaviour>::ProtocolsHandler,
+            >,
+            <BlockExchange<TSubstream> as NetworkBehaviour>::ProtocolsHandler,
+          >,
+          <Floodsub<TSubstream> as NetworkBehaviour>::ProtocolsHandler,
+        >,
+        <TimeSync<TSubstream> as NetworkBehaviour>::ProtocolsHandler,
+      >,
+      <Toggle<Mdns<TSubstream>> as NetworkBehaviour>::ProtocolsHandler,
+    >,
+    <Liveness<TSubstream> as NetworkBehaviour>::ProtocolsHandler,
   >;
   type OutEvent = AllEvents;
   fn new_handler(&mut self) -> Self::ProtocolsHandler {
-    IntoProtocolsHandler::select(self.kademlia.new_handler(), self.identify.new_handler())
+    IntoProtocolsHandler::select(
+      IntoProtocolsHandler::select(
+        IntoProtocolsHandler::select(
+          IntoProtocolsHandler::select(
+            IntoProtocolsHandler::select(
+              IntoProtocolsHandler::select(self.kademlia.new_handler(), self.identify.new_handler()),
+              self.exchange.new_handler(),
+            ),
+            self.floodsub.new_handler(),
+          ),
+          self.timesync.new_handler(),
+        ),
+        self.mdns.new_handler(),
+      ),
+      self.liveness.new_handler(),
+    )
   }
   fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+    // Without addresses, banned peers are never dialed.
+    if self.reputation.is_banned(peer_id) {
+      return Vec::new();
+    }
     let mut list = self.kademlia.addresses_of_peer(peer_id);
     list.extend_from_slice(&self.identify.addresses_of_peer(peer_id));
+    list.extend_from_slice(&self.mdns.addresses_of_peer(peer_id));
+    for addr in self.address_book.addresses_of_peer(peer_id) {
+      if !list.contains(&addr) {
+        list.push(addr);
+      }
+    }
     list
   }
   fn inject_connected(&mut self, peer_id: PeerId, endpoint: ConnectedPoint) {
     self.num_connections += 1;
+    let dialed = match &endpoint {
+      ConnectedPoint::Dialer { address } => Some(address),
+      ConnectedPoint::Listener { .. } => None,
+    };
+    self.address_book.connected(&peer_id, dialed);
     self
       .kademlia
       .inject_connected(peer_id.clone(), endpoint.clone());
     self
       .identify
       .inject_connected(peer_id.clone(), endpoint.clone());
+    self
+      .exchange
+      .inject_connected(peer_id.clone(), endpoint.clone());
+    self
+      .floodsub
+      .inject_connected(peer_id.clone(), endpoint.clone());
+    self
+      .timesync
+      .inject_connected(peer_id.clone(), endpoint.clone());
+    self
+      .mdns
+      .inject_connected(peer_id.clone(), endpoint.clone());
+    self.liveness.inject_connected(peer_id.clone(), endpoint);
+    if self.reputation.is_banned(&peer_id) {
+      println!("Disconnecting banned peer {}", peer_id);
+      self.liveness.disconnect(&peer_id);
+    }
   }
   fn inject_disconnected(&mut self, peer_id: &PeerId, endpoint: ConnectedPoint) {
     self.num_connections -= 1;
     self.kademlia.inject_disconnected(peer_id, endpoint.clone());
-    self.identify.inject_disconnected(peer_id, endpoint);
+    self.identify.inject_disconnected(peer_id, endpoint.clone());
+    self.exchange.inject_disconnected(peer_id, endpoint.clone());
+    self.floodsub.inject_disconnected(peer_id, endpoint.clone());
+    self.timesync.inject_disconnected(peer_id, endpoint.clone());
+    self.mdns.inject_disconnected(peer_id, endpoint.clone());
+    self.liveness.inject_disconnected(peer_id, endpoint);
+  }
+  fn inject_dial_failure(&mut self, peer_id: &PeerId) {
+    self.address_book.dial_failed(peer_id);
+    self.kademlia.inject_dial_failure(peer_id);
+    self.identify.inject_dial_failure(peer_id);
+    self.exchange.inject_dial_failure(peer_id);
+    self.floodsub.inject_dial_failure(peer_id);
+    self.timesync.inject_dial_failure(peer_id);
+    self.mdns.inject_dial_failure(peer_id);
+    self.liveness.inject_dial_failure(peer_id);
   }
   fn inject_node_event(
     &mut self,
     peer_id: PeerId,
     event: <<Self::ProtocolsHandler as IntoProtocolsHandler>::Handler as ProtocolsHandler>::OutEvent,
   ) {
+    // Identify, time sync and ping messages are exchanged with every peer, used or not.
     match event {
-      EitherOutput::First(event) => self.kademlia.inject_node_event(peer_id, event),
-      EitherOutput::Second(event) => self.identify.inject_node_event(peer_id, event),
+      EitherOutput::First(EitherOutput::First(EitherOutput::First(EitherOutput::First(
+        EitherOutput::First(EitherOutput::First(event)),
+      )))) => {
+        self.liveness.mark_active(&peer_id);
+        self.check_kademlia_event(&peer_id, &event);
+        self.kademlia.inject_node_event(peer_id, event)
+      }
+      EitherOutput::First(EitherOutput::First(EitherOutput::First(EitherOutput::First(
+        EitherOutput::First(EitherOutput::Second(event)),
+      )))) => self.identify.inject_node_event(peer_id, event),
+      EitherOutput::First(EitherOutput::First(EitherOutput::First(EitherOutput::First(
+        EitherOutput::Second(event),
+      )))) => {
+        self.liveness.mark_active(&peer_id);
+        self.exchange.inject_node_event(peer_id, event)
+      }
+      EitherOutput::First(EitherOutput::First(EitherOutput::First(EitherOutput::Second(event)))) => {
+        self.liveness.mark_active(&peer_id);
+        self.floodsub.inject_node_event(peer_id, event)
+      }
+      EitherOutput::First(EitherOutput::First(EitherOutput::Second(event))) => {
+        self.timesync.inject_node_event(peer_id, event)
+      }
+      EitherOutput::First(EitherOutput::Second(event)) => self.mdns.inject_node_event(peer_id, event),
+      EitherOutput::Second(event) => self.liveness.inject_node_event(peer_id, event),
     }
   }
 
@@ -167,6 +866,9 @@ where
       Self::OutEvent,
     >,
   > {
+    if let Some(event) = self.pending_events.pop_front() {
+      return Async::Ready(NetworkBehaviourAction::GenerateEvent(event));
+    }
     // Poll the stream that fires when we need to start a random Kademlia query.
     // self.kademlia.get_closest_peers(self.local_peer_id.clone());
     loop {
@@ -179,6 +881,9 @@ where
             self.num_connections, random_peer_id
           );
           self.kademlia.get_closest_peers(random_peer_id);
+          self.kademlia.store_mut().remove_expired();
+          self.reputation.remove_expired();
+          self.address_book.save();
           // Schedule the next random query with exponentially increasing delay,
           // capped at 60 seconds.
           self.next_kad_random_query = Delay::new(self.duration_to_next_kad).compat();
@@ -233,14 +938,70 @@ where
             }
           },
           KademliaEvent::GetRecordResult(res) => {
-            let ev = match res {
-              Ok(ok) => {
-                let results = ok.records.into_iter().map(|r| (r.key, r.value)).collect();
+            self.queries.resolve_value(&res);
+            // Records found short of the quorum are still worth using.
+            let records = match res {
+              Ok(ok) => Ok(ok.records),
+              Err(GetRecordError::QuorumFailed { records, .. })
+              | Err(GetRecordError::Timeout { records, .. })
+                if !records.is_empty() =>
+              {
+                Ok(records)
+              }
+              Err(e) => Err(e.into_key()),
+            };
+            let ev = match records {
+              Ok(records) => {
+                let rotated = records
+                  .first()
+                  .and_then(|r| self.stations.rotated_of(&r.key))
+                  .cloned();
+                if let Some(old) = rotated {
+                  let values = records.into_iter().map(|r| r.value).collect();
+                  let ev = self.handle_successor_records(old, values);
+                  println!("GetRecordResult: {:?}", ev);
+                  return Async::Ready(NetworkBehaviourAction::GenerateEvent(
+                    AllEvents::DiscoveryOut(ev),
+                  ));
+                }
+                let proposed = records
+                  .first()
+                  .and_then(|r| self.stations.proposal_of(&r.key))
+                  .cloned();
+                if let Some(station) = proposed {
+                  let values = records.into_iter().map(|r| r.value).collect();
+                  let ev = self.handle_proposal_records(station, values);
+                  println!("GetRecordResult: {:?}", ev);
+                  return Async::Ready(NetworkBehaviourAction::GenerateEvent(
+                    AllEvents::DiscoveryOut(ev),
+                  ));
+                }
+                let station = records
+                  .first()
+                  .and_then(|r| self.stations.station_of(&r.key))
+                  .cloned();
+                match station {
+                  Some(station) => {
+                    let values = records.into_iter().map(|r| r.value).collect();
+                    self.handle_manifest_records(station, values)
+                  }
+                  None => {
+                    let results = records.into_iter().map(|r| (r.key, r.value)).collect();
 
-                // DiscoveryOut::ValueFound(results)
-                DiscoveryOutT::ValueFound(results)
+                    // DiscoveryOut::ValueFound(results)
+                    DiscoveryOutT::ValueFound(results)
+                  }
+                }
+              }
+              Err(key) => {
+                if let Some(old) = self.stations.rotated_of(&key) {
+                  DiscoveryOutT::SuccessorNotFound(old.clone())
+                } else if let Some(station) = self.stations.proposal_of(&key) {
+                  DiscoveryOutT::ProposalNotFound(station.clone())
+                } else {
+                  DiscoveryOutT::ValueNotFound(key)
+                }
               }
-              Err(e) => DiscoveryOutT::ValueNotFound(e.into_key()),
             };
             println!("GetRecordResult: {:?}", ev);
             return Async::Ready(NetworkBehaviourAction::GenerateEvent(
@@ -265,17 +1026,32 @@ where
               e
             ),
           },
+          KademliaEvent::GetProvidersResult(res) => {
+            let (key, providers) = match res {
+              Ok(ok) => (ok.key, ok.providers),
+              Err(GetProvidersError::Timeout { key, providers, .. }) => (key, providers),
+            };
+            let ev = DiscoveryOutT::ProvidersFound(key.to_vec(), providers);
+            println!("GetProvidersResult: {:?}", ev);
+            return Async::Ready(NetworkBehaviourAction::GenerateEvent(
+              AllEvents::DiscoveryOut(ev),
+            ));
+          }
+          KademliaEvent::StartProvidingResult(res) | KademliaEvent::RepublishProviderResult(res) => {
+            match res {
+              Ok(ok) => println!("Libp2p => Providing: {:?}", ok.key),
+              Err(e) => println!(
+                "Libp2p => Providing of {:?} failed with: {:?}",
+                e.key(),
+                e
+              ),
+            }
+          }
           KademliaEvent::Discovered { .. } => {
             // We are not interested in these events at the moment.
           }
           // We never start any other type of query.
-          e => println!("Libp2p => Unhandled Kademlia event: {:?}", e), // if let PingEvent {
-                                                                        //     peer,
-                                                                        //     result: Ok(PingSuccess::Ping { rtt }),
-                                                                        // } = ev
-                                                                        // {
-                                                                        //     self.handle_ping_report(&peer, rtt)
-                                                                        // }
+          e => println!("Libp2p => Unhandled Kademlia event: {:?}", e),
         },
         Async::Ready(NetworkBehaviourAction::DialAddress { address }) => {
           return Async::Ready(NetworkBehaviourAction::DialAddress { address })
@@ -286,7 +1062,9 @@ where
         Async::Ready(NetworkBehaviourAction::SendEvent { peer_id, event }) => {
           return Async::Ready(NetworkBehaviourAction::SendEvent {
             peer_id,
-            event: EitherOutput::First(event),
+            event: EitherOutput::First(EitherOutput::First(EitherOutput::First(EitherOutput::First(
+              EitherOutput::First(EitherOutput::First(event)),
+            )))),
           })
         }
         Async::Ready(NetworkBehaviourAction::ReportObservedAddr { address }) => {
@@ -304,7 +1082,12 @@ where
             return Async::Ready(NetworkBehaviourAction::GenerateEvent(event));
           }
           IdentifyEvent::Error { peer_id, error } => {
-            println!("Identification with peer {:?} failed => {}", peer_id, error)
+            println!("Identification with peer {:?} failed => {}", peer_id, error);
+            let misbehaviour = match error {
+              ProtocolsHandlerUpgrErr::Timeout => Misbehaviour::Timeout,
+              _ => Misbehaviour::ProtocolError,
+            };
+            self.report(&peer_id, misbehaviour);
           }
           IdentifyEvent::Sent { .. } => {}
         },
@@ -325,6 +1108,167 @@ where
         }
       }
     }
+    match self.exchange.poll(params) {
+      Async::NotReady => {}
+      Async::Ready(NetworkBehaviourAction::GenerateEvent(event)) => {
+        match &event {
+          ExchangeEvent::SongComplete(song) => self.start_providing(song),
+          ExchangeEvent::BadBlock { peer_id, .. } => self.report(peer_id, Misbehaviour::BadBlock),
+          ExchangeEvent::Timeout { peer_id, .. } => self.report(peer_id, Misbehaviour::Timeout),
+          ExchangeEvent::TooManyWants { peer_id } => {
+            self.report(peer_id, Misbehaviour::ProtocolError)
+          }
+          _ => {}
+        }
+        return Async::Ready(NetworkBehaviourAction::GenerateEvent(AllEvents::Exchange(event)));
+      }
+      Async::Ready(NetworkBehaviourAction::DialAddress { address }) => {
+        return Async::Ready(NetworkBehaviourAction::DialAddress { address });
+      }
+      Async::Ready(NetworkBehaviourAction::DialPeer { peer_id }) => {
+        return Async::Ready(NetworkBehaviourAction::DialPeer { peer_id });
+      }
+      Async::Ready(NetworkBehaviourAction::SendEvent { peer_id, event }) => {
+        return Async::Ready(NetworkBehaviourAction::SendEvent {
+          peer_id,
+          event: EitherOutput::First(EitherOutput::First(EitherOutput::First(EitherOutput::First(
+            EitherOutput::Second(event),
+          )))),
+        });
+      }
+      Async::Ready(NetworkBehaviourAction::ReportObservedAddr { address }) => {
+        return Async::Ready(NetworkBehaviourAction::ReportObservedAddr { address });
+      }
+    }
+    loop {
+      match self.floodsub.poll(params) {
+        Async::NotReady => break,
+        Async::Ready(NetworkBehaviourAction::GenerateEvent(event)) => match event {
+          FloodsubEvent::Message(message) => {
+            let ev = self.handle_broadcast(message.source, &message.data, &message.topics);
+            if let Some(ev) = ev {
+              return Async::Ready(NetworkBehaviourAction::GenerateEvent(ev));
+            }
+          }
+          FloodsubEvent::Subscribed { peer_id, topic } => {
+            println!("{} subscribed to {:?}", peer_id, topic)
+          }
+          FloodsubEvent::Unsubscribed { peer_id, topic } => {
+            println!("{} unsubscribed from {:?}", peer_id, topic)
+          }
+        },
+        Async::Ready(NetworkBehaviourAction::DialAddress { address }) => {
+          return Async::Ready(NetworkBehaviourAction::DialAddress { address });
+        }
+        Async::Ready(NetworkBehaviourAction::DialPeer { peer_id }) => {
+          return Async::Ready(NetworkBehaviourAction::DialPeer { peer_id });
+        }
+        Async::Ready(NetworkBehaviourAction::SendEvent { peer_id, event }) => {
+          return Async::Ready(NetworkBehaviourAction::SendEvent {
+            peer_id,
+            event: EitherOutput::First(EitherOutput::First(EitherOutput::First(EitherOutput::Second(
+              event,
+            )))),
+          });
+        }
+        Async::Ready(NetworkBehaviourAction::ReportObservedAddr { address }) => {
+          return Async::Ready(NetworkBehaviourAction::ReportObservedAddr { address });
+        }
+      }
+    }
+    loop {
+      match self.timesync.poll(params) {
+        Async::NotReady => break,
+        Async::Ready(NetworkBehaviourAction::GenerateEvent(event)) => match event {
+          // Estimates are only used through `network_time`.
+          TimeSyncEvent::Estimate { .. } => {}
+        },
+        Async::Ready(NetworkBehaviourAction::DialAddress { address }) => {
+          return Async::Ready(NetworkBehaviourAction::DialAddress { address });
+        }
+        Async::Ready(NetworkBehaviourAction::DialPeer { peer_id }) => {
+          return Async::Ready(NetworkBehaviourAction::DialPeer { peer_id });
+        }
+        Async::Ready(NetworkBehaviourAction::SendEvent { peer_id, event }) => {
+          return Async::Ready(NetworkBehaviourAction::SendEvent {
+            peer_id,
+            event: EitherOutput::First(EitherOutput::First(EitherOutput::Second(event))),
+          });
+        }
+        Async::Ready(NetworkBehaviourAction::ReportObservedAddr { address }) => {
+          return Async::Ready(NetworkBehaviourAction::ReportObservedAddr { address });
+        }
+      }
+    }
+    loop {
+      match self.mdns.poll(params) {
+        Async::NotReady => break,
+        Async::Ready(NetworkBehaviourAction::GenerateEvent(event)) => match event {
+          MdnsEvent::Discovered(list) => {
+            for (peer_id, addr) in list {
+              println!("Found peer {} at {} on the local network", peer_id, addr);
+              self.add_self_reported_address(&peer_id, addr);
+              let ev = DiscoveryOutT::Discovered(peer_id);
+              self.pending_events.push_back(AllEvents::DiscoveryOut(ev));
+            }
+            if let Some(event) = self.pending_events.pop_front() {
+              return Async::Ready(NetworkBehaviourAction::GenerateEvent(event));
+            }
+          }
+          // Kademlia finds out on its own when the address stops working.
+          MdnsEvent::Expired(_) => {}
+        },
+        Async::Ready(NetworkBehaviourAction::DialAddress { address }) => {
+          return Async::Ready(NetworkBehaviourAction::DialAddress { address });
+        }
+        Async::Ready(NetworkBehaviourAction::DialPeer { peer_id }) => {
+          return Async::Ready(NetworkBehaviourAction::DialPeer { peer_id });
+        }
+        Async::Ready(NetworkBehaviourAction::SendEvent { peer_id, event }) => {
+          return Async::Ready(NetworkBehaviourAction::SendEvent {
+            peer_id,
+            event: EitherOutput::First(EitherOutput::Second(event)),
+          });
+        }
+        Async::Ready(NetworkBehaviourAction::ReportObservedAddr { address }) => {
+          return Async::Ready(NetworkBehaviourAction::ReportObservedAddr { address });
+        }
+      }
+    }
+    match self.liveness.poll(params) {
+      Async::NotReady => {}
+      Async::Ready(NetworkBehaviourAction::GenerateEvent(event)) => {
+        match &event {
+          LivenessEvent::Rtt { .. } => {
+            let liveness = &self.liveness;
+            self
+              .exchange
+              .sort_providers(|providers| liveness.sort_by_rtt(providers));
+          }
+          LivenessEvent::Failure { peer_id, error } => {
+            println!("Ping to {} failed: {}", peer_id, error);
+            self.report(peer_id, Misbehaviour::Timeout);
+          }
+          LivenessEvent::Evicted(_) => {}
+        }
+        return Async::Ready(NetworkBehaviourAction::GenerateEvent(AllEvents::Liveness(event)));
+      }
+      Async::Ready(NetworkBehaviourAction::DialAddress { address }) => {
+        return Async::Ready(NetworkBehaviourAction::DialAddress { address });
+      }
+      Async::Ready(NetworkBehaviourAction::DialPeer { peer_id }) => {
+        return Async::Ready(NetworkBehaviourAction::DialPeer { peer_id });
+      }
+      Async::Ready(NetworkBehaviourAction::SendEvent { peer_id, event }) => {
+        return Async::Ready(NetworkBehaviourAction::SendEvent {
+          peer_id,
+          event: EitherOutput::Second(event),
+        });
+      }
+      Async::Ready(NetworkBehaviourAction::ReportObservedAddr { address }) => {
+        return Async::Ready(NetworkBehaviourAction::ReportObservedAddr { address });
+      }
+    }
     Async::NotReady
   }
 }
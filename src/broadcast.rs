@@ -0,0 +1,344 @@
+use libp2p::core::{identity, PeerId, PublicKey};
+use libp2p::floodsub::{Topic, TopicBuilder, TopicHash};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::{cmp, fmt};
+
+/// Largest audio frame that fits in a single floodsub RPC.
+///
+/// Floodsub drops inbound RPCs over 2048 bytes, which also have to carry the
+/// source, sequence number and topic of the message, and the public key and
+/// signature of the admin that published it.
+pub const MAX_FRAME_SIZE: usize = 1408;
+/// How far the send time of a message may be from our clock, in milliseconds.
+pub const MAX_CLOCK_SKEW: u64 = 30_000;
+
+/// Message published by the admins of a station on its live channel.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum BroadcastMessage {
+  /// An encoded audio frame of the live stream.
+  Audio {
+    /// Incremented for every frame, so listeners can spot gaps and reorder.
+    sequence: u64,
+    data: Vec<u8>,
+  },
+  Control(ControlMessage),
+}
+
+/// Playback commands sent alongside the audio frames.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ControlMessage {
+  /// Playback moved to `track` of the manifest, `position_ms` into it.
+  Play { track: usize, position_ms: u64 },
+  /// The current track was skipped.
+  Skip,
+  Pause,
+  Resume,
+  /// A new manifest version was published to the DHT.
+  ManifestUpdated { version: u64 },
+  /// An update of the manifest got more signatures; see `Behaviour::propose_manifest`.
+  ProposalUpdated { version: u64 },
+}
+
+/// A `BroadcastMessage` signed by the admin that published it.
+///
+/// The source of a floodsub message is not signed, so listeners check the
+/// signature against the admins of the station instead.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SignedBroadcast {
+  /// Bincode encoding of the `BroadcastMessage`.
+  payload: Vec<u8>,
+  /// When the message was sent, in milliseconds since the Unix epoch; it grows
+  /// with every message of the signer, see `ReplayGuard`.
+  sent_at: u64,
+  /// Protobuf encoding of the signer's public key.
+  signer: Vec<u8>,
+  /// Signature over the station id, the send time and the payload, so a
+  /// message cannot be replayed later or on the channel of another station.
+  signature: Vec<u8>,
+}
+
+/// Returns the bytes signed for a message `payload` sent at `sent_at` on the channel of `station`.
+fn signed_bytes(station: &PeerId, sent_at: u64, payload: &[u8]) -> Vec<u8> {
+  let mut bytes = station.as_bytes().to_vec();
+  bytes.extend_from_slice(&sent_at.to_be_bytes());
+  bytes.extend_from_slice(payload);
+  bytes
+}
+
+#[derive(Debug)]
+pub enum BroadcastError {
+  /// The audio frame does not fit in a floodsub message.
+  FrameTooLarge(usize),
+  /// The message could not be decoded.
+  Decode(bincode::Error),
+  /// The public key of the signer could not be decoded.
+  InvalidSigner,
+  /// The signature does not match the message and the signer.
+  InvalidSignature,
+  /// Signing the message with the local key failed.
+  Signing(identity::error::SigningError),
+  /// The message was sent further than `MAX_CLOCK_SKEW` from now.
+  Stale { sent_at: u64, now: u64 },
+  /// A message sent at the same time or later was accepted from the signer already.
+  Replayed { sent_at: u64, last: u64 },
+}
+
+impl fmt::Display for BroadcastError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      BroadcastError::FrameTooLarge(len) => write!(
+        f,
+        "Audio frame of {} bytes is larger than {} bytes",
+        len, MAX_FRAME_SIZE
+      ),
+      BroadcastError::Decode(err) => write!(f, "Cannot decode broadcast message: {}", err),
+      BroadcastError::InvalidSigner => write!(f, "Broadcast signer key cannot be decoded"),
+      BroadcastError::InvalidSignature => write!(f, "Broadcast signature does not verify"),
+      BroadcastError::Signing(err) => write!(f, "Cannot sign broadcast message: {}", err),
+      BroadcastError::Stale { sent_at, now } => write!(
+        f,
+        "Broadcast message sent at {} is too far from now, {}",
+        sent_at, now
+      ),
+      BroadcastError::Replayed { sent_at, last } => write!(
+        f,
+        "Broadcast message sent at {} is not after the last one, sent at {}",
+        sent_at, last
+      ),
+    }
+  }
+}
+
+impl std::error::Error for BroadcastError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      BroadcastError::Decode(err) => Some(err),
+      BroadcastError::Signing(err) => Some(err),
+      _ => None,
+    }
+  }
+}
+
+impl From<bincode::Error> for BroadcastError {
+  fn from(err: bincode::Error) -> BroadcastError {
+    BroadcastError::Decode(err)
+  }
+}
+
+impl From<identity::error::SigningError> for BroadcastError {
+  fn from(err: identity::error::SigningError) -> BroadcastError {
+    BroadcastError::Signing(err)
+  }
+}
+
+impl BroadcastMessage {
+  /// Decodes a message published on the channel of `station`, checking its
+  /// signature. Returns the peer id of the signer, which the caller must check
+  /// against the admins of the station, and the time the message was sent at,
+  /// to check with a `ReplayGuard`.
+  pub fn open(station: &PeerId, bytes: &[u8]) -> Result<(PeerId, u64, Self), BroadcastError> {
+    let signed: SignedBroadcast = bincode::deserialize(bytes)?;
+    let key =
+      PublicKey::from_protobuf_encoding(&signed.signer).map_err(|_| BroadcastError::InvalidSigner)?;
+    let bytes = signed_bytes(station, signed.sent_at, &signed.payload);
+    if !key.verify(&bytes, &signed.signature) {
+      return Err(BroadcastError::InvalidSignature);
+    }
+    Ok((key.into_peer_id(), signed.sent_at, bincode::deserialize(&signed.payload)?))
+  }
+
+  /// Encodes the message for the channel of `station`, sent at `sent_at` and
+  /// signed with `keypair`.
+  pub fn sign(
+    &self,
+    station: &PeerId,
+    sent_at: u64,
+    keypair: &identity::Keypair,
+  ) -> Result<Vec<u8>, BroadcastError> {
+    if let BroadcastMessage::Audio { data, .. } = self {
+      if data.len() > MAX_FRAME_SIZE {
+        return Err(BroadcastError::FrameTooLarge(data.len()));
+      }
+    }
+    let payload = bincode::serialize(self)?;
+    let signed = SignedBroadcast {
+      signature: keypair.sign(&signed_bytes(station, sent_at, &payload))?,
+      sent_at,
+      signer: keypair.public().into_protobuf_encoding(),
+      payload,
+    };
+    Ok(bincode::serialize(&signed)?)
+  }
+}
+
+/// Drops the broadcast messages that were seen already or are too old, so an
+/// earlier `Skip` or `Pause` of the station cannot be played again.
+///
+/// Every message carries the time it was sent at, which must be within
+/// `MAX_CLOCK_SKEW` of our clock and after that of the last message accepted
+/// from the same signer on the same channel.
+#[derive(Default)]
+pub struct ReplayGuard {
+  /// Send time of the last message accepted, by station and signer.
+  last: HashMap<(PeerId, PeerId), u64>,
+  /// Send time of the last message we signed.
+  last_sent: u64,
+}
+
+impl ReplayGuard {
+  /// Returns the time to send a message at, `now` unless a message was sent at
+  /// it already, so the times of our messages always grow.
+  pub fn next_sent_at(&mut self, now: u64) -> u64 {
+    self.last_sent = cmp::max(now, self.last_sent + 1);
+    self.last_sent
+  }
+
+  /// Accepts a message of `signer` on the channel of `station` sent at `sent_at`,
+  /// unless it is stale or replayed. `signer` must be an admin of the station,
+  /// so the guard only grows with the admins.
+  pub fn check(
+    &mut self,
+    station: &PeerId,
+    signer: &PeerId,
+    sent_at: u64,
+    now: u64,
+  ) -> Result<(), BroadcastError> {
+    if sent_at.saturating_add(MAX_CLOCK_SKEW) < now || sent_at > now.saturating_add(MAX_CLOCK_SKEW) {
+      return Err(BroadcastError::Stale { sent_at, now });
+    }
+    let last = self.last.entry((station.clone(), signer.clone())).or_insert(0);
+    if sent_at <= *last {
+      return Err(BroadcastError::Replayed {
+        sent_at,
+        last: *last,
+      });
+    }
+    *last = sent_at;
+    Ok(())
+  }
+}
+
+/// Returns the floodsub topic of the live channel of `station`.
+pub fn station_topic(station: &PeerId) -> Topic {
+  TopicBuilder::new(format!("/radiopeer/station/{}", station.to_base58())).build()
+}
+
+/// Returns the hash of the live channel topic of `station`.
+pub fn station_topic_hash(station: &PeerId) -> TopicHash {
+  station_topic(station).hash().clone()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn frame() -> BroadcastMessage {
+    BroadcastMessage::Audio {
+      sequence: 1,
+      data: vec![0; MAX_FRAME_SIZE],
+    }
+  }
+
+  #[test]
+  fn signed_message_opens_with_its_signer() {
+    let keypair = identity::Keypair::generate_secp256k1();
+    let station = PeerId::random();
+    let bytes = frame().sign(&station, 1000, &keypair).unwrap();
+    let (signer, sent_at, message) = BroadcastMessage::open(&station, &bytes).unwrap();
+    assert_eq!(signer, keypair.public().into_peer_id());
+    assert_eq!(sent_at, 1000);
+    match message {
+      BroadcastMessage::Audio { sequence, data } => {
+        assert_eq!(sequence, 1);
+        assert_eq!(data.len(), MAX_FRAME_SIZE);
+      }
+      _ => panic!("not an audio frame"),
+    }
+  }
+
+  #[test]
+  fn signed_frame_fits_in_a_floodsub_rpc() {
+    // Leaves room for the source, sequence number and topic of the floodsub message.
+    for keypair in &[
+      identity::Keypair::generate_secp256k1(),
+      identity::Keypair::generate_ed25519(),
+    ] {
+      let bytes = frame().sign(&PeerId::random(), u64::MAX, keypair).unwrap();
+      assert!(bytes.len() <= 2048 - 200, "{} bytes", bytes.len());
+    }
+  }
+
+  #[test]
+  fn tampered_message_is_rejected() {
+    let keypair = identity::Keypair::generate_ed25519();
+    let station = PeerId::random();
+    let mut signed: SignedBroadcast =
+      bincode::deserialize(&frame().sign(&station, 1000, &keypair).unwrap()).unwrap();
+    signed.payload = bincode::serialize(&BroadcastMessage::Control(ControlMessage::Skip)).unwrap();
+    let bytes = bincode::serialize(&signed).unwrap();
+    match BroadcastMessage::open(&station, &bytes) {
+      Err(BroadcastError::InvalidSignature) => {}
+      res => panic!("{:?}", res),
+    }
+  }
+
+  #[test]
+  fn message_cannot_be_replayed_on_another_station() {
+    let keypair = identity::Keypair::generate_ed25519();
+    let message = BroadcastMessage::Control(ControlMessage::Skip);
+    let bytes = message.sign(&PeerId::random(), 1000, &keypair).unwrap();
+    assert!(BroadcastMessage::open(&PeerId::random(), &bytes).is_err());
+  }
+
+  #[test]
+  fn send_time_is_signed() {
+    let keypair = identity::Keypair::generate_ed25519();
+    let station = PeerId::random();
+    let message = BroadcastMessage::Control(ControlMessage::Skip);
+    let mut signed: SignedBroadcast =
+      bincode::deserialize(&message.sign(&station, 1000, &keypair).unwrap()).unwrap();
+    signed.sent_at = 2000;
+    let bytes = bincode::serialize(&signed).unwrap();
+    match BroadcastMessage::open(&station, &bytes) {
+      Err(BroadcastError::InvalidSignature) => {}
+      res => panic!("{:?}", res),
+    }
+  }
+
+  #[test]
+  fn replayed_and_stale_messages_are_dropped() {
+    let station = PeerId::random();
+    let admin = PeerId::random();
+    let now = 1_000_000;
+    let mut guard = ReplayGuard::default();
+    guard.check(&station, &admin, now, now).unwrap();
+    match guard.check(&station, &admin, now, now + 10) {
+      Err(BroadcastError::Replayed { .. }) => {}
+      res => panic!("{:?}", res),
+    }
+    match guard.check(&station, &admin, now - 5, now + 10) {
+      Err(BroadcastError::Replayed { .. }) => {}
+      res => panic!("{:?}", res),
+    }
+    guard.check(&station, &admin, now + 1, now + 10).unwrap();
+    // Other signers and stations have their own order.
+    guard.check(&station, &PeerId::random(), now - 5, now).unwrap();
+    guard.check(&PeerId::random(), &admin, now - 5, now).unwrap();
+    for sent_at in &[now - MAX_CLOCK_SKEW - 1, now + MAX_CLOCK_SKEW + 1, u64::MAX] {
+      match ReplayGuard::default().check(&station, &admin, *sent_at, now) {
+        Err(BroadcastError::Stale { .. }) => {}
+        res => panic!("{:?}", res),
+      }
+    }
+  }
+
+  #[test]
+  fn send_times_grow() {
+    let mut guard = ReplayGuard::default();
+    assert_eq!(guard.next_sent_at(1000), 1000);
+    assert_eq!(guard.next_sent_at(1000), 1001);
+    assert_eq!(guard.next_sent_at(999), 1002);
+    assert_eq!(guard.next_sent_at(5000), 5000);
+  }
+}
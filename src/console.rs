@@ -0,0 +1,214 @@
+use libp2p::{Multiaddr, PeerId};
+use std::{fmt, str::FromStr};
+
+/// Commands understood on the standard input of a running node, one per line.
+pub const HELP: &str = "\
+Commands:
+  put <key> <value>   store a value in the DHT
+  get <key>           look up a value in the DHT
+  peers               list the peers we know of
+  dial <multiaddr>    connect to a peer, /p2p/<id> at the end is optional
+  tune <station>      listen to a station
+  ban <peer>          disconnect a peer and refuse it until unbanned
+  unban <peer>        lift the ban on a peer
+  bans                list the banned peers
+  skip                skip the current track of our station
+  np                  show what the station we listen to is playing
+  help                show this message";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConsoleCommand {
+  Put { key: String, value: String },
+  Get { key: String },
+  Peers,
+  Dial(Multiaddr),
+  Tune(PeerId),
+  Ban(PeerId),
+  Unban(PeerId),
+  Bans,
+  Skip,
+  NowPlaying,
+  Help,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ConsoleError {
+  UnknownCommand(String),
+  /// The command is missing an argument, named by the string.
+  MissingArgument(&'static str),
+  /// The command got more arguments than it takes.
+  TrailingArguments(String),
+  InvalidMultiaddr(String),
+  InvalidPeerId(String),
+}
+
+impl fmt::Display for ConsoleError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ConsoleError::UnknownCommand(cmd) => write!(f, "Unknown command {:?}, try help", cmd),
+      ConsoleError::MissingArgument(arg) => write!(f, "Missing argument <{}>", arg),
+      ConsoleError::TrailingArguments(args) => write!(f, "Unexpected arguments {:?}", args),
+      ConsoleError::InvalidMultiaddr(e) => write!(f, "Invalid multiaddress: {}", e),
+      ConsoleError::InvalidPeerId(id) => write!(f, "Invalid peer id {:?}", id),
+    }
+  }
+}
+
+impl std::error::Error for ConsoleError {}
+
+impl FromStr for ConsoleCommand {
+  type Err = ConsoleError;
+
+  /// Parses a line; the value of `put` is the rest of the line, spaces included.
+  fn from_str(line: &str) -> Result<Self, Self::Err> {
+    let line = line.trim();
+    let (name, rest) = split_word(line);
+    let command = match name {
+      "put" => {
+        let (key, value) = split_word(rest);
+        if key.is_empty() {
+          return Err(ConsoleError::MissingArgument("key"));
+        }
+        if value.is_empty() {
+          return Err(ConsoleError::MissingArgument("value"));
+        }
+        return Ok(ConsoleCommand::Put {
+          key: key.to_string(),
+          value: value.to_string(),
+        });
+      }
+      "get" => ConsoleCommand::Get {
+        key: argument(rest, "key")?.to_string(),
+      },
+      "peers" => ConsoleCommand::Peers,
+      "dial" => {
+        let addr = argument(rest, "multiaddr")?;
+        ConsoleCommand::Dial(
+          addr
+            .parse()
+            .map_err(|e: libp2p::multiaddr::Error| ConsoleError::InvalidMultiaddr(e.to_string()))?,
+        )
+      }
+      "tune" => ConsoleCommand::Tune(peer_id(rest, "station")?),
+      "ban" => ConsoleCommand::Ban(peer_id(rest, "peer")?),
+      "unban" => ConsoleCommand::Unban(peer_id(rest, "peer")?),
+      "bans" => ConsoleCommand::Bans,
+      "skip" => ConsoleCommand::Skip,
+      "np" => ConsoleCommand::NowPlaying,
+      "help" | "?" => ConsoleCommand::Help,
+      "" => return Err(ConsoleError::MissingArgument("command")),
+      _ => return Err(ConsoleError::UnknownCommand(name.to_string())),
+    };
+    // Only `put` takes free text; everything else takes at most one word.
+    let extra = match command {
+      ConsoleCommand::Get { .. }
+      | ConsoleCommand::Dial(_)
+      | ConsoleCommand::Tune(_)
+      | ConsoleCommand::Ban(_)
+      | ConsoleCommand::Unban(_) => split_word(rest).1,
+      _ => rest,
+    };
+    if !extra.is_empty() {
+      return Err(ConsoleError::TrailingArguments(extra.to_string()));
+    }
+    Ok(command)
+  }
+}
+
+/// Splits the first word off `s`, returning it and the trimmed rest.
+fn split_word(s: &str) -> (&str, &str) {
+  match s.find(char::is_whitespace) {
+    Some(i) => (&s[..i], s[i..].trim_start()),
+    None => (s, ""),
+  }
+}
+
+fn argument<'a>(rest: &'a str, name: &'static str) -> Result<&'a str, ConsoleError> {
+  match split_word(rest).0 {
+    "" => Err(ConsoleError::MissingArgument(name)),
+    word => Ok(word),
+  }
+}
+
+fn peer_id(rest: &str, name: &'static str) -> Result<PeerId, ConsoleError> {
+  let word = argument(rest, name)?;
+  word
+    .parse()
+    .map_err(|_| ConsoleError::InvalidPeerId(word.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(line: &str) -> Result<ConsoleCommand, ConsoleError> {
+    line.parse()
+  }
+
+  #[test]
+  fn parses_commands() {
+    let peer = PeerId::random();
+    assert_eq!(
+      parse("  put  song  a title  with spaces \n"),
+      Ok(ConsoleCommand::Put {
+        key: "song".to_string(),
+        value: "a title  with spaces".to_string(),
+      })
+    );
+    assert_eq!(
+      parse("get key"),
+      Ok(ConsoleCommand::Get {
+        key: "key".to_string()
+      })
+    );
+    assert_eq!(
+      parse("dial /ip4/127.0.0.1/tcp/4001"),
+      Ok(ConsoleCommand::Dial("/ip4/127.0.0.1/tcp/4001".parse().unwrap()))
+    );
+    assert_eq!(
+      parse(&format!("tune {}", peer.to_base58())),
+      Ok(ConsoleCommand::Tune(peer.clone()))
+    );
+    assert_eq!(
+      parse(&format!("ban\t{}", peer.to_base58())),
+      Ok(ConsoleCommand::Ban(peer.clone()))
+    );
+    assert_eq!(
+      parse(&format!("unban {}", peer.to_base58())),
+      Ok(ConsoleCommand::Unban(peer))
+    );
+    assert_eq!(parse("peers"), Ok(ConsoleCommand::Peers));
+    assert_eq!(parse("bans"), Ok(ConsoleCommand::Bans));
+    assert_eq!(parse("skip"), Ok(ConsoleCommand::Skip));
+    assert_eq!(parse("np"), Ok(ConsoleCommand::NowPlaying));
+    assert_eq!(parse("?"), Ok(ConsoleCommand::Help));
+  }
+
+  #[test]
+  fn rejects_malformed_commands() {
+    assert_eq!(parse(""), Err(ConsoleError::MissingArgument("command")));
+    assert_eq!(
+      parse("play"),
+      Err(ConsoleError::UnknownCommand("play".to_string()))
+    );
+    assert_eq!(parse("put"), Err(ConsoleError::MissingArgument("key")));
+    assert_eq!(parse("put key"), Err(ConsoleError::MissingArgument("value")));
+    assert_eq!(parse("get"), Err(ConsoleError::MissingArgument("key")));
+    assert_eq!(
+      parse("get a b"),
+      Err(ConsoleError::TrailingArguments("b".to_string()))
+    );
+    assert_eq!(
+      parse("skip now"),
+      Err(ConsoleError::TrailingArguments("now".to_string()))
+    );
+    assert_eq!(
+      parse("tune station"),
+      Err(ConsoleError::InvalidPeerId("station".to_string()))
+    );
+    match parse("dial localhost") {
+      Err(ConsoleError::InvalidMultiaddr(_)) => {}
+      res => panic!("{:?}", res),
+    }
+  }
+}
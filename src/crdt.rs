@@ -0,0 +1,319 @@
+use serde::{Deserialize, Serialize};
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Identifies an edit: a Lamport counter, higher than that of every edit the
+/// editor had seen, and a random id of the editing replica to break ties.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Dot {
+  pub counter: u64,
+  pub replica: u64,
+}
+
+impl Dot {
+  /// Returns a new dot after `clock`, the highest counter seen.
+  pub fn after(clock: u64) -> Self {
+    Dot {
+      counter: clock + 1,
+      replica: rand::random(),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Node<T> {
+  /// Element this one was inserted after, `None` at the start of the sequence.
+  after: Option<Dot>,
+  value: T,
+}
+
+/// Replicated sequence: a replicated growable array (RGA).
+///
+/// Every element remembers the element it was inserted after. The elements
+/// inserted after the same one are ordered by their dots, the latest first, so
+/// the order only depends on the set of elements and not on the order the
+/// edits arrived in. Removed elements stay as tombstones, since later elements
+/// may have been inserted after them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Sequence<T> {
+  nodes: BTreeMap<Dot, Node<T>>,
+  removed: BTreeSet<Dot>,
+}
+
+impl<T> Default for Sequence<T> {
+  fn default() -> Self {
+    Sequence {
+      nodes: BTreeMap::new(),
+      removed: BTreeSet::new(),
+    }
+  }
+}
+
+impl<T: Clone> Sequence<T> {
+  /// Returns the dots of the elements that were not removed, in sequence order.
+  fn order(&self) -> Vec<Dot> {
+    let mut children: HashMap<Option<Dot>, Vec<Dot>> = HashMap::new();
+    for (dot, node) in &self.nodes {
+      children.entry(node.after).or_default().push(*dot);
+    }
+    let mut order = Vec::new();
+    // Depth first; children are pushed oldest first so the latest is visited first.
+    let mut stack: Vec<Dot> = children.remove(&None).unwrap_or_default();
+    while let Some(dot) = stack.pop() {
+      if !self.removed.contains(&dot) {
+        order.push(dot);
+      }
+      if let Some(after) = children.remove(&Some(dot)) {
+        stack.extend(after);
+      }
+    }
+    order
+  }
+
+  /// Returns the dots of the elements, in sequence order; an element keeps its
+  /// dot across edits, unlike its index.
+  pub fn dots(&self) -> Vec<Dot> {
+    self.order()
+  }
+
+  pub fn len(&self) -> usize {
+    self.order().len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  pub fn get(&self, index: usize) -> Option<&T> {
+    let dot = self.order().get(index).copied()?;
+    Some(&self.nodes[&dot].value)
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &T> {
+    self
+      .order()
+      .into_iter()
+      .map(move |dot| &self.nodes[&dot].value)
+  }
+
+  /// Inserts `value` at `index`, as the edit `dot`.
+  ///
+  /// Panics if `index` is past the end.
+  pub fn insert(&mut self, index: usize, value: T, dot: Dot) {
+    let after = match index {
+      0 => None,
+      _ => Some(self.order()[index - 1]),
+    };
+    self.nodes.insert(dot, Node { after, value });
+  }
+
+  /// Appends `value`, as the edit `dot`.
+  pub fn push(&mut self, value: T, dot: Dot) {
+    let len = self.len();
+    self.insert(len, value, dot);
+  }
+
+  /// Removes the element at `index`.
+  pub fn remove(&mut self, index: usize) -> Option<T> {
+    let dot = self.order().get(index).copied()?;
+    self.removed.insert(dot);
+    Some(self.nodes[&dot].value.clone())
+  }
+
+  /// Adds the edits of `other`.
+  pub fn merge(&mut self, other: &Sequence<T>) {
+    for (dot, node) in &other.nodes {
+      self.nodes.entry(*dot).or_insert_with(|| node.clone());
+    }
+    self.removed.extend(&other.removed);
+  }
+
+  /// Returns the highest counter of the edits.
+  pub fn clock(&self) -> u64 {
+    let inserted = self.nodes.keys().next_back();
+    let removed = self.removed.iter().next_back();
+    inserted.max(removed).map_or(0, |dot| dot.counter)
+  }
+}
+
+/// Replicated set: an observed-remove set.
+///
+/// Every insertion is tagged with a dot, and a removal removes the tags it has
+/// seen. An insertion concurrent to a removal survives it, and two replicas
+/// that saw the same edits hold the same set.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OrSet<T: Ord> {
+  adds: BTreeMap<T, BTreeSet<Dot>>,
+  removed: BTreeSet<Dot>,
+}
+
+impl<T: Ord> Default for OrSet<T> {
+  fn default() -> Self {
+    OrSet {
+      adds: BTreeMap::new(),
+      removed: BTreeSet::new(),
+    }
+  }
+}
+
+impl<T: Ord + Clone> OrSet<T> {
+  pub fn contains<Q>(&self, value: &Q) -> bool
+  where
+    T: Borrow<Q>,
+    Q: Ord + ?Sized,
+  {
+    self.adds.contains_key(value)
+  }
+
+  pub fn len(&self) -> usize {
+    self.adds.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.adds.is_empty()
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &T> {
+    self.adds.keys()
+  }
+
+  /// Adds `value`, as the edit `dot`. Returns false if it was in the set already.
+  pub fn insert(&mut self, value: T, dot: Dot) -> bool {
+    let tags = self.adds.entry(value).or_default();
+    let inserted = tags.is_empty();
+    tags.insert(dot);
+    inserted
+  }
+
+  /// Removes `value`, returning false if it was not in the set.
+  pub fn remove<Q>(&mut self, value: &Q) -> bool
+  where
+    T: Borrow<Q>,
+    Q: Ord + ?Sized,
+  {
+    match self.adds.remove(value) {
+      Some(tags) => {
+        self.removed.extend(tags);
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// Adds the edits of `other`.
+  pub fn merge(&mut self, other: &OrSet<T>) {
+    self.removed.extend(&other.removed);
+    for (value, tags) in &other.adds {
+      self.adds.entry(value.clone()).or_default().extend(tags);
+    }
+    let removed = &self.removed;
+    for tags in self.adds.values_mut() {
+      tags.retain(|tag| !removed.contains(tag));
+    }
+    self.adds.retain(|_, tags| !tags.is_empty());
+  }
+
+  /// Returns the highest counter of the edits.
+  pub fn clock(&self) -> u64 {
+    let added = self.adds.values().filter_map(|tags| tags.iter().next_back()).max();
+    let removed = self.removed.iter().next_back();
+    added.max(removed).map_or(0, |dot| dot.counter)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn dot(counter: u64, replica: u64) -> Dot {
+    Dot { counter, replica }
+  }
+
+  fn merged<T: Clone>(a: &Sequence<T>, b: &Sequence<T>) -> Sequence<T> {
+    let mut merged = a.clone();
+    merged.merge(b);
+    merged
+  }
+
+  #[test]
+  fn concurrent_sequence_edits_converge() {
+    let mut base = Sequence::default();
+    base.push("a", dot(1, 1));
+    base.push("b", dot(2, 1));
+    base.push("c", dot(3, 1));
+    let mut left = base.clone();
+    let mut right = base.clone();
+    // Both insert after "a", one removes "b" and the other appends.
+    left.insert(1, "x", dot(4, 1));
+    left.remove(2);
+    right.insert(1, "y", dot(4, 2));
+    right.push("z", dot(5, 2));
+    let one = merged(&left, &right);
+    let other = merged(&right, &left);
+    assert_eq!(one, other);
+    let values: Vec<_> = one.iter().copied().collect();
+    assert_eq!(values, vec!["a", "y", "x", "c", "z"]);
+    assert_eq!(one.clock(), 5);
+    // Merging again changes nothing.
+    assert_eq!(merged(&one, &left), one);
+  }
+
+  #[test]
+  fn insertions_after_removed_elements_survive() {
+    let mut base = Sequence::default();
+    base.push(1, dot(1, 1));
+    base.push(2, dot(2, 1));
+    let mut left = base.clone();
+    let mut right = base.clone();
+    left.remove(1);
+    right.insert(2, 3, dot(3, 2));
+    let one = merged(&left, &right);
+    assert_eq!(one, merged(&right, &left));
+    assert_eq!(one.iter().copied().collect::<Vec<_>>(), vec![1, 3]);
+    assert_eq!(one.get(1), Some(&3));
+    assert_eq!(one.len(), 2);
+  }
+
+  fn merged_set<T: Ord + Clone>(a: &OrSet<T>, b: &OrSet<T>) -> OrSet<T> {
+    let mut merged = a.clone();
+    merged.merge(b);
+    merged
+  }
+
+  #[test]
+  fn concurrent_set_edits_converge() {
+    let mut base = OrSet::default();
+    base.insert("a", dot(1, 1));
+    base.insert("b", dot(2, 1));
+    let mut left = base.clone();
+    let mut right = base.clone();
+    // An insertion concurrent to a removal survives it.
+    assert!(left.remove("a"));
+    assert!(!right.insert("a", dot(3, 2)));
+    assert!(right.remove("b"));
+    left.insert("c", dot(3, 1));
+    let one = merged_set(&left, &right);
+    assert_eq!(one, merged_set(&right, &left));
+    assert_eq!(one.iter().copied().collect::<Vec<_>>(), vec!["a", "c"]);
+    assert_eq!(one.clock(), 3);
+  }
+
+  #[test]
+  fn removed_values_can_be_added_again() {
+    let mut left = OrSet::default();
+    left.insert("a", dot(1, 1));
+    let mut right = left.clone();
+    assert!(left.remove("a"));
+    assert!(!left.contains("a"));
+    assert!(left.insert("a", dot(2, 1)));
+    // The other replica only saw the first insertion, which the removal covers.
+    let one = merged_set(&left, &right);
+    assert_eq!(one, merged_set(&right, &left));
+    assert!(one.contains("a"));
+    right.merge(&left);
+    assert!(right.remove("a"));
+    left.merge(&right);
+    assert!(left.is_empty());
+    assert!(!merged_set(&left, &one).contains("a"));
+  }
+}
@@ -0,0 +1,642 @@
+use crate::manifest::SongHash;
+use crate::store::{ChunkStore, StoreError};
+use std::fmt;
+use std::io::Cursor;
+use std::time::Duration;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{self, DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::{Error as SymphoniaError, SeekErrorKind};
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use symphonia::core::units::Time;
+
+/// Sample rate and channel count of decoded PCM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PcmFormat {
+  pub sample_rate: u32,
+  pub channels: u16,
+}
+
+impl PcmFormat {
+  /// Number of frames (one sample per channel) played in `duration`.
+  pub fn frames_in(&self, duration: Duration) -> u64 {
+    (duration.as_micros() * u128::from(self.sample_rate) / 1_000_000) as u64
+  }
+
+  /// How long `frames` frames play for.
+  pub fn duration_of(&self, frames: u64) -> Duration {
+    Duration::from_micros(frames * 1_000_000 / u64::from(self.sample_rate))
+  }
+}
+
+/// Converts PCM of any format to a fixed one, for outputs that cannot change
+/// format in the middle of a stream.
+///
+/// Channels are mixed down or duplicated and the rate is converted by linear
+/// interpolation, which is cheap and good enough for a monitoring stream.
+pub struct Resampler {
+  to: PcmFormat,
+  from: Option<PcmFormat>,
+  /// Last input frame, already in the output channel layout.
+  last: Option<Vec<i32>>,
+  /// Position of the next output frame, in input frames after `last`.
+  position: f64,
+}
+
+impl Resampler {
+  pub fn new(to: PcmFormat) -> Self {
+    Resampler {
+      to,
+      from: None,
+      last: None,
+      position: 0.0,
+    }
+  }
+
+  pub fn format(&self) -> PcmFormat {
+    self.to
+  }
+
+  pub fn process(&mut self, from: PcmFormat, samples: &[i16]) -> Vec<i16> {
+    if self.from != Some(from) {
+      self.from = Some(from);
+      self.last = None;
+      self.position = 0.0;
+    }
+    let from_channels = usize::from(from.channels);
+    let to_channels = usize::from(self.to.channels);
+    let mut frames: Vec<Vec<i32>> = Vec::with_capacity(samples.len() / from_channels + 1);
+    frames.extend(self.last.take());
+    for frame in samples.chunks_exact(from_channels) {
+      let mapped = (0..to_channels).map(|c| {
+        if to_channels == 1 {
+          frame.iter().map(|&s| i32::from(s)).sum::<i32>() / from_channels as i32
+        } else {
+          i32::from(frame[c.min(from_channels - 1)])
+        }
+      });
+      frames.push(mapped.collect());
+    }
+    if frames.is_empty() {
+      return Vec::new();
+    }
+    let step = f64::from(from.sample_rate) / f64::from(self.to.sample_rate);
+    let mut out = Vec::new();
+    while self.position + 1.0 < frames.len() as f64 {
+      let i = self.position as usize;
+      let frac = self.position - i as f64;
+      for (&a, &b) in frames[i].iter().zip(&frames[i + 1]) {
+        let (a, b) = (f64::from(a), f64::from(b));
+        out.push((a + (b - a) * frac).round() as i16);
+      }
+      self.position += step;
+    }
+    self.position -= (frames.len() - 1) as f64;
+    self.last = frames.pop();
+    out
+  }
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+  /// The song could not be read from the chunk store.
+  Store(StoreError),
+  /// The container or codec of the song is not supported.
+  Unsupported(String),
+  /// The seek target is past the end of the song.
+  SeekOutOfRange(Duration),
+  /// The stream is malformed.
+  Codec(SymphoniaError),
+  #[cfg(feature = "opus")]
+  Ogg(ogg::OggReadError),
+  #[cfg(feature = "opus")]
+  Opus(audiopus::Error),
+}
+
+impl fmt::Display for DecodeError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DecodeError::Store(err) => write!(f, "Cannot read song: {}", err),
+      DecodeError::Unsupported(what) => write!(f, "Unsupported audio: {}", what),
+      DecodeError::SeekOutOfRange(position) => {
+        write!(f, "Cannot seek to {:?}, past the end of the song", position)
+      }
+      DecodeError::Codec(err) => write!(f, "Cannot decode audio: {}", err),
+      #[cfg(feature = "opus")]
+      DecodeError::Ogg(err) => write!(f, "Cannot read Ogg stream: {}", err),
+      #[cfg(feature = "opus")]
+      DecodeError::Opus(err) => write!(f, "Cannot decode Opus: {}", err),
+    }
+  }
+}
+
+impl std::error::Error for DecodeError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      DecodeError::Store(err) => Some(err),
+      DecodeError::Codec(err) => Some(err),
+      #[cfg(feature = "opus")]
+      DecodeError::Ogg(err) => Some(err),
+      #[cfg(feature = "opus")]
+      DecodeError::Opus(err) => Some(err),
+      _ => None,
+    }
+  }
+}
+
+impl From<StoreError> for DecodeError {
+  fn from(err: StoreError) -> DecodeError {
+    DecodeError::Store(err)
+  }
+}
+
+impl From<SymphoniaError> for DecodeError {
+  fn from(err: SymphoniaError) -> DecodeError {
+    match err {
+      SymphoniaError::Unsupported(what) => DecodeError::Unsupported(what.to_string()),
+      err => DecodeError::Codec(err),
+    }
+  }
+}
+
+#[cfg(feature = "opus")]
+impl From<ogg::OggReadError> for DecodeError {
+  fn from(err: ogg::OggReadError) -> DecodeError {
+    DecodeError::Ogg(err)
+  }
+}
+
+#[cfg(feature = "opus")]
+impl From<audiopus::Error> for DecodeError {
+  fn from(err: audiopus::Error) -> DecodeError {
+    DecodeError::Opus(err)
+  }
+}
+
+/// Returns whether `data` is an Ogg stream starting with an Opus header.
+fn is_opus(data: &[u8]) -> bool {
+  // The first page holds only the identification header, right after the
+  // 27 byte page header and its segment table.
+  data.starts_with(b"OggS")
+    && data.len() > 27
+    && data[27..].windows(8).take(255).any(|w| w == b"OpusHead")
+}
+
+/// Decodes a song into interleaved 16-bit PCM.
+///
+/// WAV, FLAC, MP3 and Ogg/Vorbis are always supported. Ogg/Opus needs the `opus`
+/// feature, which is off by default since it links against libopus, see
+/// Cargo.toml; without it Opus songs are rejected with an error saying so.
+/// Seeking is sample exact, so a listener joining mid-song starts on the same
+/// frame as everyone else.
+pub struct Decoder {
+  format: PcmFormat,
+  /// Frames of the song before the next one `next_frames` returns.
+  position: u64,
+  backend: Backend,
+}
+
+enum Backend {
+  Symphonia(SymphoniaStream),
+  #[cfg(feature = "opus")]
+  Opus(opus::OpusStream),
+}
+
+impl Decoder {
+  /// Opens an encoded song held in memory.
+  pub fn new(data: Vec<u8>) -> Result<Self, DecodeError> {
+    if is_opus(&data) {
+      #[cfg(feature = "opus")]
+      {
+        let stream = opus::OpusStream::new(data)?;
+        return Ok(Decoder {
+          format: stream.format(),
+          position: 0,
+          backend: Backend::Opus(stream),
+        });
+      }
+      #[cfg(not(feature = "opus"))]
+      return Err(DecodeError::Unsupported(
+        "Ogg/Opus, build radiopeer with `--features opus`, which needs libopus or cmake"
+          .to_string(),
+      ));
+    }
+    let stream = SymphoniaStream::new(data)?;
+    Ok(Decoder {
+      format: stream.format,
+      position: 0,
+      backend: Backend::Symphonia(stream),
+    })
+  }
+
+  /// Opens a song reassembled from the chunk store.
+  pub fn from_store(store: &ChunkStore, song: &SongHash) -> Result<Self, DecodeError> {
+    Decoder::new(store.read_song(song)?)
+  }
+
+  pub fn format(&self) -> PcmFormat {
+    self.format
+  }
+
+  /// Total duration of the song, if the container tells it.
+  pub fn duration(&self) -> Option<Duration> {
+    let frames = match &self.backend {
+      Backend::Symphonia(stream) => stream.n_frames,
+      #[cfg(feature = "opus")]
+      Backend::Opus(stream) => stream.n_frames(),
+    }?;
+    Some(self.format.duration_of(frames))
+  }
+
+  /// Total duration of the song, decoding it all if the container does not tell it.
+  ///
+  /// This consumes the decoder when it has to count the frames.
+  pub fn measure(mut self) -> Result<Duration, DecodeError> {
+    if let Some(duration) = self.duration() {
+      return Ok(duration);
+    }
+    while self.next_frames()?.is_some() {}
+    Ok(self.position())
+  }
+
+  /// Position in the song of the next frame `next_frames` returns.
+  pub fn position(&self) -> Duration {
+    self.format.duration_of(self.position)
+  }
+
+  /// Moves to the frame at `position` in the song.
+  pub fn seek(&mut self, position: Duration) -> Result<(), DecodeError> {
+    let frame = self.format.frames_in(position);
+    match &mut self.backend {
+      Backend::Symphonia(stream) => stream.seek(frame, position)?,
+      #[cfg(feature = "opus")]
+      Backend::Opus(stream) => stream.seek(frame, position)?,
+    }
+    self.position = frame;
+    Ok(())
+  }
+
+  /// Decodes the next block of interleaved frames, or `None` at the end of the song.
+  pub fn next_frames(&mut self) -> Result<Option<Vec<i16>>, DecodeError> {
+    let samples = match &mut self.backend {
+      Backend::Symphonia(stream) => stream.next_frames()?,
+      #[cfg(feature = "opus")]
+      Backend::Opus(stream) => stream.next_frames()?,
+    };
+    if let Some(samples) = &samples {
+      self.position += (samples.len() / usize::from(self.format.channels)) as u64;
+    }
+    Ok(samples)
+  }
+}
+
+/// Containers and codecs handled by symphonia.
+///
+/// Timestamps of these formats count frames, which is what makes the seeks exact:
+/// the demuxer seeks to a packet at or before the target and we drop the frames
+/// decoded before it.
+struct SymphoniaStream {
+  reader: Box<dyn FormatReader>,
+  decoder: Box<dyn codecs::Decoder>,
+  track_id: u32,
+  format: PcmFormat,
+  n_frames: Option<u64>,
+  /// Frames before this timestamp are dropped after a seek.
+  skip_until: u64,
+}
+
+impl SymphoniaStream {
+  fn new(data: Vec<u8>) -> Result<Self, DecodeError> {
+    let source = MediaSourceStream::new(Box::new(Cursor::new(data)), Default::default());
+    let options = FormatOptions {
+      enable_gapless: true,
+      ..Default::default()
+    };
+    let probed = symphonia::default::get_probe().format(
+      &Hint::new(),
+      source,
+      &options,
+      &MetadataOptions::default(),
+    )?;
+    let reader = probed.format;
+    let track = reader
+      .tracks()
+      .iter()
+      .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+      .ok_or_else(|| DecodeError::Unsupported("no audio track".to_string()))?;
+    let params = &track.codec_params;
+    let format = match (params.sample_rate, params.channels) {
+      (Some(sample_rate), Some(channels)) => PcmFormat {
+        sample_rate,
+        channels: channels.count() as u16,
+      },
+      _ => return Err(DecodeError::Unsupported("unknown sample format".to_string())),
+    };
+    let decoder = symphonia::default::get_codecs().make(params, &DecoderOptions::default())?;
+    Ok(SymphoniaStream {
+      track_id: track.id,
+      n_frames: params.n_frames,
+      reader,
+      decoder,
+      format,
+      skip_until: 0,
+    })
+  }
+
+  fn seek(&mut self, frame: u64, position: Duration) -> Result<(), DecodeError> {
+    let time = Time::new(position.as_secs(), f64::from(position.subsec_nanos()) / 1e9);
+    let to = SeekTo::Time {
+      time,
+      track_id: Some(self.track_id),
+    };
+    match self.reader.seek(SeekMode::Accurate, to) {
+      Ok(_) => {}
+      Err(SymphoniaError::SeekError(SeekErrorKind::OutOfRange)) => {
+        return Err(DecodeError::SeekOutOfRange(position))
+      }
+      Err(e) => return Err(e.into()),
+    }
+    self.decoder.reset();
+    self.skip_until = frame;
+    Ok(())
+  }
+
+  fn next_frames(&mut self) -> Result<Option<Vec<i16>>, DecodeError> {
+    loop {
+      let packet = match self.reader.next_packet() {
+        Ok(packet) => packet,
+        Err(SymphoniaError::IoError(ref e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+          return Ok(None)
+        }
+        Err(e) => return Err(e.into()),
+      };
+      if packet.track_id() != self.track_id {
+        continue;
+      }
+      let decoded = match self.decoder.decode(&packet) {
+        Ok(decoded) => decoded,
+        // A corrupted packet only costs its own frames.
+        Err(SymphoniaError::DecodeError(e)) => {
+          println!("Skipping undecodable audio packet: {}", e);
+          continue;
+        }
+        Err(e) => return Err(e.into()),
+      };
+      let frames = decoded.frames() as u64;
+      if packet.ts() + frames <= self.skip_until {
+        continue;
+      }
+      let mut buffer = SampleBuffer::<i16>::new(decoded.capacity() as u64, *decoded.spec());
+      buffer.copy_interleaved_ref(decoded);
+      let skip = self.skip_until.saturating_sub(packet.ts()) as usize;
+      self.skip_until = 0;
+      let channels = usize::from(self.format.channels);
+      return Ok(Some(buffer.samples()[skip * channels..].to_vec()));
+    }
+  }
+}
+
+#[cfg(feature = "opus")]
+mod opus {
+  use super::{DecodeError, PcmFormat};
+  use audiopus::coder::Decoder;
+  use audiopus::packet::Packet;
+  use audiopus::{Channels, MutSignals, SampleRate};
+  use ogg::PacketReader;
+  use std::convert::TryFrom;
+  use std::io::{Cursor, SeekFrom};
+  use std::time::Duration;
+
+  /// Opus always decodes at 48 kHz; granule positions count frames at that rate.
+  const SAMPLE_RATE: u32 = 48_000;
+  /// Longest Opus packet: 120 ms at 48 kHz.
+  const MAX_PACKET_FRAMES: usize = 5_760;
+
+  /// An Ogg/Opus stream with a single, mono or stereo, logical stream.
+  pub struct OpusStream {
+    reader: PacketReader<Cursor<Vec<u8>>>,
+    decoder: Decoder,
+    channels: Channels,
+    /// Frames at the start of the stream that are only decoder warm-up.
+    pre_skip: u64,
+    /// Frames decoded since the start of the stream, warm-up included.
+    decoded: u64,
+    /// Frames before this position, warm-up included, are dropped.
+    skip_until: u64,
+    last_granule: Option<u64>,
+  }
+
+  impl OpusStream {
+    pub fn new(data: Vec<u8>) -> Result<Self, DecodeError> {
+      let last_granule = last_granule(&data);
+      let mut reader = PacketReader::new(Cursor::new(data));
+      let head = reader
+        .read_packet()?
+        .ok_or_else(|| DecodeError::Unsupported("empty Ogg stream".to_string()))?;
+      if head.data.len() < 19 || !head.data.starts_with(b"OpusHead") {
+        return Err(DecodeError::Unsupported("missing Opus header".to_string()));
+      }
+      let channels = match head.data[9] {
+        1 => Channels::Mono,
+        2 => Channels::Stereo,
+        n => return Err(DecodeError::Unsupported(format!("Opus with {} channels", n))),
+      };
+      let pre_skip = u64::from(u16::from_le_bytes([head.data[10], head.data[11]]));
+      // The comment header carries nothing we need.
+      reader.read_packet()?;
+      Ok(OpusStream {
+        reader,
+        decoder: Decoder::new(SampleRate::Hz48000, channels)?,
+        channels,
+        pre_skip,
+        decoded: 0,
+        skip_until: pre_skip,
+        last_granule,
+      })
+    }
+
+    pub fn format(&self) -> PcmFormat {
+      PcmFormat {
+        sample_rate: SAMPLE_RATE,
+        channels: if self.channels.is_mono() { 1 } else { 2 },
+      }
+    }
+
+    pub fn n_frames(&self) -> Option<u64> {
+      self.last_granule?.checked_sub(self.pre_skip)
+    }
+
+    /// Opus needs the packets before a target to converge, so we decode from the
+    /// start of the stream and drop everything before the target frame.
+    pub fn seek(&mut self, frame: u64, position: Duration) -> Result<(), DecodeError> {
+      if self.n_frames().is_some_and(|n| frame > n) {
+        return Err(DecodeError::SeekOutOfRange(position));
+      }
+      self
+        .reader
+        .seek_bytes(SeekFrom::Start(0))
+        .map_err(ogg::OggReadError::from)?;
+      self.reader.read_packet()?;
+      self.reader.read_packet()?;
+      self.decoder = Decoder::new(SampleRate::Hz48000, self.channels)?;
+      self.decoded = 0;
+      self.skip_until = self.pre_skip + frame;
+      Ok(())
+    }
+
+    pub fn next_frames(&mut self) -> Result<Option<Vec<i16>>, DecodeError> {
+      let channels = if self.channels.is_mono() { 1 } else { 2 };
+      loop {
+        let packet = match self.reader.read_packet()? {
+          Some(packet) => packet,
+          None => return Ok(None),
+        };
+        let mut output = vec![0i16; MAX_PACKET_FRAMES * channels];
+        let input = Packet::try_from(&packet.data)?;
+        let signals = MutSignals::try_from(&mut output)?;
+        let frames = self.decoder.decode(Some(input), signals, false)? as u64;
+        let start = self.decoded;
+        let mut end = start + frames;
+        self.decoded = end;
+        // The granule position of the last page marks the exact end of the song.
+        // A malformed page may put it before this packet, which is then dropped.
+        if packet.last_in_stream() {
+          end = end.min(packet.absgp_page());
+        }
+        if end <= start.max(self.skip_until) {
+          continue;
+        }
+        let from = self.skip_until.saturating_sub(start) as usize;
+        let to = end.saturating_sub(start) as usize;
+        self.skip_until = 0;
+        return Ok(Some(output[from * channels..to * channels].to_vec()));
+      }
+    }
+  }
+
+  /// Finds the granule position of the last Ogg page, which is the length of the stream.
+  fn last_granule(data: &[u8]) -> Option<u64> {
+    let start = (0..data.len().saturating_sub(14))
+      .rev()
+      .find(|&i| &data[i..i + 4] == b"OggS")?;
+    let mut granule = [0u8; 8];
+    granule.copy_from_slice(&data[start + 6..start + 14]);
+    Some(u64::from_le_bytes(granule))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// First page of an Ogg/Opus stream: the page header, a one entry segment
+  /// table and the start of the identification header.
+  fn opus_head() -> Vec<u8> {
+    let mut data = b"OggS".to_vec();
+    data.extend_from_slice(&[0; 22]);
+    data.push(1);
+    data.push(19);
+    data.extend_from_slice(b"OpusHead");
+    data.extend_from_slice(&[1, 2, 0, 0, 0x80, 0xbb, 0, 0, 0, 0, 0]);
+    data
+  }
+
+  #[test]
+  fn detects_opus() {
+    assert!(is_opus(&opus_head()));
+    assert!(!is_opus(b"RIFF\0\0\0\0WAVE"));
+    assert!(!is_opus(b"OggS"));
+  }
+
+  #[cfg(not(feature = "opus"))]
+  #[test]
+  fn opus_needs_the_feature() {
+    match Decoder::new(opus_head()) {
+      Err(DecodeError::Unsupported(_)) => {}
+      Err(e) => panic!("{}", e),
+      Ok(_) => panic!("decoded Opus without the opus feature"),
+    }
+  }
+
+  /// Encodes `frames` frames of a stereo sine at 48 kHz as Ogg/Opus, ending
+  /// mid-packet so the last granule position has to trim it.
+  #[cfg(feature = "opus")]
+  fn opus_song(frames: u64) -> Vec<u8> {
+    use audiopus::coder::Encoder;
+    use audiopus::{Application, Channels, SampleRate};
+    use ogg::{PacketWriteEndInfo, PacketWriter};
+    const PACKET_FRAMES: u64 = 960;
+    let encoder = Encoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Audio).unwrap();
+    let pre_skip = u64::from(encoder.lookahead().unwrap());
+    let mut head = b"OpusHead".to_vec();
+    head.extend_from_slice(&[1, 2]);
+    head.extend_from_slice(&(pre_skip as u16).to_le_bytes());
+    head.extend_from_slice(&48_000u32.to_le_bytes());
+    head.extend_from_slice(&[0, 0, 0]);
+    let mut tags = b"OpusTags".to_vec();
+    tags.extend_from_slice(&[0; 8]);
+    let mut writer = PacketWriter::new(Vec::new());
+    writer
+      .write_packet(head.into_boxed_slice(), 1, PacketWriteEndInfo::EndPage, 0)
+      .unwrap();
+    writer
+      .write_packet(tags.into_boxed_slice(), 1, PacketWriteEndInfo::EndPage, 0)
+      .unwrap();
+    let end = pre_skip + frames;
+    let packets = end.div_ceil(PACKET_FRAMES);
+    for packet in 0..packets {
+      let input: Vec<i16> = (packet * PACKET_FRAMES..(packet + 1) * PACKET_FRAMES)
+        .flat_map(|frame| {
+          let t = frame as f64 / 48_000.0;
+          let sample = ((t * 440.0 * 2.0 * std::f64::consts::PI).sin() * 8000.0) as i16;
+          vec![sample, sample]
+        })
+        .collect();
+      let mut output = vec![0u8; 4000];
+      let len = encoder.encode(&input, &mut output).unwrap();
+      output.truncate(len);
+      let (info, granule) = if packet + 1 == packets {
+        (PacketWriteEndInfo::EndStream, end)
+      } else {
+        (PacketWriteEndInfo::NormalPacket, (packet + 1) * PACKET_FRAMES)
+      };
+      writer
+        .write_packet(output.into_boxed_slice(), 1, info, granule)
+        .unwrap();
+    }
+    writer.into_inner()
+  }
+
+  #[cfg(feature = "opus")]
+  #[test]
+  fn decodes_and_seeks_opus() {
+    let song = opus_song(48_000 + 500);
+    let mut decoder = Decoder::new(song.clone()).unwrap();
+    assert_eq!(
+      decoder.format(),
+      PcmFormat {
+        sample_rate: 48_000,
+        channels: 2
+      }
+    );
+    assert_eq!(decoder.duration(), Some(decoder.format().duration_of(48_500)));
+    let mut frames = 0;
+    let mut loudest = 0;
+    while let Some(samples) = decoder.next_frames().unwrap() {
+      frames += samples.len() / 2;
+      loudest = samples.iter().map(|s| s.abs()).max().unwrap_or(0).max(loudest);
+    }
+    assert_eq!(frames, 48_500);
+    assert!(loudest > 4000, "decoded silence");
+    let mut decoder = Decoder::new(song).unwrap();
+    decoder.seek(Duration::from_millis(750)).unwrap();
+    let mut frames = 0;
+    while let Some(samples) = decoder.next_frames().unwrap() {
+      frames += samples.len() / 2;
+    }
+    assert_eq!(frames, 48_500 - 36_000);
+    assert!(decoder.seek(Duration::from_secs(2)).is_err());
+  }
+}
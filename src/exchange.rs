@@ -0,0 +1,796 @@
+use crate::manifest::SongHash;
+use crate::store::{ChunkHash, ChunkStore, StoreError, CHUNK_SIZE};
+use futures::{future, prelude::*};
+use futures03::{compat::Compat, TryFutureExt as _};
+use futures_timer::Delay;
+use libp2p::core::{upgrade, ConnectedPoint, InboundUpgrade, OutboundUpgrade, PeerId, UpgradeInfo};
+use libp2p::swarm::{
+  NetworkBehaviour, NetworkBehaviourAction, OneShotHandler, PollParameters, ProtocolsHandler,
+};
+use libp2p::tokio_io::{AsyncRead, AsyncWrite};
+use libp2p::Multiaddr;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{error, fmt, io, iter};
+
+const PROTOCOL_NAME: &[u8] = b"/radiopeer/exchange/1.0.0";
+/// Largest message we accept: one chunk plus some room for the envelope.
+const MAX_MESSAGE_SIZE: usize = CHUNK_SIZE + 64 * 1024;
+/// Chunks requested from a single peer at the same time.
+const MAX_IN_FLIGHT_PER_PEER: usize = 8;
+/// Blocks being sent to a single peer at the same time.
+const MAX_SENDING_PER_PEER: usize = 2;
+/// Chunks a single peer may have on its want-list; wants past it are a protocol error.
+const MAX_WANTS_PER_PEER: usize = 1024;
+/// After this long without an answer a request is handed to another provider.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// A message of the block exchange protocol. Every message is sent on its own substream.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ExchangeMessage {
+  /// Asks for the ordered chunk list of a song.
+  WantIndex(SongHash),
+  /// The ordered chunk list of a song.
+  Index(SongHash, Vec<ChunkHash>),
+  /// Adds chunks to the sender's want-list.
+  Want(Vec<ChunkHash>),
+  /// Removes chunks from the sender's want-list.
+  Cancel(Vec<ChunkHash>),
+  /// A chunk from the receiver's want-list.
+  Block(ChunkHash, Vec<u8>),
+  /// The sender does not have these chunks or song indexes.
+  DontHave(Vec<Vec<u8>>),
+}
+
+/// Implementation of the inbound side of the block exchange protocol.
+#[derive(Debug, Clone, Default)]
+pub struct ExchangeConfig {}
+
+impl UpgradeInfo for ExchangeConfig {
+  type Info = &'static [u8];
+  type InfoIter = iter::Once<Self::Info>;
+
+  fn protocol_info(&self) -> Self::InfoIter {
+    iter::once(PROTOCOL_NAME)
+  }
+}
+
+impl<TSocket> InboundUpgrade<TSocket> for ExchangeConfig
+where
+  TSocket: AsyncRead + AsyncWrite,
+{
+  type Output = ExchangeMessage;
+  type Error = ExchangeDecodeError;
+  type Future = upgrade::ReadOneThen<
+    upgrade::Negotiated<TSocket>,
+    (),
+    fn(Vec<u8>, ()) -> Result<ExchangeMessage, ExchangeDecodeError>,
+  >;
+
+  fn upgrade_inbound(self, socket: upgrade::Negotiated<TSocket>, _: Self::Info) -> Self::Future {
+    upgrade::read_one_then(socket, MAX_MESSAGE_SIZE, (), |packet, ()| {
+      Ok(bincode::deserialize(&packet)?)
+    })
+  }
+}
+
+impl UpgradeInfo for ExchangeMessage {
+  type Info = &'static [u8];
+  type InfoIter = iter::Once<Self::Info>;
+
+  fn protocol_info(&self) -> Self::InfoIter {
+    iter::once(PROTOCOL_NAME)
+  }
+}
+
+impl<TSocket> OutboundUpgrade<TSocket> for ExchangeMessage
+where
+  TSocket: AsyncWrite + AsyncRead,
+{
+  type Output = Sent;
+  type Error = io::Error;
+  type Future = future::Map<upgrade::WriteOne<upgrade::Negotiated<TSocket>>, fn(()) -> Sent>;
+
+  fn upgrade_outbound(self, socket: upgrade::Negotiated<TSocket>, _: Self::Info) -> Self::Future {
+    let sent: fn(()) -> Sent = match self {
+      ExchangeMessage::Block(..) => |()| Sent::Block,
+      _ => |()| Sent::Other,
+    };
+    let bytes = bincode::serialize(&self).expect("Serializing an ExchangeMessage cannot fail");
+    upgrade::write_one(socket, bytes).map(sent)
+  }
+}
+
+/// Kind of message an outbound substream delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sent {
+  Block,
+  Other,
+}
+
+#[derive(Debug)]
+pub enum ExchangeDecodeError {
+  /// Error when reading the packet from the socket.
+  ReadError(upgrade::ReadOneError),
+  /// Error when decoding the message.
+  Decode(bincode::Error),
+}
+
+impl From<upgrade::ReadOneError> for ExchangeDecodeError {
+  fn from(err: upgrade::ReadOneError) -> Self {
+    ExchangeDecodeError::ReadError(err)
+  }
+}
+
+impl From<bincode::Error> for ExchangeDecodeError {
+  fn from(err: bincode::Error) -> Self {
+    ExchangeDecodeError::Decode(err)
+  }
+}
+
+impl fmt::Display for ExchangeDecodeError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ExchangeDecodeError::ReadError(err) => write!(f, "Error while reading from socket: {}", err),
+      ExchangeDecodeError::Decode(err) => write!(f, "Error while decoding message: {}", err),
+    }
+  }
+}
+
+impl error::Error for ExchangeDecodeError {
+  fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+    match self {
+      ExchangeDecodeError::ReadError(err) => Some(err),
+      ExchangeDecodeError::Decode(err) => Some(err),
+    }
+  }
+}
+
+/// Transmission between the `OneShotHandler` and the `BlockExchange` behaviour.
+pub enum InnerMessage {
+  /// We received a message from a remote.
+  Rx(ExchangeMessage),
+  /// We successfully sent a message.
+  Sent(Sent),
+}
+
+impl From<ExchangeMessage> for InnerMessage {
+  fn from(message: ExchangeMessage) -> InnerMessage {
+    InnerMessage::Rx(message)
+  }
+}
+
+impl From<Sent> for InnerMessage {
+  fn from(sent: Sent) -> InnerMessage {
+    InnerMessage::Sent(sent)
+  }
+}
+
+/// Event that can be emitted by the block exchange.
+#[derive(Debug)]
+pub enum ExchangeEvent {
+  /// A verified chunk of a song was received and stored.
+  BlockReceived { peer_id: PeerId, song: SongHash, chunk: ChunkHash },
+  /// A peer sent a chunk or song index that does not match its hash.
+  BadBlock { peer_id: PeerId, hash: Vec<u8> },
+  /// A peer did not answer a request in time.
+  Timeout { peer_id: PeerId, hash: Vec<u8> },
+  /// A peer asked for more chunks than its want-list holds.
+  TooManyWants { peer_id: PeerId },
+  /// All chunks of a song are in the local store.
+  SongComplete(SongHash),
+  /// None of the providers could serve the song.
+  SongUnavailable(SongHash),
+  /// The local store failed while serving or storing a chunk.
+  StoreFailed(StoreError),
+}
+
+/// State of a song being downloaded.
+struct Download {
+  /// Peers that can be asked for the song, in order of preference.
+  providers: Vec<PeerId>,
+  /// Providers that failed to serve the song; they are not asked again.
+  exhausted: HashSet<PeerId>,
+  /// Provider the song index was requested from.
+  index_request: Option<(PeerId, Instant)>,
+  /// Whether the song index is known.
+  has_index: bool,
+  /// Chunks not requested yet.
+  missing: VecDeque<ChunkHash>,
+  /// Chunks requested and not received yet.
+  in_flight: HashMap<ChunkHash, (PeerId, Instant)>,
+}
+
+/// Chunks a remote asked for, in order, with a set to find them quickly.
+#[derive(Default)]
+struct WantList {
+  queue: VecDeque<ChunkHash>,
+  chunks: HashSet<ChunkHash>,
+}
+
+impl WantList {
+  fn is_empty(&self) -> bool {
+    self.queue.is_empty()
+  }
+
+  fn is_full(&self) -> bool {
+    self.queue.len() >= MAX_WANTS_PER_PEER
+  }
+
+  fn contains(&self, chunk: &[u8]) -> bool {
+    self.chunks.contains(chunk)
+  }
+
+  fn push(&mut self, chunk: ChunkHash) {
+    if self.chunks.insert(chunk.clone()) {
+      self.queue.push_back(chunk);
+    }
+  }
+
+  fn pop(&mut self) -> Option<ChunkHash> {
+    let chunk = self.queue.pop_front()?;
+    self.chunks.remove(&chunk);
+    Some(chunk)
+  }
+
+  fn cancel(&mut self, chunks: &[ChunkHash]) {
+    let before = self.chunks.len();
+    for chunk in chunks {
+      self.chunks.remove(chunk);
+    }
+    if self.chunks.len() != before {
+      let kept = &self.chunks;
+      self.queue.retain(|c| kept.contains(c));
+    }
+  }
+}
+
+impl Download {
+  fn usable_providers(&self) -> impl Iterator<Item = &PeerId> {
+    let exhausted = &self.exhausted;
+    self.providers.iter().filter(move |p| !exhausted.contains(p))
+  }
+
+  fn in_flight_to(&self, peer_id: &PeerId) -> usize {
+    self.in_flight.values().filter(|(p, _)| p == peer_id).count()
+  }
+}
+
+/// Network behaviour that exchanges song chunks with other peers.
+///
+/// Peers keep a want-list for each remote: chunks are served one at a time, so a
+/// `Cancel` removes whatever has not been sent yet. Downloads spread the missing
+/// chunks of a song over all its providers and move them to another provider when
+/// one times out, disconnects or sends a corrupted block.
+pub struct BlockExchange<TSubstream> {
+  store: Arc<ChunkStore>,
+  events: VecDeque<NetworkBehaviourAction<ExchangeMessage, ExchangeEvent>>,
+  connected_peers: HashSet<PeerId>,
+  /// Messages waiting for a connection to the peer.
+  pending: HashMap<PeerId, Vec<ExchangeMessage>>,
+  /// Chunks each remote asked for that have not been sent yet.
+  want_lists: HashMap<PeerId, WantList>,
+  /// Number of blocks being sent to each remote.
+  sending: HashMap<PeerId, usize>,
+  downloads: HashMap<SongHash, Download>,
+  next_timeout_check: Compat<Delay>,
+  marker: PhantomData<TSubstream>,
+}
+
+impl<TSubstream> BlockExchange<TSubstream> {
+  pub fn new(store: Arc<ChunkStore>) -> Self {
+    BlockExchange {
+      store,
+      events: VecDeque::new(),
+      connected_peers: HashSet::new(),
+      pending: HashMap::new(),
+      want_lists: HashMap::new(),
+      sending: HashMap::new(),
+      downloads: HashMap::new(),
+      next_timeout_check: Delay::new(Duration::from_secs(1)).compat(),
+      marker: PhantomData,
+    }
+  }
+
+  pub fn store(&self) -> &Arc<ChunkStore> {
+    &self.store
+  }
+
+  /// Starts downloading `song` from `providers`, or adds providers to a running download.
+  pub fn fetch(&mut self, song: SongHash, providers: Vec<PeerId>) {
+    if self.store.is_complete(&song) {
+      self.emit(ExchangeEvent::SongComplete(song));
+      return;
+    }
+    let download = self.downloads.entry(song.clone()).or_insert_with(|| Download {
+      providers: Vec::new(),
+      exhausted: HashSet::new(),
+      index_request: None,
+      has_index: false,
+      missing: VecDeque::new(),
+      in_flight: HashMap::new(),
+    });
+    for provider in providers {
+      if !download.providers.contains(&provider) {
+        download.providers.push(provider);
+      }
+    }
+    if !download.has_index {
+      if let Ok(missing) = self.missing_chunks(&song) {
+        let download = self.downloads.get_mut(&song).expect("inserted above");
+        download.has_index = true;
+        download.missing = missing.into();
+      }
+    }
+    self.schedule(&song);
+  }
+
+  /// Stops downloading `song`, cancelling the chunks still requested from other peers.
+  pub fn cancel(&mut self, song: &[u8]) {
+    if let Some(download) = self.downloads.remove(song) {
+      let mut cancels: HashMap<PeerId, Vec<ChunkHash>> = HashMap::new();
+      for (chunk, (peer_id, _)) in download.in_flight {
+        cancels.entry(peer_id).or_default().push(chunk);
+      }
+      for (peer_id, chunks) in cancels {
+        self.send(peer_id, ExchangeMessage::Cancel(chunks));
+      }
+    }
+  }
+
+  /// Reorders the providers of every download with `sort`; the first providers
+  /// are handed the most chunks.
+  pub fn sort_providers(&mut self, sort: impl Fn(&mut [PeerId])) {
+    for download in self.downloads.values_mut() {
+      sort(&mut download.providers);
+    }
+  }
+
+  /// Returns the songs currently being downloaded.
+  pub fn downloads(&self) -> impl Iterator<Item = &SongHash> {
+    self.downloads.keys()
+  }
+
+  fn missing_chunks(&self, song: &[u8]) -> Result<Vec<ChunkHash>, StoreError> {
+    let chunks = self.store.chunks(song)?;
+    Ok(chunks.into_iter().filter(|c| !self.store.has_chunk(c)).collect())
+  }
+
+  fn emit(&mut self, event: ExchangeEvent) {
+    self
+      .events
+      .push_back(NetworkBehaviourAction::GenerateEvent(event));
+  }
+
+  fn send(&mut self, peer_id: PeerId, message: ExchangeMessage) {
+    if self.connected_peers.contains(&peer_id) {
+      self.events.push_back(NetworkBehaviourAction::SendEvent {
+        peer_id,
+        event: message,
+      });
+    } else {
+      let pending = self.pending.entry(peer_id.clone()).or_default();
+      if pending.is_empty() {
+        self
+          .events
+          .push_back(NetworkBehaviourAction::DialPeer { peer_id });
+      }
+      pending.push(message);
+    }
+  }
+
+  /// Hands out the missing chunks of a song to its providers.
+  fn schedule(&mut self, song: &[u8]) {
+    let download = match self.downloads.get_mut(song) {
+      Some(download) => download,
+      None => return,
+    };
+    let now = Instant::now();
+    if !download.has_index {
+      if download.index_request.is_none() {
+        let provider = download.usable_providers().next().cloned();
+        match provider {
+          Some(peer_id) => {
+            download.index_request = Some((peer_id.clone(), now));
+            self.send(peer_id, ExchangeMessage::WantIndex(song.to_vec()));
+          }
+          None => {
+            self.downloads.remove(song);
+            self.emit(ExchangeEvent::SongUnavailable(song.to_vec()));
+          }
+        }
+      }
+      return;
+    }
+
+    let mut wants: HashMap<PeerId, Vec<ChunkHash>> = HashMap::new();
+    while let Some(chunk) = download.missing.front().cloned() {
+      let peer_id = download
+        .usable_providers()
+        .map(|p| (download.in_flight_to(p), p))
+        .filter(|(n, _)| *n < MAX_IN_FLIGHT_PER_PEER)
+        .min_by_key(|(n, _)| *n)
+        .map(|(_, p)| p.clone());
+      let peer_id = match peer_id {
+        Some(peer_id) => peer_id,
+        None => break,
+      };
+      download.missing.pop_front();
+      download.in_flight.insert(chunk.clone(), (peer_id.clone(), now));
+      wants.entry(peer_id).or_default().push(chunk);
+    }
+
+    let done = download.missing.is_empty() && download.in_flight.is_empty();
+    let stuck = download.in_flight.is_empty() && download.usable_providers().next().is_none();
+    for (peer_id, chunks) in wants {
+      self.send(peer_id, ExchangeMessage::Want(chunks));
+    }
+    if done {
+      self.downloads.remove(song);
+      self.emit(ExchangeEvent::SongComplete(song.to_vec()));
+    } else if stuck {
+      self.downloads.remove(song);
+      self.emit(ExchangeEvent::SongUnavailable(song.to_vec()));
+    }
+  }
+
+  /// Returns a request to the queue of its download, so another provider is asked.
+  fn requeue(&mut self, peer_id: &PeerId, hash: &[u8], exhaust: bool) -> Option<SongHash> {
+    for (song, download) in self.downloads.iter_mut() {
+      if download
+        .index_request
+        .as_ref()
+        .is_some_and(|(p, _)| p == peer_id)
+        && song.as_slice() == hash
+      {
+        download.index_request = None;
+      } else if download
+        .in_flight
+        .get(hash)
+        .is_some_and(|(p, _)| p == peer_id)
+      {
+        download.in_flight.remove(hash);
+        download.missing.push_back(hash.to_vec());
+      } else {
+        continue;
+      }
+      if exhaust {
+        download.exhausted.insert(peer_id.clone());
+      }
+      return Some(song.clone());
+    }
+    None
+  }
+
+  fn handle_message(&mut self, peer_id: PeerId, message: ExchangeMessage) {
+    match message {
+      ExchangeMessage::WantIndex(song) => match self.store.chunks(&song) {
+        Ok(chunks) => self.send(peer_id, ExchangeMessage::Index(song, chunks)),
+        Err(_) => self.send(peer_id, ExchangeMessage::DontHave(vec![song])),
+      },
+      ExchangeMessage::Want(chunks) => {
+        let want_list = self.want_lists.entry(peer_id.clone()).or_default();
+        let mut dont_have = Vec::new();
+        let mut excess = false;
+        for chunk in chunks {
+          if want_list.contains(&chunk) {
+            continue;
+          }
+          if want_list.is_full() {
+            // Answered so the peer asks someone else instead of waiting.
+            excess = true;
+            dont_have.push(chunk);
+          } else if self.store.has_chunk(&chunk) {
+            want_list.push(chunk);
+          } else {
+            dont_have.push(chunk);
+          }
+        }
+        if !dont_have.is_empty() {
+          self.send(peer_id.clone(), ExchangeMessage::DontHave(dont_have));
+        }
+        if excess {
+          self.emit(ExchangeEvent::TooManyWants { peer_id });
+        }
+      }
+      ExchangeMessage::Cancel(chunks) => {
+        if let Some(want_list) = self.want_lists.get_mut(&peer_id) {
+          want_list.cancel(&chunks);
+        }
+      }
+      ExchangeMessage::Index(song, chunks) => {
+        let download = match self.downloads.get_mut(&song) {
+          Some(download) if !download.has_index => download,
+          _ => return,
+        };
+        download.index_request = None;
+        match self.store.put_index(&song, &chunks) {
+          Ok(()) => {
+            download.has_index = true;
+            let store = &self.store;
+            download.missing = chunks.into_iter().filter(|c| !store.has_chunk(c)).collect();
+          }
+          Err(_) => {
+            download.exhausted.insert(peer_id.clone());
+            self.emit(ExchangeEvent::BadBlock {
+              peer_id,
+              hash: song.clone(),
+            });
+          }
+        }
+        self.schedule(&song);
+      }
+      ExchangeMessage::Block(hash, data) => {
+        let song = self
+          .downloads
+          .iter()
+          .find(|(_, d)| d.in_flight.get(&hash).is_some_and(|(p, _)| p == &peer_id))
+          .map(|(song, _)| song.clone());
+        // Blocks we did not ask this peer for are dropped.
+        let song = match song {
+          Some(song) => song,
+          None => return,
+        };
+        match self.store.put_chunk(&hash, &data) {
+          Ok(()) => {
+            if let Some(download) = self.downloads.get_mut(&song) {
+              download.in_flight.remove(&hash);
+            }
+            self.emit(ExchangeEvent::BlockReceived {
+              peer_id,
+              song: song.clone(),
+              chunk: hash,
+            });
+          }
+          Err(StoreError::Corrupted(_)) => {
+            self.requeue(&peer_id, &hash, true);
+            self.emit(ExchangeEvent::BadBlock { peer_id, hash });
+          }
+          Err(err) => {
+            self.requeue(&peer_id, &hash, false);
+            self.emit(ExchangeEvent::StoreFailed(err));
+          }
+        }
+        self.schedule(&song);
+      }
+      ExchangeMessage::DontHave(hashes) => {
+        let songs: HashSet<_> = hashes
+          .iter()
+          .filter_map(|hash| self.requeue(&peer_id, hash, true))
+          .collect();
+        for song in songs {
+          self.schedule(&song);
+        }
+      }
+    }
+  }
+
+  /// Moves requests that were not answered in time to other providers.
+  fn check_timeouts(&mut self) {
+    let now = Instant::now();
+    let mut expired = Vec::new();
+    for (song, download) in self.downloads.iter() {
+      if let Some((peer_id, sent)) = &download.index_request {
+        if now.duration_since(*sent) >= REQUEST_TIMEOUT {
+          expired.push((peer_id.clone(), song.clone()));
+        }
+      }
+      for (chunk, (peer_id, sent)) in download.in_flight.iter() {
+        if now.duration_since(*sent) >= REQUEST_TIMEOUT {
+          expired.push((peer_id.clone(), chunk.clone()));
+        }
+      }
+    }
+    let mut songs = HashSet::new();
+    for (peer_id, hash) in expired {
+      if let Some(song) = self.requeue(&peer_id, &hash, true) {
+        songs.insert(song);
+      }
+      self.send(peer_id.clone(), ExchangeMessage::Cancel(vec![hash.clone()]));
+      self.emit(ExchangeEvent::Timeout { peer_id, hash });
+    }
+    for song in songs {
+      self.schedule(&song);
+    }
+  }
+
+  /// Hands every request made to `peer_id` to other providers.
+  fn release_peer(&mut self, peer_id: &PeerId, exhaust: bool) {
+    let mut songs = Vec::new();
+    for (song, download) in self.downloads.iter_mut() {
+      let lost: Vec<_> = download
+        .in_flight
+        .iter()
+        .filter(|(_, (p, _))| p == peer_id)
+        .map(|(c, _)| c.clone())
+        .collect();
+      let lost_index = download.index_request.as_ref().is_some_and(|(p, _)| p == peer_id);
+      if lost.is_empty() && !lost_index {
+        continue;
+      }
+      for chunk in lost {
+        download.in_flight.remove(&chunk);
+        download.missing.push_back(chunk);
+      }
+      if lost_index {
+        download.index_request = None;
+      }
+      if exhaust {
+        download.exhausted.insert(peer_id.clone());
+      }
+      songs.push(song.clone());
+    }
+    for song in songs {
+      self.schedule(&song);
+    }
+  }
+
+  /// Sends the next block from the want-list of a peer with room for it.
+  fn next_block(&mut self) -> Option<(PeerId, ExchangeMessage)> {
+    let sending = &self.sending;
+    let peer_id = self
+      .want_lists
+      .iter()
+      .find(|(p, w)| !w.is_empty() && sending.get(*p).cloned().unwrap_or(0) < MAX_SENDING_PER_PEER)
+      .map(|(p, _)| p.clone())?;
+    let hash = self.want_lists.get_mut(&peer_id)?.pop()?;
+    match self.store.get_chunk(&hash) {
+      Ok(data) => {
+        *self.sending.entry(peer_id.clone()).or_insert(0) += 1;
+        Some((peer_id, ExchangeMessage::Block(hash, data)))
+      }
+      Err(StoreError::Corrupted(_)) | Err(StoreError::MissingChunk(_)) => {
+        Some((peer_id, ExchangeMessage::DontHave(vec![hash])))
+      }
+      Err(err) => {
+        self.emit(ExchangeEvent::StoreFailed(err));
+        Some((peer_id, ExchangeMessage::DontHave(vec![hash])))
+      }
+    }
+  }
+}
+
+impl<TSubstream> NetworkBehaviour for BlockExchange<TSubstream>
+where
+  TSubstream: AsyncRead + AsyncWrite,
+{
+  type ProtocolsHandler = OneShotHandler<TSubstream, ExchangeConfig, ExchangeMessage, InnerMessage>;
+  type OutEvent = ExchangeEvent;
+
+  fn new_handler(&mut self) -> Self::ProtocolsHandler {
+    Default::default()
+  }
+
+  fn addresses_of_peer(&mut self, _: &PeerId) -> Vec<Multiaddr> {
+    Vec::new()
+  }
+
+  fn inject_connected(&mut self, peer_id: PeerId, _: ConnectedPoint) {
+    self.connected_peers.insert(peer_id.clone());
+    for message in self.pending.remove(&peer_id).unwrap_or_default() {
+      self.send(peer_id.clone(), message);
+    }
+  }
+
+  fn inject_disconnected(&mut self, peer_id: &PeerId, _: ConnectedPoint) {
+    self.connected_peers.remove(peer_id);
+    self.want_lists.remove(peer_id);
+    self.sending.remove(peer_id);
+    self.release_peer(peer_id, false);
+  }
+
+  fn inject_dial_failure(&mut self, peer_id: &PeerId) {
+    self.pending.remove(peer_id);
+    self.release_peer(peer_id, true);
+  }
+
+  fn inject_node_event(&mut self, peer_id: PeerId, event: InnerMessage) {
+    match event {
+      InnerMessage::Rx(message) => self.handle_message(peer_id, message),
+      // Only blocks count against `MAX_SENDING_PER_PEER`.
+      InnerMessage::Sent(Sent::Block) => {
+        if let Some(n) = self.sending.get_mut(&peer_id) {
+          *n = n.saturating_sub(1);
+        }
+      }
+      InnerMessage::Sent(Sent::Other) => {}
+    }
+  }
+
+  fn poll(
+    &mut self,
+    _: &mut impl PollParameters,
+  ) -> Async<NetworkBehaviourAction<<Self::ProtocolsHandler as ProtocolsHandler>::InEvent, Self::OutEvent>>
+  {
+    loop {
+      match self.next_timeout_check.poll() {
+        Ok(Async::NotReady) => break,
+        Ok(Async::Ready(_)) => {
+          self.check_timeouts();
+          self.next_timeout_check = Delay::new(Duration::from_secs(1)).compat();
+        }
+        Err(err) => {
+          println!("Block exchange timer errored: {:?}", err);
+          break;
+        }
+      }
+    }
+    if let Some(event) = self.events.pop_front() {
+      return Async::Ready(event);
+    }
+    if let Some((peer_id, message)) = self.next_block() {
+      return Async::Ready(NetworkBehaviourAction::SendEvent {
+        peer_id,
+        event: message,
+      });
+    }
+    Async::NotReady
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::fs;
+
+  type Exchange = BlockExchange<io::Cursor<Vec<u8>>>;
+
+  fn exchange(name: &str) -> (Exchange, std::path::PathBuf) {
+    let home = std::env::temp_dir().join(format!("radiopeer-{}-{}", name, std::process::id()));
+    let store = ChunkStore::open(&home).unwrap();
+    (BlockExchange::new(Arc::new(store)), home)
+  }
+
+  fn want_len(exchange: &Exchange, peer_id: &PeerId) -> usize {
+    exchange.want_lists.get(peer_id).map_or(0, |w| w.queue.len())
+  }
+
+  #[test]
+  fn want_list_is_capped() {
+    let (mut exchange, home) = exchange("wants");
+    let peer_id = PeerId::random();
+    let chunks: Vec<ChunkHash> = (0..MAX_WANTS_PER_PEER as u32 + 10)
+      .map(|i| {
+        let data = i.to_be_bytes();
+        let hash = crate::store::chunk_hash(&data);
+        exchange.store.put_chunk(&hash, &data).unwrap();
+        hash
+      })
+      .collect();
+    exchange.handle_message(peer_id.clone(), ExchangeMessage::Want(chunks[..10].to_vec()));
+    // Wanting the same chunks again does not queue them twice.
+    exchange.handle_message(peer_id.clone(), ExchangeMessage::Want(chunks[..10].to_vec()));
+    assert_eq!(want_len(&exchange, &peer_id), 10);
+    assert!(exchange.events.is_empty());
+    exchange.handle_message(peer_id.clone(), ExchangeMessage::Want(chunks.clone()));
+    assert_eq!(want_len(&exchange, &peer_id), MAX_WANTS_PER_PEER);
+    let too_many = exchange.events.iter().any(|event| match event {
+      NetworkBehaviourAction::GenerateEvent(ExchangeEvent::TooManyWants { peer_id: p }) => {
+        *p == peer_id
+      }
+      _ => false,
+    });
+    assert!(too_many);
+    exchange.handle_message(peer_id.clone(), ExchangeMessage::Cancel(chunks[..20].to_vec()));
+    assert_eq!(want_len(&exchange, &peer_id), MAX_WANTS_PER_PEER - 20);
+    let want_list = &exchange.want_lists[&peer_id];
+    assert_eq!(want_list.chunks.len(), want_list.queue.len());
+    let _ = fs::remove_dir_all(home);
+  }
+
+  #[test]
+  fn only_blocks_count_as_sending() {
+    let (mut exchange, home) = exchange("sending");
+    let peer_id = PeerId::random();
+    exchange.sending.insert(peer_id.clone(), 1);
+    exchange.inject_node_event(peer_id.clone(), InnerMessage::Sent(Sent::Other));
+    assert_eq!(exchange.sending[&peer_id], 1);
+    exchange.inject_node_event(peer_id.clone(), InnerMessage::Sent(Sent::Block));
+    assert_eq!(exchange.sending[&peer_id], 0);
+    let _ = fs::remove_dir_all(home);
+  }
+}
@@ -0,0 +1,312 @@
+use crate::manifest::{Manifest, ManifestError, PeerID, SignedManifest};
+use crate::playback::unix_millis;
+use crate::store::sha256;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::{self, prelude::*, BufReader, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use std::{error, fmt};
+
+/// A manifest this node accepted, linked to the entry before it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryEntry {
+  /// SHA2-256 multihash of the previous entry; empty for the first.
+  pub prev: Vec<u8>,
+  /// When the manifest was accepted, in milliseconds since the Unix epoch.
+  pub accepted_at: u64,
+  /// The admins that signed the manifest, the primary signer first.
+  pub signers: Vec<PeerID>,
+  /// The manifest as it was accepted, so its signatures can be checked again.
+  pub signed: SignedManifest,
+}
+
+impl HistoryEntry {
+  pub fn manifest(&self) -> Result<Manifest, ManifestError> {
+    self.signed.open().map(|(_, manifest)| manifest)
+  }
+
+  /// Returns the signers that are valid peer ids.
+  pub fn signers(&self) -> Vec<PeerId> {
+    self
+      .signers
+      .iter()
+      .filter_map(|signer| PeerId::from_bytes(signer.clone()).ok())
+      .collect()
+  }
+}
+
+#[derive(Debug)]
+pub enum HistoryError {
+  Io(io::Error),
+  Decode(bincode::Error),
+  Manifest(ManifestError),
+  /// The entry at this index does not point to the one before it; the log was tampered with.
+  BrokenLink(usize),
+}
+
+impl fmt::Display for HistoryError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      HistoryError::Io(err) => write!(f, "Cannot access the manifest history: {}", err),
+      HistoryError::Decode(err) => write!(f, "Cannot decode the manifest history: {}", err),
+      HistoryError::Manifest(err) => write!(f, "{}", err),
+      HistoryError::BrokenLink(index) => write!(
+        f,
+        "Manifest history entry {} does not follow the one before it",
+        index
+      ),
+    }
+  }
+}
+
+impl error::Error for HistoryError {
+  fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+    match self {
+      HistoryError::Io(err) => Some(err),
+      HistoryError::Decode(err) => Some(err),
+      HistoryError::Manifest(err) => Some(err),
+      HistoryError::BrokenLink(_) => None,
+    }
+  }
+}
+
+impl From<io::Error> for HistoryError {
+  fn from(err: io::Error) -> Self {
+    HistoryError::Io(err)
+  }
+}
+
+impl From<bincode::Error> for HistoryError {
+  fn from(err: bincode::Error) -> Self {
+    HistoryError::Decode(err)
+  }
+}
+
+impl From<ManifestError> for HistoryError {
+  fn from(err: ManifestError) -> Self {
+    HistoryError::Manifest(err)
+  }
+}
+
+/// What is needed to append to the log of a station.
+struct Log {
+  /// Hash of the last entry.
+  head: Vec<u8>,
+  /// Length of the complete entries; the file is truncated to it before
+  /// appending, so an entry cut short is overwritten.
+  len: u64,
+  /// Hashes of the manifest payloads logged, so refetched manifests are logged once.
+  payloads: HashSet<Vec<u8>>,
+}
+
+/// Log of every manifest accepted for the stations this node follows.
+///
+/// Every station has an append-only file in the home directory, where each
+/// entry holds the hash of the one before it, so entries cannot be changed or
+/// dropped without breaking the chain. A write cut short leaves an incomplete
+/// last entry, which is ignored and overwritten by the next entry.
+pub struct History {
+  path: PathBuf,
+  /// Loaded on the first manifest accepted for a station.
+  logs: HashMap<PeerId, Log>,
+}
+
+impl History {
+  pub fn open(home_path: &Path) -> Self {
+    History {
+      path: home_path.join("history"),
+      logs: HashMap::new(),
+    }
+  }
+
+  fn log_path(&self, station: &PeerId) -> PathBuf {
+    self.path.join(station.to_base58())
+  }
+
+  /// Returns the entries of `station`, oldest first, checking the links between them.
+  pub fn entries(&self, station: &PeerId) -> Result<Vec<HistoryEntry>, HistoryError> {
+    let (entries, _) = self.load(station)?;
+    Ok(entries.into_iter().map(|(entry, _)| entry).collect())
+  }
+
+  /// Returns the entries of `station` with their hashes, and the length of the
+  /// file they take up.
+  #[allow(clippy::type_complexity)]
+  fn load(&self, station: &PeerId) -> Result<(Vec<(HistoryEntry, Vec<u8>)>, u64), HistoryError> {
+    let file = match fs::File::open(self.log_path(station)) {
+      Ok(file) => file,
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+      Err(e) => return Err(e.into()),
+    };
+    let mut reader = BufReader::new(file);
+    let mut entries: Vec<(HistoryEntry, Vec<u8>)> = Vec::new();
+    let mut len = 0;
+    loop {
+      let data: Vec<u8> = match bincode::deserialize_from(&mut reader) {
+        Ok(data) => data,
+        // The end of the log, or an entry cut short.
+        Err(e) => match *e {
+          bincode::ErrorKind::Io(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+          _ => return Err(e.into()),
+        },
+      };
+      let entry: HistoryEntry = bincode::deserialize(&data)?;
+      let prev = entries.last().map(|(_, hash)| hash.clone()).unwrap_or_default();
+      if entry.prev != prev {
+        return Err(HistoryError::BrokenLink(entries.len()));
+      }
+      len += bincode::serialized_size(&data)?;
+      entries.push((entry, sha256(&data)));
+    }
+    Ok((entries, len))
+  }
+
+  /// Appends `signed`, a manifest accepted for `station`, unless it was logged already.
+  ///
+  /// Returns true if it was appended.
+  pub fn record(&mut self, station: &PeerId, signed: &SignedManifest) -> Result<bool, HistoryError> {
+    if !self.logs.contains_key(station) {
+      let (entries, len) = self.load(station)?;
+      let log = Log {
+        head: entries.last().map(|(_, hash)| hash.clone()).unwrap_or_default(),
+        len,
+        payloads: entries
+          .iter()
+          .map(|(entry, _)| sha256(&entry.signed.payload))
+          .collect(),
+      };
+      self.logs.insert(station.clone(), log);
+    }
+    let payload = sha256(&signed.payload);
+    if self.logs[station].payloads.contains(&payload) {
+      return Ok(false);
+    }
+    let entry = HistoryEntry {
+      prev: self.logs[station].head.clone(),
+      accepted_at: unix_millis(SystemTime::now()),
+      signers: signed
+        .signers()?
+        .into_iter()
+        .map(PeerId::into_bytes)
+        .collect(),
+      signed: signed.clone(),
+    };
+    let data = bincode::serialize(&entry)?;
+    let record = bincode::serialize(&data)?;
+    fs::create_dir_all(&self.path)?;
+    let mut file = OpenOptions::new()
+      .create(true)
+      .write(true)
+      .truncate(false)
+      .open(self.log_path(station))?;
+    let log = self.logs.get_mut(station).expect("loaded above");
+    file.set_len(log.len)?;
+    file.seek(SeekFrom::Start(log.len))?;
+    file.write_all(&record)?;
+    file.sync_all()?;
+    log.head = sha256(&data);
+    log.len += record.len() as u64;
+    log.payloads.insert(payload);
+    Ok(true)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use libp2p::identity::Keypair;
+
+  struct Home(PathBuf);
+
+  impl Home {
+    fn new(name: &str) -> Self {
+      let path = std::env::temp_dir().join(format!("radiopeer-{}-{}", name, std::process::id()));
+      let _ = fs::remove_dir_all(&path);
+      Home(path)
+    }
+  }
+
+  impl Drop for Home {
+    fn drop(&mut self) {
+      let _ = fs::remove_dir_all(&self.0);
+    }
+  }
+
+  fn versions(keypair: &Keypair, count: u64) -> Vec<SignedManifest> {
+    let mut manifest = Manifest::new(&keypair.public().into_peer_id());
+    (1..=count)
+      .map(|version| {
+        manifest.add_song(vec![version as u8], 1000, format!("song {}", version));
+        manifest.version = version;
+        manifest.sign(keypair).unwrap()
+      })
+      .collect()
+  }
+
+  #[test]
+  fn entries_are_linked_and_logged_once() {
+    let home = Home::new("history-linked");
+    let keypair = Keypair::generate_ed25519();
+    let station = keypair.public().into_peer_id();
+    let signed = versions(&keypair, 2);
+    let mut history = History::open(&home.0);
+    assert!(history.record(&station, &signed[0]).unwrap());
+    assert!(!history.record(&station, &signed[0]).unwrap());
+    assert!(history.record(&station, &signed[1]).unwrap());
+    let mut history = History::open(&home.0);
+    assert!(!history.record(&station, &signed[1]).unwrap());
+    let entries = history.entries(&station).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert!(entries[0].prev.is_empty());
+    assert_eq!(entries[0].signers(), vec![station]);
+    assert_eq!(entries[1].manifest().unwrap().version, 2);
+  }
+
+  #[test]
+  fn dropped_entry_breaks_the_chain() {
+    let home = Home::new("history-broken");
+    let keypair = Keypair::generate_ed25519();
+    let station = keypair.public().into_peer_id();
+    let mut history = History::open(&home.0);
+    for signed in versions(&keypair, 2) {
+      history.record(&station, &signed).unwrap();
+    }
+    let path = history.log_path(&station);
+    let data = fs::read(&path).unwrap();
+    let first: Vec<u8> = bincode::deserialize(&data).unwrap();
+    let first_len = bincode::serialized_size(&first).unwrap() as usize;
+    fs::write(&path, &data[first_len..]).unwrap();
+    match history.entries(&station) {
+      Err(HistoryError::BrokenLink(0)) => {}
+      res => panic!("{:?}", res.map(|entries| entries.len())),
+    }
+  }
+
+  #[test]
+  fn entry_cut_short_is_overwritten() {
+    let home = Home::new("history-truncated");
+    let keypair = Keypair::generate_ed25519();
+    let station = keypair.public().into_peer_id();
+    let signed = versions(&keypair, 3);
+    let mut history = History::open(&home.0);
+    history.record(&station, &signed[0]).unwrap();
+    history.record(&station, &signed[1]).unwrap();
+    let path = history.log_path(&station);
+    for cut in &[5, 20] {
+      let data = fs::read(&path).unwrap();
+      fs::write(&path, &data[..data.len() - cut]).unwrap();
+      assert_eq!(History::open(&home.0).entries(&station).unwrap().len(), 1);
+    }
+    let mut history = History::open(&home.0);
+    assert!(history.record(&station, &signed[2]).unwrap());
+    let entries = History::open(&home.0).entries(&station).unwrap();
+    let versions: Vec<u64> = entries
+      .iter()
+      .map(|entry| entry.manifest().unwrap().version)
+      .collect();
+    assert_eq!(versions, vec![1, 3]);
+  }
+}
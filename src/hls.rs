@@ -0,0 +1,489 @@
+use crate::decode::{PcmFormat, Resampler};
+use crate::sink::AudioSink;
+use crate::store::write_atomic;
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Format of every segment, songs are converted to it.
+const SEGMENT_FORMAT: PcmFormat = PcmFormat {
+  sample_rate: 44_100,
+  channels: 2,
+};
+/// Frames per FLAC frame, and so per MP4 sample.
+const FLAC_BLOCK_SIZE: usize = 4096;
+/// Jumps in station time smaller than this are taken as continuous playback.
+const SEEK_TOLERANCE: Duration = Duration::from_millis(50);
+/// Segments out of the playlist are kept a little longer, for clients still fetching them.
+const EXTRA_SEGMENTS: usize = 2;
+/// FLAC codes sample numbers in at most 36 bits, some 18 days at 44.1 kHz.
+const MAX_SAMPLE_NUMBER: u64 = (1 << 36) - 1;
+const PLAYLIST: &str = "live.m3u8";
+const INIT_SEGMENT: &str = "init.mp4";
+
+/// Writes the station we play as an HLS stream into a directory, for browsers
+/// and mobile players. Serve the directory with any static file server.
+///
+/// Segments are fragmented MP4 holding FLAC frames, which HLS allows and which
+/// we can produce without an encoder library: the frames store the samples
+/// verbatim. Segment boundaries fall on multiples of the segment duration in
+/// station time, so every node tuned to a station cuts it at the same points.
+pub struct HlsSink {
+  dir: PathBuf,
+  segment_duration: Duration,
+  window: usize,
+  resampler: Resampler,
+  /// Station time of the next frame, in frames; `None` until the first seek.
+  position: Option<u64>,
+  /// Frames of the segment being filled.
+  pending: Vec<i16>,
+  /// Station time of the first frame in `pending`.
+  pending_start: u64,
+  /// Station time the FLAC frames are numbered from: the first frame we wrote
+  /// or the last discontinuity.
+  stream_start: u64,
+  /// The segment being filled does not follow the previous one.
+  discontinuity: bool,
+  next_sequence: u64,
+  /// Segments in the playlist, oldest first.
+  segments: VecDeque<Segment>,
+  /// Discontinuities that went out of the playlist, for `EXT-X-DISCONTINUITY-SEQUENCE`.
+  discontinuity_sequence: u64,
+}
+
+struct Segment {
+  sequence: u64,
+  duration: Duration,
+  discontinuity: bool,
+}
+
+impl HlsSink {
+  pub fn new(dir: &Path, segment_duration: Duration, window: usize) -> io::Result<Self> {
+    fs::create_dir_all(dir)?;
+    write_atomic(&dir.join(INIT_SEGMENT), &init_segment(SEGMENT_FORMAT))?;
+    println!(
+      "Writing the station as HLS to {}",
+      dir.join(PLAYLIST).display()
+    );
+    Ok(HlsSink {
+      dir: dir.to_path_buf(),
+      segment_duration,
+      window: window.max(1),
+      resampler: Resampler::new(SEGMENT_FORMAT),
+      position: None,
+      pending: Vec::new(),
+      pending_start: 0,
+      stream_start: 0,
+      discontinuity: false,
+      next_sequence: 0,
+      segments: VecDeque::new(),
+      discontinuity_sequence: 0,
+    })
+  }
+
+  fn segment_frames(&self) -> u64 {
+    SEGMENT_FORMAT.frames_in(self.segment_duration).max(1)
+  }
+
+  /// Writes the frames in `pending` as the next segment and updates the playlist.
+  fn cut(&mut self) -> io::Result<()> {
+    if self.pending.is_empty() {
+      return Ok(());
+    }
+    let channels = usize::from(SEGMENT_FORMAT.channels);
+    let frames = (self.pending.len() / channels) as u64;
+    // Start over before the frame numbers outgrow FLAC.
+    if self.pending_start + frames - self.stream_start > MAX_SAMPLE_NUMBER {
+      self.stream_start = self.pending_start;
+      self.discontinuity = true;
+    }
+    let sequence = self.next_sequence;
+    self.next_sequence += 1;
+    let data = media_segment(
+      sequence,
+      self.pending_start,
+      self.pending_start - self.stream_start,
+      &self.pending,
+    );
+    write_atomic(&self.segment_path(sequence), &data)?;
+    self.segments.push_back(Segment {
+      sequence,
+      duration: SEGMENT_FORMAT.duration_of(frames),
+      discontinuity: self.discontinuity,
+    });
+    self.discontinuity = false;
+    self.pending_start += frames;
+    self.pending.clear();
+    while self.segments.len() > self.window {
+      let old = self.segments.pop_front().expect("the window is not empty");
+      if old.discontinuity {
+        self.discontinuity_sequence += 1;
+      }
+      if let Some(stale) = old.sequence.checked_sub(EXTRA_SEGMENTS as u64) {
+        // Might already be gone if the directory was cleaned up under us.
+        let _ = fs::remove_file(self.segment_path(stale));
+      }
+    }
+    write_atomic(&self.dir.join(PLAYLIST), self.playlist().as_bytes())
+  }
+
+  fn segment_path(&self, sequence: u64) -> PathBuf {
+    self.dir.join(format!("{}.m4s", sequence))
+  }
+
+  fn playlist(&self) -> String {
+    let target = self
+      .segments
+      .iter()
+      .map(|s| s.duration)
+      .max()
+      .unwrap_or(self.segment_duration);
+    let first = self.segments.front().map_or(0, |s| s.sequence);
+    let mut playlist = String::from("#EXTM3U\n#EXT-X-VERSION:7\n");
+    playlist.push_str(&format!(
+      "#EXT-X-TARGETDURATION:{}\n",
+      (target.as_millis() as u64).div_ceil(1000)
+    ));
+    playlist.push_str(&format!("#EXT-X-MEDIA-SEQUENCE:{}\n", first));
+    playlist.push_str(&format!(
+      "#EXT-X-DISCONTINUITY-SEQUENCE:{}\n",
+      self.discontinuity_sequence
+    ));
+    playlist.push_str(&format!("#EXT-X-MAP:URI=\"{}\"\n", INIT_SEGMENT));
+    for segment in &self.segments {
+      if segment.discontinuity {
+        playlist.push_str("#EXT-X-DISCONTINUITY\n");
+      }
+      playlist.push_str(&format!(
+        "#EXTINF:{:.3},\n{}.m4s\n",
+        segment.duration.as_secs_f64(),
+        segment.sequence
+      ));
+    }
+    playlist
+  }
+}
+
+impl AudioSink for HlsSink {
+  fn write(&mut self, format: PcmFormat, samples: &[i16]) -> io::Result<()> {
+    let position = match self.position {
+      Some(position) => position,
+      // We do not know where in the station this goes.
+      None => return Ok(()),
+    };
+    let samples = self.resampler.process(format, samples);
+    let channels = usize::from(SEGMENT_FORMAT.channels);
+    let segment_frames = self.segment_frames();
+    let mut samples = &samples[..];
+    let mut position = position;
+    while !samples.is_empty() {
+      let boundary = (position / segment_frames + 1) * segment_frames;
+      let frames = ((boundary - position) as usize).min(samples.len() / channels);
+      self.pending.extend_from_slice(&samples[..frames * channels]);
+      samples = &samples[frames * channels..];
+      position += frames as u64;
+      if position == boundary {
+        self.cut()?;
+      }
+    }
+    self.position = Some(position);
+    Ok(())
+  }
+
+  fn seek(&mut self, station_time: Duration) {
+    let target = SEGMENT_FORMAT.frames_in(station_time);
+    if let Some(position) = self.position {
+      let tolerance = SEGMENT_FORMAT.frames_in(SEEK_TOLERANCE);
+      if position.max(target) - position.min(target) <= tolerance {
+        return;
+      }
+      // Keep what we have of the interrupted segment.
+      if let Err(e) = self.cut() {
+        println!("Cannot write HLS segment: {}", e);
+      }
+      self.discontinuity = true;
+    }
+    self.position = Some(target);
+    self.pending.clear();
+    self.pending_start = target;
+    self.stream_start = target;
+  }
+}
+
+fn mp4_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+  let mut b = Vec::with_capacity(payload.len() + 8);
+  b.extend_from_slice(&(payload.len() as u32 + 8).to_be_bytes());
+  b.extend_from_slice(kind);
+  b.extend_from_slice(payload);
+  b
+}
+
+fn full_box(kind: &[u8; 4], version: u8, flags: u32, payload: &[u8]) -> Vec<u8> {
+  let mut p = Vec::with_capacity(payload.len() + 4);
+  p.extend_from_slice(&((u32::from(version) << 24) | flags).to_be_bytes());
+  p.extend_from_slice(payload);
+  mp4_box(kind, &p)
+}
+
+fn concat(parts: &[&[u8]]) -> Vec<u8> {
+  parts.concat()
+}
+
+const MATRIX: [u32; 9] = [0x10000, 0, 0, 0, 0x10000, 0, 0, 0, 0x4000_0000];
+
+fn matrix() -> Vec<u8> {
+  MATRIX.iter().flat_map(|v| v.to_be_bytes().to_vec()).collect()
+}
+
+/// The initialization segment: a movie with one FLAC track and no samples.
+fn init_segment(format: PcmFormat) -> Vec<u8> {
+  let ftyp = mp4_box(b"ftyp", &concat(&[b"iso6", &0u32.to_be_bytes(), b"iso6mp41"]));
+
+  let mut mvhd = vec![0u8; 8];
+  mvhd.extend_from_slice(&1000u32.to_be_bytes());
+  mvhd.extend_from_slice(&0u32.to_be_bytes());
+  mvhd.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+  mvhd.extend_from_slice(&0x0100u16.to_be_bytes());
+  mvhd.extend_from_slice(&[0u8; 10]);
+  mvhd.extend_from_slice(&matrix());
+  mvhd.extend_from_slice(&[0u8; 24]);
+  mvhd.extend_from_slice(&2u32.to_be_bytes());
+  let mvhd = full_box(b"mvhd", 0, 0, &mvhd);
+
+  let mut tkhd = vec![0u8; 8];
+  tkhd.extend_from_slice(&1u32.to_be_bytes());
+  tkhd.extend_from_slice(&[0u8; 4]);
+  tkhd.extend_from_slice(&0u32.to_be_bytes());
+  tkhd.extend_from_slice(&[0u8; 8]);
+  tkhd.extend_from_slice(&[0u8; 4]);
+  tkhd.extend_from_slice(&0x0100u16.to_be_bytes());
+  tkhd.extend_from_slice(&[0u8; 2]);
+  tkhd.extend_from_slice(&matrix());
+  tkhd.extend_from_slice(&[0u8; 8]);
+  // Enabled and in movie.
+  let tkhd = full_box(b"tkhd", 0, 3, &tkhd);
+
+  let mut mdhd = vec![0u8; 8];
+  mdhd.extend_from_slice(&format.sample_rate.to_be_bytes());
+  mdhd.extend_from_slice(&0u32.to_be_bytes());
+  // "und"
+  mdhd.extend_from_slice(&0x55c4u16.to_be_bytes());
+  mdhd.extend_from_slice(&[0u8; 2]);
+  let mdhd = full_box(b"mdhd", 0, 0, &mdhd);
+  let hdlr = full_box(
+    b"hdlr",
+    0,
+    0,
+    &concat(&[&[0u8; 4], b"soun", &[0u8; 12], b"SoundHandler\0"]),
+  );
+
+  let smhd = full_box(b"smhd", 0, 0, &[0u8; 4]);
+  let url = full_box(b"url ", 0, 1, &[]);
+  let dref = full_box(b"dref", 0, 0, &concat(&[&1u32.to_be_bytes(), &url]));
+  let dinf = mp4_box(b"dinf", &dref);
+
+  let mut entry = vec![0u8; 6];
+  entry.extend_from_slice(&1u16.to_be_bytes());
+  entry.extend_from_slice(&[0u8; 8]);
+  entry.extend_from_slice(&format.channels.to_be_bytes());
+  entry.extend_from_slice(&16u16.to_be_bytes());
+  entry.extend_from_slice(&[0u8; 4]);
+  entry.extend_from_slice(&(format.sample_rate << 16).to_be_bytes());
+  entry.extend_from_slice(&full_box(b"dfLa", 0, 0, &flac_stream_info(format)));
+  let stsd = full_box(
+    b"stsd",
+    0,
+    0,
+    &concat(&[&1u32.to_be_bytes(), &mp4_box(b"fLaC", &entry)]),
+  );
+  let empty = 0u32.to_be_bytes();
+  let stbl = mp4_box(
+    b"stbl",
+    &concat(&[
+      &stsd,
+      &full_box(b"stts", 0, 0, &empty),
+      &full_box(b"stsc", 0, 0, &empty),
+      &full_box(b"stsz", 0, 0, &[0u8; 8]),
+      &full_box(b"stco", 0, 0, &empty),
+    ]),
+  );
+  let minf = mp4_box(b"minf", &concat(&[&smhd, &dinf, &stbl]));
+  let mdia = mp4_box(b"mdia", &concat(&[&mdhd, &hdlr, &minf]));
+  let trak = mp4_box(b"trak", &concat(&[&tkhd, &mdia]));
+
+  let mut trex = Vec::new();
+  trex.extend_from_slice(&1u32.to_be_bytes());
+  trex.extend_from_slice(&1u32.to_be_bytes());
+  trex.extend_from_slice(&[0u8; 12]);
+  let mvex = mp4_box(b"mvex", &full_box(b"trex", 0, 0, &trex));
+
+  let moov = mp4_box(b"moov", &concat(&[&mvhd, &trak, &mvex]));
+  concat(&[&ftyp, &moov])
+}
+
+/// A media segment holding `samples`, which start `start` frames into the
+/// station and `sample_number` frames into the FLAC stream.
+fn media_segment(sequence: u64, start: u64, sample_number: u64, samples: &[i16]) -> Vec<u8> {
+  let channels = usize::from(SEGMENT_FORMAT.channels);
+  let mut frames = Vec::new();
+  let mut sample_number = sample_number;
+  for block in samples.chunks(FLAC_BLOCK_SIZE * channels) {
+    frames.push(flac_frame(SEGMENT_FORMAT, sample_number, block));
+    sample_number += (block.len() / channels) as u64;
+  }
+
+  let mfhd = full_box(b"mfhd", 0, 0, &(sequence as u32).to_be_bytes());
+  // default-base-is-moof
+  let tfhd = full_box(b"tfhd", 0, 0x02_0000, &1u32.to_be_bytes());
+  let tfdt = full_box(b"tfdt", 1, 0, &start.to_be_bytes());
+  let trun_size = 4 + 8 + 4 + 4 + frames.len() * 8;
+  let moof_size = 8 + mfhd.len() + 8 + tfhd.len() + tfdt.len() + trun_size;
+  let mut trun = Vec::new();
+  trun.extend_from_slice(&(frames.len() as u32).to_be_bytes());
+  // Samples start right after the header of `mdat`.
+  trun.extend_from_slice(&(moof_size as u32 + 8).to_be_bytes());
+  let mut remaining = samples.len() / channels;
+  for frame in &frames {
+    let duration = remaining.min(FLAC_BLOCK_SIZE);
+    remaining -= duration;
+    trun.extend_from_slice(&(duration as u32).to_be_bytes());
+    trun.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+  }
+  // data-offset, sample-duration and sample-size present
+  let trun = full_box(b"trun", 0, 0x000301, &trun);
+  let traf = mp4_box(b"traf", &concat(&[&tfhd, &tfdt, &trun]));
+  let moof = mp4_box(b"moof", &concat(&[&mfhd, &traf]));
+  debug_assert_eq!(moof.len(), moof_size);
+  let mdat = mp4_box(b"mdat", &frames.concat());
+  concat(&[&moof, &mdat])
+}
+
+/// The STREAMINFO metadata block, as the last and only block of the stream header.
+fn flac_stream_info(format: PcmFormat) -> Vec<u8> {
+  let mut info = Vec::with_capacity(38);
+  // Last metadata block, STREAMINFO, 34 bytes long.
+  info.extend_from_slice(&[0x80, 0, 0, 34]);
+  // Blocks are shorter than FLAC_BLOCK_SIZE at the end of segments.
+  info.extend_from_slice(&16u16.to_be_bytes());
+  info.extend_from_slice(&(FLAC_BLOCK_SIZE as u16).to_be_bytes());
+  // Unknown minimum and maximum frame sizes.
+  info.extend_from_slice(&[0u8; 6]);
+  // 20 bits of sample rate, 3 of channels - 1, 5 of bits per sample - 1 and 36
+  // of total samples, left unknown.
+  let packed = (u64::from(format.sample_rate) << 44)
+    | (u64::from(format.channels - 1) << 41)
+    | (15u64 << 36);
+  info.extend_from_slice(&packed.to_be_bytes());
+  // No MD5 of the stream.
+  info.extend_from_slice(&[0u8; 16]);
+  info
+}
+
+/// Encodes interleaved 16-bit samples as a FLAC frame with verbatim subframes.
+///
+/// Frames use the variable block size strategy, which numbers them by their
+/// first sample, counted from the start of the stream; `sample_number` must be
+/// at most `MAX_SAMPLE_NUMBER`.
+fn flac_frame(format: PcmFormat, sample_number: u64, samples: &[i16]) -> Vec<u8> {
+  let channels = usize::from(format.channels);
+  let block_size = samples.len() / channels;
+  let mut frame = vec![0xff, 0xf9];
+  // Block size as 16 bits at the end of the header, sample rate from STREAMINFO.
+  frame.push(0x70);
+  // Independent channels, 16 bits per sample.
+  frame.push(((format.channels as u8 - 1) << 4) | 0x08);
+  frame.extend_from_slice(&utf8_number(sample_number));
+  frame.extend_from_slice(&(block_size as u16 - 1).to_be_bytes());
+  frame.push(crc8(&frame));
+  for channel in 0..channels {
+    // Verbatim subframe, no wasted bits.
+    frame.push(0x02);
+    for sample in samples.iter().skip(channel).step_by(channels) {
+      frame.extend_from_slice(&sample.to_be_bytes());
+    }
+  }
+  let crc = crc16(&frame);
+  frame.extend_from_slice(&crc.to_be_bytes());
+  frame
+}
+
+/// The UTF-8 like variable length coding FLAC uses for frame and sample numbers,
+/// up to `MAX_SAMPLE_NUMBER`.
+fn utf8_number(value: u64) -> Vec<u8> {
+  debug_assert!(value <= MAX_SAMPLE_NUMBER);
+  if value < 0x80 {
+    return vec![value as u8];
+  }
+  let mut continuation = 1;
+  while value >= 1u64 << (6 * continuation + 6 - continuation) {
+    continuation += 1;
+  }
+  let mut bytes = vec![0u8; continuation + 1];
+  let mut v = value;
+  for byte in bytes[1..].iter_mut().rev() {
+    *byte = 0x80 | (v & 0x3f) as u8;
+    v >>= 6;
+  }
+  let marker = !(0xffu8 >> (continuation + 1));
+  bytes[0] = marker | v as u8;
+  bytes
+}
+
+fn crc8(data: &[u8]) -> u8 {
+  let mut crc = 0u8;
+  for &byte in data {
+    crc ^= byte;
+    for _ in 0..8 {
+      crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+    }
+  }
+  crc
+}
+
+fn crc16(data: &[u8]) -> u16 {
+  let mut crc = 0u16;
+  for &byte in data {
+    crc ^= u16::from(byte) << 8;
+    for _ in 0..8 {
+      crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+    }
+  }
+  crc
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn utf8_number_lengths() {
+    assert_eq!(utf8_number(0x7f), vec![0x7f]);
+    assert_eq!(utf8_number(0x80), vec![0xc2, 0x80]);
+    assert_eq!(utf8_number(0x7ff), vec![0xdf, 0xbf]);
+    assert_eq!(utf8_number(0x800), vec![0xe0, 0xa0, 0x80]);
+    assert_eq!(
+      utf8_number(MAX_SAMPLE_NUMBER),
+      vec![0xfe, 0xbf, 0xbf, 0xbf, 0xbf, 0xbf, 0xbf]
+    );
+  }
+
+  #[test]
+  fn frames_are_numbered_from_the_stream_start() {
+    let dir = std::env::temp_dir().join(format!("radiopeer-hls-{}", std::process::id()));
+    let mut sink = HlsSink::new(&dir, Duration::from_secs(1), 3).unwrap();
+    // Three weeks on air, past what FLAC can number in station time.
+    sink.seek(Duration::from_secs(21 * 24 * 3600));
+    sink.write(SEGMENT_FORMAT, &vec![0; 2 * 44_100 * 2]).unwrap();
+    assert!(sink.pending_start - sink.stream_start <= 2 * 44_100);
+    assert!(!sink.segments.is_empty());
+    assert!(sink.segments.iter().all(|segment| !segment.discontinuity));
+    // Continuous playback close to the limit starts a discontinuity.
+    sink.stream_start = sink.pending_start - MAX_SAMPLE_NUMBER + 100;
+    sink.write(SEGMENT_FORMAT, &vec![0; 2 * 44_100 * 2]).unwrap();
+    assert!(sink.segments.iter().any(|segment| segment.discontinuity));
+    assert!(sink.pending_start - sink.stream_start <= 2 * 44_100);
+    let _ = fs::remove_dir_all(dir);
+  }
+}
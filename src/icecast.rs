@@ -0,0 +1,371 @@
+use crate::decode::{PcmFormat, Resampler};
+use crate::sink::AudioSink;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+/// Format of the stream served to every client, songs are converted to it.
+const STREAM_FORMAT: PcmFormat = PcmFormat {
+  sample_rate: 44_100,
+  channels: 2,
+};
+/// Bytes of audio between two ICY metadata blocks.
+const ICY_METAINT: usize = 16_000;
+/// A client whose socket is this far behind is dropped; about two seconds of audio.
+const MAX_CLIENT_BACKLOG: usize = 2 * 44_100 * 4;
+/// Longest request header we accept.
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+/// Time a client has to send its request header.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// A client that takes nothing of its backlog for this long is dropped, even
+/// while the player writes too little for the backlog to overflow.
+const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+/// Clients served at once, including those still sending their request.
+const MAX_CLIENTS: usize = 64;
+
+/// Serves the station we play over HTTP, the way Icecast and SHOUTcast do, so
+/// ordinary media players can listen through this node.
+///
+/// Every GET gets the same continuous WAV stream, whatever path is asked for.
+/// Clients that send `Icy-MetaData: 1` also get the title of the current track
+/// inline, every `ICY_METAINT` bytes of audio. The stream has no end, so its
+/// WAV header announces the largest size the format allows.
+///
+/// The sink is driven by the player: clients are accepted and written to when it
+/// writes or flushes, never blocking. Clients that fall behind or stall, and
+/// those that do not send their request in time, are dropped; past
+/// `MAX_CLIENTS`, new clients are turned away.
+pub struct IcecastSink {
+  listener: TcpListener,
+  clients: Vec<Client>,
+  resampler: Resampler,
+  title: String,
+}
+
+struct Client {
+  stream: TcpStream,
+  addr: SocketAddr,
+  /// Request header, until it is complete.
+  request: Vec<u8>,
+  /// Whether the response header was queued, i.e. the client is streaming.
+  streaming: bool,
+  /// `Some` with the audio bytes left before the next metadata block if the
+  /// client asked for ICY metadata.
+  metadata_in: Option<usize>,
+  /// Title to send in the next metadata block, if it changed.
+  title: Option<String>,
+  backlog: Vec<u8>,
+  accepted_at: Instant,
+  /// Last time the backlog was emptied or shrank.
+  last_progress: Instant,
+}
+
+impl IcecastSink {
+  pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+    let listener = TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    println!("Serving the station on http://{}", listener.local_addr()?);
+    Ok(IcecastSink {
+      listener,
+      clients: Vec::new(),
+      resampler: Resampler::new(STREAM_FORMAT),
+      title: String::new(),
+    })
+  }
+
+  fn accept(&mut self) {
+    loop {
+      match self.listener.accept() {
+        Ok((mut stream, addr)) => {
+          if self.clients.len() >= MAX_CLIENTS {
+            println!("Turning away HTTP client {}: too many clients", addr);
+            let _ = stream.set_nonblocking(true);
+            let _ = stream.write_all(b"HTTP/1.0 503 Service Unavailable\r\n\r\n");
+            continue;
+          }
+          if let Err(e) = stream.set_nonblocking(true) {
+            println!("Dropping HTTP client {}: {}", addr, e);
+            continue;
+          }
+          let now = Instant::now();
+          self.clients.push(Client {
+            stream,
+            addr,
+            request: Vec::new(),
+            streaming: false,
+            metadata_in: None,
+            title: Some(self.title.clone()).filter(|t| !t.is_empty()),
+            backlog: Vec::new(),
+            accepted_at: now,
+            last_progress: now,
+          });
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+        Err(e) => {
+          println!("Cannot accept HTTP client: {}", e);
+          break;
+        }
+      }
+    }
+  }
+
+  /// Reads requests, sends what is queued and drops the clients that are gone or too slow.
+  fn serve(&mut self) {
+    self.accept();
+    self.clients.retain_mut(|client| match client.serve() {
+      Ok(true) => true,
+      Ok(false) => false,
+      Err(e) => {
+        println!("Dropping HTTP client {}: {}", client.addr, e);
+        false
+      }
+    });
+  }
+}
+
+impl Client {
+  /// Returns false once the client should be dropped.
+  fn serve(&mut self) -> io::Result<bool> {
+    if !self.streaming {
+      if !self.read_request()? {
+        return Ok(false);
+      }
+      if !self.streaming && self.accepted_at.elapsed() > REQUEST_TIMEOUT {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "no request"));
+      }
+    }
+    while !self.backlog.is_empty() {
+      match self.stream.write(&self.backlog) {
+        Ok(0) => return Ok(false),
+        Ok(n) => {
+          self.backlog.drain(..n);
+          self.last_progress = Instant::now();
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+        Err(e) => return Err(e),
+      }
+    }
+    if self.backlog.is_empty() {
+      self.last_progress = Instant::now();
+    }
+    if self.backlog.len() > MAX_CLIENT_BACKLOG {
+      return Err(io::Error::new(io::ErrorKind::TimedOut, "too slow"));
+    }
+    if self.last_progress.elapsed() > STALL_TIMEOUT {
+      return Err(io::Error::new(io::ErrorKind::TimedOut, "stalled"));
+    }
+    Ok(true)
+  }
+
+  /// Reads the request header and queues the response header once it is complete.
+  fn read_request(&mut self) -> io::Result<bool> {
+    let mut buf = [0u8; 1024];
+    loop {
+      match self.stream.read(&mut buf) {
+        Ok(0) => return Ok(false),
+        Ok(n) => self.request.extend_from_slice(&buf[..n]),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+        Err(e) => return Err(e),
+      }
+      if self.request.len() > MAX_REQUEST_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "request too large"));
+      }
+    }
+    let request = String::from_utf8_lossy(&self.request);
+    let end = match request.find("\r\n\r\n").or_else(|| request.find("\n\n")) {
+      Some(end) => end,
+      None => return Ok(true),
+    };
+    let mut lines = request[..end].lines();
+    let method = lines.next().and_then(|l| l.split_whitespace().next());
+    if method != Some("GET") {
+      let _ = self
+        .stream
+        .write_all(b"HTTP/1.0 405 Method Not Allowed\r\nAllow: GET\r\n\r\n");
+      return Ok(false);
+    }
+    let wants_metadata = lines.any(|line| {
+      let mut parts = line.splitn(2, ':');
+      let name = parts.next().unwrap_or("").trim();
+      let value = parts.next().unwrap_or("").trim();
+      name.eq_ignore_ascii_case("icy-metadata") && value == "1"
+    });
+    let mut response = String::from("HTTP/1.0 200 OK\r\n");
+    response.push_str("Content-Type: audio/wav\r\n");
+    response.push_str("Cache-Control: no-cache, no-store\r\n");
+    response.push_str("Connection: close\r\n");
+    response.push_str("icy-name: radiopeer\r\n");
+    response.push_str(&format!(
+      "icy-br: {}\r\n",
+      STREAM_FORMAT.sample_rate * u32::from(STREAM_FORMAT.channels) * 16 / 1000
+    ));
+    if wants_metadata {
+      response.push_str(&format!("icy-metaint: {}\r\n", ICY_METAINT));
+      self.metadata_in = Some(ICY_METAINT);
+    }
+    response.push_str("\r\n");
+    self.backlog = response.into_bytes();
+    self.streaming = true;
+    self.request = Vec::new();
+    self.push_audio(&stream_header());
+    Ok(true)
+  }
+
+  /// Queues audio, interleaving the metadata blocks if the client asked for them.
+  fn push_audio(&mut self, mut audio: &[u8]) {
+    let mut metadata_in = match self.metadata_in {
+      Some(metadata_in) => metadata_in,
+      None => return self.backlog.extend_from_slice(audio),
+    };
+    while !audio.is_empty() {
+      let n = metadata_in.min(audio.len());
+      self.backlog.extend_from_slice(&audio[..n]);
+      audio = &audio[n..];
+      metadata_in -= n;
+      if metadata_in == 0 {
+        let block = self.metadata_block();
+        self.backlog.extend_from_slice(&block);
+        metadata_in = ICY_METAINT;
+      }
+    }
+    self.metadata_in = Some(metadata_in);
+  }
+
+  /// Builds the next metadata block; it is empty unless the title changed.
+  fn metadata_block(&mut self) -> Vec<u8> {
+    let title = match &self.title {
+      Some(title) => title.clone(),
+      None => return vec![0],
+    };
+    self.title = None;
+    // Quotes would end the value early, and blocks hold at most 255 * 16 bytes.
+    let title: String = title.replace('\'', "’").chars().take(1000).collect();
+    let mut block = format!("StreamTitle='{}';", title).into_bytes();
+    let len = block.len().div_ceil(16);
+    block.resize(len * 16, 0);
+    block.insert(0, len as u8);
+    block
+  }
+}
+
+/// WAV header of the endless stream.
+fn stream_header() -> Vec<u8> {
+  let block_align = STREAM_FORMAT.channels * 2;
+  let mut header = Vec::with_capacity(44);
+  header.extend_from_slice(b"RIFF");
+  header.extend_from_slice(&u32::MAX.to_le_bytes());
+  header.extend_from_slice(b"WAVEfmt ");
+  header.extend_from_slice(&16u32.to_le_bytes());
+  header.extend_from_slice(&1u16.to_le_bytes());
+  header.extend_from_slice(&STREAM_FORMAT.channels.to_le_bytes());
+  header.extend_from_slice(&STREAM_FORMAT.sample_rate.to_le_bytes());
+  header.extend_from_slice(&(STREAM_FORMAT.sample_rate * u32::from(block_align)).to_le_bytes());
+  header.extend_from_slice(&block_align.to_le_bytes());
+  header.extend_from_slice(&16u16.to_le_bytes());
+  header.extend_from_slice(b"data");
+  header.extend_from_slice(&(u32::MAX - 36).to_le_bytes());
+  header
+}
+
+impl AudioSink for IcecastSink {
+  fn write(&mut self, format: PcmFormat, samples: &[i16]) -> io::Result<()> {
+    let samples = self.resampler.process(format, samples);
+    let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes().to_vec()).collect();
+    for client in self.clients.iter_mut().filter(|c| c.streaming) {
+      client.push_audio(&bytes);
+    }
+    self.serve();
+    Ok(())
+  }
+
+  fn set_title(&mut self, title: &str) {
+    self.title = title.to_string();
+    for client in &mut self.clients {
+      client.title = Some(self.title.clone());
+    }
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.serve();
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sink() -> (IcecastSink, SocketAddr) {
+    let sink = IcecastSink::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = sink.listener.local_addr().unwrap();
+    (sink, addr)
+  }
+
+  fn ago(duration: Duration) -> Instant {
+    Instant::now().checked_sub(duration).unwrap()
+  }
+
+  #[test]
+  fn clients_without_request_time_out() {
+    let (mut sink, addr) = sink();
+    let _client = TcpStream::connect(addr).unwrap();
+    let mut listening = TcpStream::connect(addr).unwrap();
+    listening.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+    while sink.clients.iter().filter(|c| c.streaming).count() < 1 {
+      sink.flush().unwrap();
+    }
+    assert_eq!(sink.clients.len(), 2);
+    for client in &mut sink.clients {
+      client.accepted_at = ago(REQUEST_TIMEOUT * 2);
+    }
+    sink.flush().unwrap();
+    assert_eq!(sink.clients.len(), 1);
+    assert!(sink.clients[0].streaming);
+  }
+
+  #[test]
+  fn stalled_clients_are_dropped() {
+    let (mut sink, addr) = sink();
+    let _client = TcpStream::connect(addr).unwrap();
+    while sink.clients.is_empty() {
+      sink.flush().unwrap();
+    }
+    // The client reads nothing: fill its socket until writing would block.
+    let client = &mut sink.clients[0];
+    let chunk = [0u8; 64 * 1024];
+    loop {
+      match client.stream.write(&chunk) {
+        Ok(_) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+        Err(e) => panic!("{}", e),
+      }
+    }
+    client.streaming = true;
+    client.backlog = vec![0; 16];
+    sink.flush().unwrap();
+    assert_eq!(sink.clients.len(), 1);
+    sink.clients[0].last_progress = ago(STALL_TIMEOUT * 2);
+    sink.flush().unwrap();
+    assert!(sink.clients.is_empty());
+  }
+
+  #[test]
+  fn clients_past_the_cap_are_turned_away() {
+    let (mut sink, addr) = sink();
+    let clients: Vec<TcpStream> = (0..MAX_CLIENTS)
+      .map(|_| TcpStream::connect(addr).unwrap())
+      .collect();
+    while sink.clients.len() < MAX_CLIENTS {
+      sink.flush().unwrap();
+    }
+    let mut extra = TcpStream::connect(addr).unwrap();
+    extra.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    sink.flush().unwrap();
+    let mut response = String::new();
+    extra.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.0 503"));
+    assert_eq!(sink.clients.len(), clients.len());
+  }
+}
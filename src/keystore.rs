@@ -0,0 +1,430 @@
+use asn1_der::{Asn1Der, Asn1DerError, FromDerObject, IntoDerObject};
+use libp2p::identity::{self, error::DecodingError};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{aead, pbkdf2};
+use std::fs::OpenOptions;
+use std::io::{self, prelude::*, BufReader};
+use std::num::NonZeroU32;
+use std::os::unix::io::AsRawFd;
+use std::str::FromStr;
+use std::{env, error, fmt, mem};
+
+/// Environment variable the passphrase of the key file is read from before
+/// asking on the terminal.
+pub const PASSPHRASE_ENV: &str = "RADIOPEER_PASSPHRASE";
+
+const VERSION: u128 = 1;
+/// Key derivation and cipher of the encrypted key files.
+const SCHEME: &str = "pbkdf2-hmac-sha256/aes-256-gcm";
+const ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// The kinds of identity key a node can have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyType {
+  Secp256k1,
+  Ed25519,
+}
+
+impl KeyType {
+  pub fn of(keypair: &identity::Keypair) -> Option<Self> {
+    match keypair {
+      identity::Keypair::Secp256k1(_) => Some(KeyType::Secp256k1),
+      identity::Keypair::Ed25519(_) => Some(KeyType::Ed25519),
+      identity::Keypair::Rsa(_) => None,
+    }
+  }
+
+  pub fn generate(self) -> identity::Keypair {
+    match self {
+      KeyType::Secp256k1 => identity::Keypair::generate_secp256k1(),
+      KeyType::Ed25519 => identity::Keypair::generate_ed25519(),
+    }
+  }
+
+  /// Returns the key pair of this type with the raw secret key `bytes`.
+  pub fn keypair(self, bytes: Vec<u8>) -> Result<identity::Keypair, DecodingError> {
+    match self {
+      KeyType::Secp256k1 => {
+        let secret = identity::secp256k1::SecretKey::from_bytes(bytes)?;
+        Ok(identity::Keypair::Secp256k1(secret.into()))
+      }
+      KeyType::Ed25519 => {
+        let secret = identity::ed25519::SecretKey::from_bytes(bytes)?;
+        Ok(identity::Keypair::Ed25519(secret.into()))
+      }
+    }
+  }
+
+  fn name(self) -> &'static str {
+    match self {
+      KeyType::Secp256k1 => "secp256k1",
+      KeyType::Ed25519 => "ed25519",
+    }
+  }
+}
+
+impl fmt::Display for KeyType {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.name())
+  }
+}
+
+impl FromStr for KeyType {
+  type Err = KeystoreError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "secp256k1" => Ok(KeyType::Secp256k1),
+      "ed25519" => Ok(KeyType::Ed25519),
+      _ => Err(KeystoreError::UnknownAlgorithm(s.to_string())),
+    }
+  }
+}
+
+/// Returns the raw secret key of `keypair`, the bytes `KeyType::keypair` reads.
+pub fn secret_bytes(keypair: &identity::Keypair) -> Vec<u8> {
+  match keypair {
+    identity::Keypair::Secp256k1(keypair) => keypair.secret().to_bytes().to_vec(),
+    identity::Keypair::Ed25519(keypair) => keypair.secret().as_ref().to_vec(),
+    identity::Keypair::Rsa(_) => unreachable!("RSA keys cannot be generated or imported"),
+  }
+}
+
+/// A secret key and its type; the DER layout follows PKCS#8 `PrivateKeyInfo`,
+/// with the algorithm named rather than given as an OID.
+#[derive(Asn1Der)]
+struct PrivateKeyInfo {
+  version: u128,
+  algorithm: String,
+  private_key: Vec<u8>,
+}
+
+/// A `PrivateKeyInfo` encrypted with a key derived from a passphrase, after
+/// PKCS#8 `EncryptedPrivateKeyInfo`.
+#[derive(Asn1Der)]
+struct EncryptedPrivateKeyInfo {
+  version: u128,
+  scheme: String,
+  salt: Vec<u8>,
+  iterations: u128,
+  nonce: Vec<u8>,
+  /// The DER `PrivateKeyInfo` followed by the authentication tag.
+  encrypted_data: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum KeystoreError {
+  Io(io::Error),
+  /// The key file is neither DER nor a raw secp256k1 secret key.
+  Der(Asn1DerError),
+  UnknownAlgorithm(String),
+  UnknownScheme(String),
+  /// The key file is encrypted and no passphrase was given.
+  PassphraseRequired,
+  /// The passphrase does not decrypt the key file, or the file was tampered with.
+  WrongPassphrase,
+  InvalidKey(DecodingError),
+}
+
+impl fmt::Display for KeystoreError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      KeystoreError::Io(err) => write!(f, "Cannot access the key file: {}", err),
+      KeystoreError::Der(err) => write!(f, "Cannot decode the key file: {}", err),
+      KeystoreError::UnknownAlgorithm(name) => write!(f, "Unknown key type {:?}", name),
+      KeystoreError::UnknownScheme(name) => write!(f, "Unknown key encryption {:?}", name),
+      KeystoreError::PassphraseRequired => write!(
+        f,
+        "The key file is encrypted, set {} or run on a terminal",
+        PASSPHRASE_ENV
+      ),
+      KeystoreError::WrongPassphrase => write!(f, "Wrong passphrase for the key file"),
+      KeystoreError::InvalidKey(err) => write!(f, "Invalid secret key: {}", err),
+    }
+  }
+}
+
+impl error::Error for KeystoreError {
+  fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+    match self {
+      KeystoreError::Io(err) => Some(err),
+      KeystoreError::Der(err) => Some(err),
+      KeystoreError::InvalidKey(err) => Some(err),
+      _ => None,
+    }
+  }
+}
+
+impl From<io::Error> for KeystoreError {
+  fn from(err: io::Error) -> Self {
+    KeystoreError::Io(err)
+  }
+}
+
+impl From<Asn1DerError> for KeystoreError {
+  fn from(err: Asn1DerError) -> Self {
+    KeystoreError::Der(err)
+  }
+}
+
+impl From<DecodingError> for KeystoreError {
+  fn from(err: DecodingError) -> Self {
+    KeystoreError::InvalidKey(err)
+  }
+}
+
+/// What is stored in a key file, as far as can be told without decrypting it.
+pub enum KeyFile {
+  Plain(identity::Keypair),
+  Encrypted(Vec<u8>),
+  /// A secp256k1 secret key of 32 raw bytes, the format before DER.
+  Raw(identity::Keypair),
+}
+
+impl KeyFile {
+  pub fn decode(data: &[u8]) -> Result<Self, KeystoreError> {
+    if let Ok(info) = PrivateKeyInfo::deserialize(data.iter()) {
+      return Ok(KeyFile::Plain(info.into_keypair()?));
+    }
+    match EncryptedPrivateKeyInfo::deserialize(data.iter()) {
+      Ok(_) => Ok(KeyFile::Encrypted(data.to_vec())),
+      Err(e) if data.len() == 32 => {
+        let secret = identity::secp256k1::SecretKey::from_bytes(data.to_vec()).map_err(|_| e)?;
+        Ok(KeyFile::Raw(identity::Keypair::Secp256k1(secret.into())))
+      }
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  pub fn is_encrypted(&self) -> bool {
+    matches!(self, KeyFile::Encrypted(_))
+  }
+
+  /// Returns the key pair, decrypting it with `passphrase` if needed.
+  pub fn into_keypair(self, passphrase: Option<&str>) -> Result<identity::Keypair, KeystoreError> {
+    match self {
+      KeyFile::Plain(keypair) | KeyFile::Raw(keypair) => Ok(keypair),
+      KeyFile::Encrypted(data) => {
+        let passphrase = passphrase.ok_or(KeystoreError::PassphraseRequired)?;
+        decrypt(&data, passphrase)
+      }
+    }
+  }
+}
+
+impl PrivateKeyInfo {
+  fn new(keypair: &identity::Keypair) -> Self {
+    let key_type = KeyType::of(keypair).expect("RSA keys cannot be generated or imported");
+    PrivateKeyInfo {
+      version: VERSION,
+      algorithm: key_type.to_string(),
+      private_key: secret_bytes(keypair),
+    }
+  }
+
+  fn into_keypair(self) -> Result<identity::Keypair, KeystoreError> {
+    let key_type: KeyType = self.algorithm.parse()?;
+    Ok(key_type.keypair(self.private_key)?)
+  }
+}
+
+/// Encodes `keypair` as DER, encrypted with `passphrase` if given.
+pub fn encode(keypair: &identity::Keypair, passphrase: Option<&str>) -> Vec<u8> {
+  let info = PrivateKeyInfo::new(keypair);
+  let plain = to_der(info);
+  match passphrase {
+    Some(passphrase) => to_der(encrypt(plain, passphrase)),
+    None => plain,
+  }
+}
+
+fn to_der<T: IntoDerObject>(value: T) -> Vec<u8> {
+  let mut der = vec![0u8; value.serialized_len()];
+  value
+    .serialize(der.iter_mut())
+    .expect("The buffer has the serialized length");
+  der
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], iterations: NonZeroU32) -> aead::LessSafeKey {
+  let mut key = [0u8; 32];
+  pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, passphrase.as_bytes(), &mut key);
+  let key = aead::UnboundKey::new(&aead::AES_256_GCM, &key).expect("The key has the AES-256 length");
+  aead::LessSafeKey::new(key)
+}
+
+fn encrypt(mut data: Vec<u8>, passphrase: &str) -> EncryptedPrivateKeyInfo {
+  let rng = SystemRandom::new();
+  let mut salt = [0u8; SALT_LEN];
+  let mut nonce = [0u8; NONCE_LEN];
+  rng.fill(&mut salt).expect("The system random generator works");
+  rng.fill(&mut nonce).expect("The system random generator works");
+  let iterations = NonZeroU32::new(ITERATIONS).expect("ITERATIONS is not zero");
+  derive_key(passphrase, &salt, iterations)
+    .seal_in_place_append_tag(
+      aead::Nonce::assume_unique_for_key(nonce),
+      aead::Aad::from(SCHEME.as_bytes()),
+      &mut data,
+    )
+    .expect("A key fits in an AES-GCM message");
+  EncryptedPrivateKeyInfo {
+    version: VERSION,
+    scheme: SCHEME.to_string(),
+    salt: salt.to_vec(),
+    iterations: u128::from(ITERATIONS),
+    nonce: nonce.to_vec(),
+    encrypted_data: data,
+  }
+}
+
+fn decrypt(data: &[u8], passphrase: &str) -> Result<identity::Keypair, KeystoreError> {
+  let info = EncryptedPrivateKeyInfo::deserialize(data.iter())?;
+  if info.scheme != SCHEME {
+    return Err(KeystoreError::UnknownScheme(info.scheme));
+  }
+  // Any other count is either weaker than ours or makes opening the file hang.
+  if info.iterations != u128::from(ITERATIONS) {
+    return Err(Asn1DerError::InvalidEncoding.into());
+  }
+  let iterations = NonZeroU32::new(ITERATIONS).expect("ITERATIONS is not zero");
+  let mut nonce = [0u8; NONCE_LEN];
+  if info.nonce.len() != NONCE_LEN {
+    return Err(Asn1DerError::InvalidEncoding.into());
+  }
+  nonce.copy_from_slice(&info.nonce);
+  let mut encrypted = info.encrypted_data;
+  let plain = derive_key(passphrase, &info.salt, iterations)
+    .open_in_place(
+      aead::Nonce::assume_unique_for_key(nonce),
+      aead::Aad::from(SCHEME.as_bytes()),
+      &mut encrypted,
+    )
+    .map_err(|_| KeystoreError::WrongPassphrase)?;
+  PrivateKeyInfo::deserialize(plain.iter())?.into_keypair()
+}
+
+/// Returns the passphrase from `PASSPHRASE_ENV`, or else asks for it on the
+/// terminal, twice if `confirm` is set.
+///
+/// Returns `None` without a terminal to ask on, or if the passphrase given is empty.
+pub fn passphrase(prompt: &str, confirm: bool) -> io::Result<Option<String>> {
+  if let Ok(passphrase) = env::var(PASSPHRASE_ENV) {
+    return Ok(Some(passphrase).filter(|p| !p.is_empty()));
+  }
+  let passphrase = match read_hidden(prompt) {
+    Ok(passphrase) => passphrase,
+    // No controlling terminal.
+    Err(ref e) if e.raw_os_error() == Some(libc::ENXIO) => return Ok(None),
+    Err(e) => return Err(e),
+  };
+  if confirm && !passphrase.is_empty() && read_hidden("Repeat the passphrase: ")? != passphrase {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidInput,
+      "The passphrases do not match",
+    ));
+  }
+  Ok(Some(passphrase).filter(|p| !p.is_empty()))
+}
+
+/// Reads a line from the terminal without echoing it.
+fn read_hidden(prompt: &str) -> io::Result<String> {
+  let mut tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+  let fd = tty.as_raw_fd();
+  let mut term: libc::termios = unsafe { mem::zeroed() };
+  if unsafe { libc::tcgetattr(fd, &mut term) } != 0 {
+    return Err(io::Error::last_os_error());
+  }
+  let mut hidden = term;
+  hidden.c_lflag &= !libc::ECHO;
+  if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &hidden) } != 0 {
+    return Err(io::Error::last_os_error());
+  }
+  let mut line = String::new();
+  let res = write!(tty, "{}", prompt)
+    .and_then(|()| tty.flush())
+    .and_then(|()| BufReader::new(&tty).read_line(&mut line));
+  unsafe { libc::tcsetattr(fd, libc::TCSANOW, &term) };
+  writeln!(tty)?;
+  res?;
+  Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn peer_id(keypair: &identity::Keypair) -> libp2p::PeerId {
+    keypair.public().into_peer_id()
+  }
+
+  #[test]
+  fn plain_and_encrypted_keys_round_trip() {
+    for key_type in &[KeyType::Ed25519, KeyType::Secp256k1] {
+      let keypair = key_type.generate();
+      let file = KeyFile::decode(&encode(&keypair, None)).unwrap();
+      assert!(!file.is_encrypted());
+      let decoded = file.into_keypair(Some("ignored")).unwrap();
+      assert_eq!(KeyType::of(&decoded), Some(*key_type));
+      assert_eq!(peer_id(&decoded), peer_id(&keypair));
+
+      let file = KeyFile::decode(&encode(&keypair, Some("secret"))).unwrap();
+      assert!(file.is_encrypted());
+      let decoded = file.into_keypair(Some("secret")).unwrap();
+      assert_eq!(KeyType::of(&decoded), Some(*key_type));
+      assert_eq!(peer_id(&decoded), peer_id(&keypair));
+    }
+  }
+
+  #[test]
+  fn encrypted_keys_need_the_passphrase() {
+    let keypair = KeyType::Ed25519.generate();
+    let data = encode(&keypair, Some("secret"));
+    let open = |data: &[u8], passphrase| KeyFile::decode(data).unwrap().into_keypair(passphrase);
+    assert!(matches!(open(&data, None), Err(KeystoreError::PassphraseRequired)));
+    assert!(matches!(open(&data, Some("wrong")), Err(KeystoreError::WrongPassphrase)));
+    let mut tampered = data.clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    assert!(matches!(open(&tampered, Some("secret")), Err(KeystoreError::WrongPassphrase)));
+  }
+
+  #[test]
+  fn other_iteration_counts_are_rejected() {
+    let keypair = KeyType::Ed25519.generate();
+    let plain = encode(&keypair, None);
+    let mut info = encrypt(plain, "secret");
+    info.iterations = u128::from(u32::MAX);
+    let data = to_der(info);
+    assert!(matches!(decrypt(&data, "secret"), Err(KeystoreError::Der(_))));
+  }
+
+  #[test]
+  fn raw_secp256k1_keys_are_read() {
+    let keypair = KeyType::Secp256k1.generate();
+    let raw = secret_bytes(&keypair);
+    assert_eq!(raw.len(), 32);
+    let file = KeyFile::decode(&raw).unwrap();
+    assert!(matches!(file, KeyFile::Raw(_)));
+    let decoded = file.into_keypair(None).unwrap();
+    assert_eq!(peer_id(&decoded), peer_id(&keypair));
+    // Written back in the current format.
+    let migrated = KeyFile::decode(&encode(&decoded, None)).unwrap();
+    assert!(matches!(migrated, KeyFile::Plain(_)));
+  }
+
+  #[test]
+  fn key_types_are_kept_apart() {
+    let ed25519 = KeyType::Ed25519.generate();
+    let secret = secret_bytes(&ed25519);
+    let secp256k1 = KeyType::Secp256k1.keypair(secret.clone()).unwrap();
+    assert_ne!(peer_id(&secp256k1), peer_id(&ed25519));
+    let decoded = KeyType::Ed25519.keypair(secret).unwrap();
+    assert_eq!(peer_id(&decoded), peer_id(&ed25519));
+    assert_eq!("ed25519".parse::<KeyType>().unwrap(), KeyType::Ed25519);
+    assert!(matches!(
+      "rsa".parse::<KeyType>(),
+      Err(KeystoreError::UnknownAlgorithm(_))
+    ));
+  }
+}
@@ -1,4 +1,24 @@
+pub mod address_book;
 pub mod behaviour;
+pub mod broadcast;
+pub mod console;
+pub mod crdt;
+pub mod decode;
+pub mod exchange;
+pub mod history;
+pub mod hls;
+pub mod keystore;
+pub mod icecast;
+pub mod liveness;
 pub mod manifest;
 pub mod params;
+pub mod playback;
+pub mod player;
+pub mod query;
+pub mod records;
+pub mod reputation;
+pub mod sink;
+pub mod store;
+pub mod successor;
+pub mod timesync;
 pub mod utils;
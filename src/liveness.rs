@@ -0,0 +1,448 @@
+use futures::prelude::*;
+use futures03::{compat::Compat, TryFutureExt as _};
+use futures_timer::Delay;
+use libp2p::core::either::EitherOutput;
+use libp2p::core::upgrade::{DeniedUpgrade, InboundUpgrade, OutboundUpgrade};
+use libp2p::core::{ConnectedPoint, PeerId};
+use libp2p::ping::handler::PingHandler;
+use libp2p::ping::{PingConfig, PingFailure, PingResult, PingSuccess};
+use libp2p::swarm::{
+  KeepAlive, NetworkBehaviour, NetworkBehaviourAction, PollParameters, ProtocolsHandler,
+  ProtocolsHandlerEvent, ProtocolsHandlerSelect, ProtocolsHandlerUpgrErr, SubstreamProtocol,
+};
+use libp2p::tokio_io::{AsyncRead, AsyncWrite};
+use libp2p::Multiaddr;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::io;
+use std::marker::PhantomData;
+use std::num::NonZeroU32;
+use std::time::{Duration, Instant};
+use void::Void;
+
+/// Round-trip times kept per peer.
+const MAX_RTT_SAMPLES: usize = 8;
+/// How often every connected peer is pinged.
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+const PING_TIMEOUT: Duration = Duration::from_secs(20);
+/// Pings failing in a row before the connection is closed.
+const MAX_FAILURES: u32 = 3;
+/// How often the idle peers are looked for.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// Connections kept open however idle they are, so the node stays in the network.
+const MIN_CONNECTIONS: usize = 8;
+
+/// What we know of the liveness of a connected peer.
+#[derive(Debug, Clone)]
+pub struct PeerLiveness {
+  /// Latest round-trip times, oldest first.
+  rtts: VecDeque<Duration>,
+  /// Pings that failed since the last one answered.
+  failures: u32,
+  /// Last time the peer sent us something other than a ping.
+  last_active: Instant,
+}
+
+impl PeerLiveness {
+  fn new() -> Self {
+    PeerLiveness {
+      rtts: VecDeque::new(),
+      failures: 0,
+      last_active: Instant::now(),
+    }
+  }
+
+  /// Returns the median of the latest round-trip times, if the peer answered a ping yet.
+  pub fn rtt(&self) -> Option<Duration> {
+    let mut rtts: Vec<Duration> = self.rtts.iter().cloned().collect();
+    rtts.sort();
+    rtts.get(rtts.len() / 2).cloned()
+  }
+
+  /// Returns the latest round-trip times, oldest first.
+  pub fn samples(&self) -> impl Iterator<Item = &Duration> {
+    self.rtts.iter()
+  }
+
+  pub fn failures(&self) -> u32 {
+    self.failures
+  }
+
+  /// Returns how long the peer has not sent us anything other than a ping.
+  pub fn idle_for(&self) -> Duration {
+    self.last_active.elapsed()
+  }
+}
+
+#[derive(Debug)]
+pub enum LivenessEvent {
+  /// A peer answered a ping.
+  Rtt { peer_id: PeerId, rtt: Duration },
+  /// A ping was not answered; the connection is closed after a few in a row.
+  Failure { peer_id: PeerId, error: PingFailure },
+  /// The connection to a peer is being closed because it was idle for too long.
+  Evicted(PeerId),
+}
+
+/// Network behaviour that pings the connected peers, keeps a history of their
+/// round-trip times and closes the connections that are not used.
+///
+/// A peer is active when it sends us anything other than a ping; `Behaviour`
+/// reports that with `mark_active`. The longest idle peers are disconnected
+/// once idle for `idle_timeout`, as long as more than `MIN_CONNECTIONS` remain.
+pub struct Liveness<TSubstream> {
+  peers: HashMap<PeerId, PeerLiveness>,
+  /// Peers are never evicted with `None`.
+  idle_timeout: Option<Duration>,
+  events: VecDeque<NetworkBehaviourAction<LivenessInEvent, LivenessEvent>>,
+  next_idle_check: Compat<Delay>,
+  marker: PhantomData<TSubstream>,
+}
+
+type LivenessInEvent = EitherOutput<Void, Disconnect>;
+
+impl<TSubstream> Liveness<TSubstream> {
+  pub fn new(idle_timeout: Option<Duration>) -> Self {
+    Liveness {
+      peers: HashMap::new(),
+      idle_timeout,
+      events: VecDeque::new(),
+      next_idle_check: Delay::new(IDLE_CHECK_INTERVAL).compat(),
+      marker: PhantomData,
+    }
+  }
+
+  pub fn peer(&self, peer_id: &PeerId) -> Option<&PeerLiveness> {
+    self.peers.get(peer_id)
+  }
+
+  /// Returns the median round-trip time to `peer_id`, if it answered a ping yet.
+  pub fn rtt(&self, peer_id: &PeerId) -> Option<Duration> {
+    self.peers.get(peer_id)?.rtt()
+  }
+
+  /// Sorts `peers` from the lowest round-trip time to the highest, with the
+  /// peers never pinged last and otherwise in the same order.
+  pub fn sort_by_rtt(&self, peers: &mut [PeerId]) {
+    peers.sort_by_key(|peer_id| {
+      let rtt = self.rtt(peer_id);
+      (rtt.is_none(), rtt)
+    });
+  }
+
+  /// Records that `peer_id` sent us something, which keeps it from being evicted.
+  pub fn mark_active(&mut self, peer_id: &PeerId) {
+    if let Some(peer) = self.peers.get_mut(peer_id) {
+      peer.last_active = Instant::now();
+    }
+  }
+
+  /// Closes the connection to `peer_id`, if we are connected.
+  pub fn disconnect(&mut self, peer_id: &PeerId) {
+    if self.peers.contains_key(peer_id) {
+      self.events.push_back(NetworkBehaviourAction::SendEvent {
+        peer_id: peer_id.clone(),
+        event: EitherOutput::Second(Disconnect),
+      });
+    }
+  }
+
+  fn handle_ping(&mut self, peer_id: PeerId, result: PingResult) {
+    let peer = match self.peers.get_mut(&peer_id) {
+      Some(peer) => peer,
+      None => return,
+    };
+    let event = match result {
+      Ok(PingSuccess::Ping { rtt }) => {
+        peer.failures = 0;
+        if peer.rtts.len() == MAX_RTT_SAMPLES {
+          peer.rtts.pop_front();
+        }
+        peer.rtts.push_back(rtt);
+        LivenessEvent::Rtt { peer_id, rtt }
+      }
+      // The remote pinging us says nothing about its latency.
+      Ok(PingSuccess::Pong) => return,
+      Err(error) => {
+        peer.failures += 1;
+        LivenessEvent::Failure { peer_id, error }
+      }
+    };
+    self
+      .events
+      .push_back(NetworkBehaviourAction::GenerateEvent(event));
+  }
+
+  /// Disconnects the longest idle peers beyond `MIN_CONNECTIONS`.
+  fn evict_idle(&mut self) {
+    let idle_timeout = match self.idle_timeout {
+      Some(idle_timeout) => idle_timeout,
+      None => return,
+    };
+    let mut idle: Vec<(Duration, PeerId)> = self
+      .peers
+      .iter()
+      .map(|(peer_id, peer)| (peer.idle_for(), peer_id.clone()))
+      .filter(|(idle_for, _)| *idle_for >= idle_timeout)
+      .collect();
+    idle.sort_by_key(|(idle_for, _)| cmp::Reverse(*idle_for));
+    let evictable = self.peers.len().saturating_sub(MIN_CONNECTIONS);
+    for (idle_for, peer_id) in idle.into_iter().take(evictable) {
+      println!("Disconnecting {}, idle for {} s", peer_id, idle_for.as_secs());
+      self.disconnect(&peer_id);
+      self
+        .events
+        .push_back(NetworkBehaviourAction::GenerateEvent(LivenessEvent::Evicted(peer_id)));
+    }
+  }
+}
+
+impl<TSubstream> NetworkBehaviour for Liveness<TSubstream>
+where
+  TSubstream: AsyncRead + AsyncWrite,
+{
+  type ProtocolsHandler = ProtocolsHandlerSelect<PingHandler<TSubstream>, DisconnectHandler<TSubstream>>;
+  type OutEvent = LivenessEvent;
+
+  fn new_handler(&mut self) -> Self::ProtocolsHandler {
+    let config = PingConfig::new()
+      .with_interval(PING_INTERVAL)
+      .with_timeout(PING_TIMEOUT)
+      .with_max_failures(NonZeroU32::new(MAX_FAILURES).expect("MAX_FAILURES is not zero"));
+    PingHandler::new(config).select(DisconnectHandler::default())
+  }
+
+  fn addresses_of_peer(&mut self, _: &PeerId) -> Vec<Multiaddr> {
+    Vec::new()
+  }
+
+  fn inject_connected(&mut self, peer_id: PeerId, _: ConnectedPoint) {
+    self.peers.insert(peer_id, PeerLiveness::new());
+  }
+
+  fn inject_disconnected(&mut self, peer_id: &PeerId, _: ConnectedPoint) {
+    self.peers.remove(peer_id);
+  }
+
+  fn inject_node_event(&mut self, peer_id: PeerId, event: EitherOutput<PingResult, Infallible>) {
+    match event {
+      EitherOutput::First(result) => self.handle_ping(peer_id, result),
+      EitherOutput::Second(never) => match never {},
+    }
+  }
+
+  fn poll(
+    &mut self,
+    _: &mut impl PollParameters,
+  ) -> Async<NetworkBehaviourAction<LivenessInEvent, LivenessEvent>> {
+    loop {
+      match self.next_idle_check.poll() {
+        Ok(Async::NotReady) => break,
+        Ok(Async::Ready(())) => {
+          self.evict_idle();
+          self.next_idle_check = Delay::new(IDLE_CHECK_INTERVAL).compat();
+        }
+        Err(e) => {
+          println!("Idle peer timer errored: {:?}", e);
+          break;
+        }
+      }
+    }
+    match self.events.pop_front() {
+      Some(event) => Async::Ready(event),
+      None => Async::NotReady,
+    }
+  }
+}
+
+/// Asks the `DisconnectHandler` to close its connection.
+#[derive(Debug, Clone)]
+pub struct Disconnect;
+
+/// Protocol handler that speaks no protocol and closes the connection on `Disconnect`.
+///
+/// Behaviours cannot close a connection themselves, while a handler closes it by
+/// returning an error.
+pub struct DisconnectHandler<TSubstream> {
+  disconnect: bool,
+  marker: PhantomData<TSubstream>,
+}
+
+impl<TSubstream> Default for DisconnectHandler<TSubstream> {
+  fn default() -> Self {
+    DisconnectHandler {
+      disconnect: false,
+      marker: PhantomData,
+    }
+  }
+}
+
+impl<TSubstream> ProtocolsHandler for DisconnectHandler<TSubstream>
+where
+  TSubstream: AsyncRead + AsyncWrite,
+{
+  type InEvent = Disconnect;
+  type OutEvent = Infallible;
+  type Error = io::Error;
+  type Substream = TSubstream;
+  type InboundProtocol = DeniedUpgrade;
+  type OutboundProtocol = DeniedUpgrade;
+  type OutboundOpenInfo = ();
+
+  fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
+    SubstreamProtocol::new(DeniedUpgrade)
+  }
+
+  fn inject_fully_negotiated_inbound(
+    &mut self,
+    _: <Self::InboundProtocol as InboundUpgrade<TSubstream>>::Output,
+  ) {
+  }
+
+  fn inject_fully_negotiated_outbound(
+    &mut self,
+    _: <Self::OutboundProtocol as OutboundUpgrade<TSubstream>>::Output,
+    _: Self::OutboundOpenInfo,
+  ) {
+  }
+
+  fn inject_event(&mut self, _: Disconnect) {
+    self.disconnect = true;
+  }
+
+  fn inject_dial_upgrade_error(
+    &mut self,
+    _: Self::OutboundOpenInfo,
+    _: ProtocolsHandlerUpgrErr<<Self::OutboundProtocol as OutboundUpgrade<TSubstream>>::Error>,
+  ) {
+  }
+
+  fn connection_keep_alive(&self) -> KeepAlive {
+    KeepAlive::No
+  }
+
+  fn poll(
+    &mut self,
+  ) -> Poll<ProtocolsHandlerEvent<Self::OutboundProtocol, Self::OutboundOpenInfo, Infallible>, io::Error>
+  {
+    if self.disconnect {
+      return Err(io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "Disconnected by the local node",
+      ));
+    }
+    Ok(Async::NotReady)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  type TestLiveness = Liveness<io::Cursor<Vec<u8>>>;
+
+  fn millis(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+  }
+
+  fn idle_peer(idle_for: Duration) -> PeerLiveness {
+    let mut peer = PeerLiveness::new();
+    peer.last_active = Instant::now().checked_sub(idle_for).unwrap();
+    peer
+  }
+
+  fn evicted(liveness: &mut TestLiveness) -> Vec<PeerId> {
+    liveness
+      .events
+      .drain(..)
+      .filter_map(|event| match event {
+        NetworkBehaviourAction::GenerateEvent(LivenessEvent::Evicted(peer_id)) => Some(peer_id),
+        _ => None,
+      })
+      .collect()
+  }
+
+  #[test]
+  fn rtt_is_the_median_of_the_latest_samples() {
+    let mut liveness = TestLiveness::new(None);
+    let peer_id = PeerId::random();
+    liveness.inject_connected(peer_id.clone(), ConnectedPoint::Listener {
+      local_addr: "/ip4/127.0.0.1/tcp/1".parse().unwrap(),
+      send_back_addr: "/ip4/127.0.0.1/tcp/2".parse().unwrap(),
+    });
+    assert_eq!(liveness.rtt(&peer_id), None);
+    for rtt in &[50, 10, 40] {
+      liveness.handle_ping(peer_id.clone(), Ok(PingSuccess::Ping { rtt: millis(*rtt) }));
+    }
+    assert_eq!(liveness.rtt(&peer_id), Some(millis(40)));
+    // A slow outlier does not move the median much.
+    liveness.handle_ping(peer_id.clone(), Ok(PingSuccess::Ping { rtt: millis(5000) }));
+    assert_eq!(liveness.rtt(&peer_id), Some(millis(50)));
+    // Only the latest samples count.
+    for _ in 0..MAX_RTT_SAMPLES {
+      liveness.handle_ping(peer_id.clone(), Ok(PingSuccess::Ping { rtt: millis(20) }));
+    }
+    let peer = liveness.peer(&peer_id).unwrap();
+    assert_eq!(peer.samples().count(), MAX_RTT_SAMPLES);
+    assert_eq!(peer.rtt(), Some(millis(20)));
+    liveness.handle_ping(peer_id.clone(), Err(PingFailure::Timeout));
+    assert_eq!(liveness.peer(&peer_id).unwrap().failures(), 1);
+    liveness.handle_ping(peer_id.clone(), Ok(PingSuccess::Ping { rtt: millis(20) }));
+    assert_eq!(liveness.peer(&peer_id).unwrap().failures(), 0);
+  }
+
+  #[test]
+  fn peers_are_sorted_by_rtt() {
+    let mut liveness = TestLiveness::new(None);
+    let peers: Vec<PeerId> = (0..3).map(|_| PeerId::random()).collect();
+    for (peer_id, rtt) in peers.iter().zip(&[Some(30), None, Some(10)]) {
+      let mut peer = PeerLiveness::new();
+      peer.rtts.extend(rtt.map(millis));
+      liveness.peers.insert(peer_id.clone(), peer);
+    }
+    let mut sorted = peers.clone();
+    liveness.sort_by_rtt(&mut sorted);
+    assert_eq!(sorted, vec![peers[2].clone(), peers[0].clone(), peers[1].clone()]);
+  }
+
+  #[test]
+  fn longest_idle_peers_are_evicted_above_the_minimum() {
+    let idle_timeout = Duration::from_secs(60);
+    let mut liveness = TestLiveness::new(Some(idle_timeout));
+    let mut idle = Vec::new();
+    for i in 0..MIN_CONNECTIONS as u64 + 2 {
+      let peer_id = PeerId::random();
+      liveness
+        .peers
+        .insert(peer_id.clone(), idle_peer(idle_timeout + Duration::from_secs(i)));
+      idle.push(peer_id);
+    }
+    let active = PeerId::random();
+    liveness.peers.insert(active.clone(), idle_peer(Duration::from_secs(1)));
+    liveness.evict_idle();
+    // Three peers above the minimum, all but the active one idle: the three idle longest go.
+    let mut longest: Vec<PeerId> = idle[idle.len() - 3..].to_vec();
+    let mut evicted = evicted(&mut liveness);
+    longest.sort_by_key(PeerId::to_base58);
+    evicted.sort_by_key(PeerId::to_base58);
+    assert_eq!(evicted, longest);
+  }
+
+  #[test]
+  fn no_eviction_at_the_minimum_or_without_timeout() {
+    let idle_timeout = Duration::from_secs(60);
+    let mut liveness = TestLiveness::new(Some(idle_timeout));
+    for _ in 0..MIN_CONNECTIONS {
+      liveness.peers.insert(PeerId::random(), idle_peer(idle_timeout * 2));
+    }
+    liveness.evict_idle();
+    assert!(evicted(&mut liveness).is_empty());
+    let mut liveness = TestLiveness::new(None);
+    for _ in 0..MIN_CONNECTIONS * 2 {
+      liveness.peers.insert(PeerId::random(), idle_peer(idle_timeout * 2));
+    }
+    liveness.evict_idle();
+    assert!(evicted(&mut liveness).is_empty());
+  }
+}
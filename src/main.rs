@@ -1,4 +1,4 @@
-//! A basic example demonstrating some core APIs and concepts of libp2p.
+//! The radiopeer node.
 //!
 //! In the first terminal window, run:
 //!
@@ -9,54 +9,530 @@
 //! It will print the PeerId and the listening address, e.g. `Listening on
 //! "/ip4/0.0.0.0/tcp/24915"`
 //!
-//! In the second terminal window, start a new instance of the example with:
+//! In the second terminal window, start a new instance with:
 //!
 //! ```sh
-//! cargo run - /ip4/127.0.0.1/tcp/24915
+//! cargo run -- --bootnodes /ip4/127.0.0.1/tcp/24915/p2p/<PEER_ID>
 //! ```
 //!
-use clap::App;
+//! Create a station, fill it and publish it with the `station` commands, and
+//! listen to one with `tune`; see `--help` for all commands.
+//!
 use futures::prelude::*;
+use futures03::{compat::Compat, TryFutureExt as _};
+use futures_timer::Delay;
 use libp2p::{
     core::PeerId,
     identity,
+    kad::record,
+    multihash,
     tokio_codec::{FramedRead, LinesCodec},
-    Swarm,
+    Multiaddr, Swarm,
+};
+use radiopeer::address_book::AddressBook;
+use radiopeer::behaviour::{AllEvents, Behaviour, DiscoveryOutT};
+use radiopeer::broadcast::{BroadcastMessage, ControlMessage};
+use radiopeer::console::{ConsoleCommand, HELP};
+use radiopeer::decode::Decoder;
+use radiopeer::exchange::ExchangeEvent;
+use radiopeer::history::{History, HistoryEntry};
+use radiopeer::keystore::{self, KeyType};
+use radiopeer::manifest::{
+    proposal_key, station_key, Manifest, ManifestError, SignedManifest, SongHash, Track,
 };
-use libp2p::{multiaddr, Multiaddr};
-use log::{error, info, warn};
-use radiopeer::behaviour::Behaviour;
 use radiopeer::params::*;
+use radiopeer::playback::unix_millis;
+use radiopeer::player::Player;
+use radiopeer::records::DiskStore;
+use radiopeer::reputation::{Ban, Reputation};
+use radiopeer::sink::{SinkOptions, SinkSpec};
+use radiopeer::store::ChunkStore;
+use radiopeer::successor::{self, SignedSuccessor};
 use radiopeer::utils::*;
+use std::collections::{BTreeSet, HashSet};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use std::{cmp, fs, io, process};
 use structopt::StructOpt;
 
+/// How long a node waits for the network before publishing or fetching a manifest.
+const SETTLE_DELAY: Duration = Duration::from_secs(3);
+/// How often a listener checks for a new manifest of the station.
+const MANIFEST_REFRESH: Duration = Duration::from_secs(30);
+/// How long the publisher waits before trying again after a failed publication.
+const PUBLISH_RETRY: Duration = Duration::from_secs(60);
+
 fn main() {
     let opt = Params::from_args();
-    let path = opt.path;
-    let port = opt.port.unwrap_or(0);
+    let home_path = create_home_dir(opt.path.as_deref());
+    let res = match opt.command.clone() {
+        Some(Command::Key(cmd)) => run_key(&home_path, opt.key_type, cmd),
+        Some(Command::History(cmd)) => run_history(&home_path, opt.key_type, cmd),
+        Some(Command::Station(StationCommand::Create { force })) => {
+            create_station(&home_path, opt.key_type, force)
+        }
+        Some(Command::Station(StationCommand::AddSong { file, title })) => {
+            add_song(&home_path, &file, title)
+        }
+        Some(Command::Station(StationCommand::AddAdmin { peer })) => {
+            edit_admins(&home_path, &peer, true)
+        }
+        Some(Command::Station(StationCommand::RemoveAdmin { peer })) => {
+            edit_admins(&home_path, &peer, false)
+        }
+        Some(Command::Station(StationCommand::Threshold { threshold })) => {
+            set_threshold(&home_path, threshold)
+        }
+        _ => run_node(opt, home_path),
+    };
+    if let Err(e) = res {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn station_path(home_path: &Path) -> PathBuf {
+    home_path.join("station.manifest")
+}
+
+fn run_key(home_path: &Path, key_type: KeyType, cmd: KeyCommand) -> Result<(), Box<dyn Error>> {
+    match cmd {
+        KeyCommand::Show => {
+            let local_key = create_keys(home_path, key_type)?;
+            let key_type = KeyType::of(&local_key).ok_or("Unsupported key type")?;
+            println!("{} {}", PeerId::from(local_key.public()), key_type);
+        }
+        KeyCommand::Encrypt => {
+            let passphrase = keystore::passphrase("New passphrase: ", true)?
+                .ok_or("No passphrase given")?;
+            set_passphrase(home_path, key_type, Some(&passphrase))?;
+            println!("Encrypted {}", key_path(home_path).display());
+        }
+        KeyCommand::Decrypt => {
+            set_passphrase(home_path, key_type, None)?;
+            println!("Decrypted {}", key_path(home_path).display());
+        }
+        KeyCommand::Export { file } => {
+            let hex = export_key(home_path, key_type)?;
+            match file {
+                Some(file) => fs::write(file, hex + "\n")?,
+                None => println!("{}", hex),
+            }
+        }
+        KeyCommand::Import { file, force } => {
+            let keypair = import_key(home_path, &fs::read(file)?, key_type, force)?;
+            println!("{}", PeerId::from(keypair.public()));
+        }
+        KeyCommand::Rotate => {
+            let (old, new) = rotate_key(home_path, key_type)?;
+            println!(
+                "Rotated {} to {}, the successor record is published when the node runs",
+                PeerId::from(old.public()),
+                PeerId::from(new.public())
+            );
+        }
+    }
+    Ok(())
+}
+
+fn create_station(home_path: &Path, key_type: KeyType, force: bool) -> Result<(), Box<dyn Error>> {
+    let path = station_path(home_path);
+    if path.exists() && !force {
+        return Err(format!("{} already exists", path.display()).into());
+    }
+    let local_peer_id = PeerId::from(create_keys(home_path, key_type)?.public());
+    // A new station is identified by the current key, not by the key of the one it replaces.
+    match fs::remove_file(station_id_path(home_path)) {
+        Err(ref e) if e.kind() != io::ErrorKind::NotFound => return Err(e.to_string().into()),
+        _ => {}
+    }
+    Manifest::new(&local_peer_id).save(&path)?;
+    println!("{}", local_peer_id);
+    Ok(())
+}
 
-    let home_path = create_home_dir(path.as_ref().map(String::as_str));
+fn add_song(home_path: &Path, file: &Path, title: Option<String>) -> Result<(), Box<dyn Error>> {
+    let path = station_path(home_path);
+    let mut manifest = Manifest::load(&path)?;
+    let chunk_store = ChunkStore::open(home_path)?;
+    let song = chunk_store.add_file(file)?;
+    let duration = Decoder::from_store(&chunk_store, &song)?.measure()?;
+    let title = title.unwrap_or_else(|| {
+        file.file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    });
+    let before = manifest.clone();
+    manifest.add_song(song.clone(), duration.as_millis() as u64, title);
+    manifest.rebase(&before, SystemTime::now());
+    manifest.version += 1;
+    manifest.save(&path)?;
+    println!(
+        "{} {} {}",
+        manifest.songs.len() - 1,
+        multihash::to_hex(&song),
+        duration.as_millis()
+    );
+    Ok(())
+}
+
+/// Adds `peer` to the admins of the station, or removes it.
+fn edit_admins(home_path: &Path, peer: &PeerId, add: bool) -> Result<(), Box<dyn Error>> {
+    let path = station_path(home_path);
+    let mut manifest = Manifest::load(&path)?;
+    if add && !manifest.add_admin(peer) {
+        return Err(format!("{} is already an admin", peer).into());
+    }
+    if !add {
+        if !manifest.remove_admin(peer) {
+            return Err(format!("{} is not an admin", peer).into());
+        }
+        if manifest.admins.len() < manifest.threshold() {
+            return Err(format!(
+                "The station requires {} signatures, lower the threshold first",
+                manifest.threshold()
+            )
+            .into());
+        }
+    }
+    manifest.version += 1;
+    manifest.save(&path)?;
+    println!(
+        "Version {}: {} admins, {} required",
+        manifest.version,
+        manifest.admins.len(),
+        manifest.threshold()
+    );
+    Ok(())
+}
+
+/// Sets how many admins must sign an update of the station.
+fn set_threshold(home_path: &Path, threshold: u32) -> Result<(), Box<dyn Error>> {
+    let path = station_path(home_path);
+    let mut manifest = Manifest::load(&path)?;
+    if threshold == 0 || threshold as usize > manifest.admins.len() {
+        return Err(format!(
+            "The threshold must be between 1 and the {} admins",
+            manifest.admins.len()
+        )
+        .into());
+    }
+    manifest.threshold = threshold;
+    manifest.version += 1;
+    manifest.save(&path)?;
+    println!(
+        "Version {}: {} of {} admins required",
+        manifest.version,
+        manifest.threshold(),
+        manifest.admins.len()
+    );
+    Ok(())
+}
+
+/// Merges the manifest of our station held by the network into the one in
+/// `home_path`, returning the merged manifest signed with `local_key` and its
+/// version, or `None` if the local manifest has every update already.
+///
+/// The merged manifest gets a version of its own, higher than both, so the
+/// other admins and the listeners take it over the ones it merged.
+fn merge_station(
+    home_path: &Path,
+    local_key: &identity::Keypair,
+    held: &Manifest,
+) -> Result<Option<(SignedManifest, u64)>, Box<dyn Error>> {
+    let path = station_path(home_path);
+    let mut manifest = Manifest::load(&path)?;
+    if manifest.includes(held) {
+        return Ok(None);
+    }
+    manifest.merge(held);
+    manifest.version += 1;
+    manifest.save(&path)?;
+    Ok(Some((manifest.sign(local_key)?, manifest.version)))
+}
+
+/// Prints the update proposed for `station`, with the changes from the manifest we hold.
+fn print_proposal(
+    station: &PeerId,
+    held: Option<&Manifest>,
+    proposal: &SignedManifest,
+) -> Result<Manifest, Box<dyn Error>> {
+    let (_, manifest) = proposal.open()?;
+    let signers = proposal.signers()?;
+    let empty = Manifest::default();
+    println!("Proposal version {} for {}", manifest.version, station);
+    print_diff(held.unwrap_or(&empty), &manifest);
+    for signer in signers {
+        println!("  signed by {}", signer);
+    }
+    Ok(manifest)
+}
+
+/// Prints the songs and admins added to or removed from `old` in `new`, and the
+/// settings that changed.
+fn print_diff(old: &Manifest, new: &Manifest) {
+    let songs: HashSet<&SongHash> = new.songs.iter().map(|t| &t.song).collect();
+    let old_songs: HashSet<&SongHash> = old.songs.iter().map(|t| &t.song).collect();
+    println!("  songs: {} -> {}", old.songs.len(), new.songs.len());
+    for track in old.songs.iter().filter(|t| !songs.contains(&t.song)) {
+        println!("  song -{} {}", multihash::to_hex(&track.song), track.title);
+    }
+    for track in new.songs.iter().filter(|t| !old_songs.contains(&t.song)) {
+        println!("  song +{} {}", multihash::to_hex(&track.song), track.title);
+    }
+    let admins: BTreeSet<&Vec<u8>> = new.admins.iter().chain(old.admins.iter()).collect();
+    for admin in admins {
+        let marker = match (old.admins.contains(admin), new.admins.contains(admin)) {
+            (false, true) => "+",
+            (true, false) => "-",
+            _ => " ",
+        };
+        match PeerId::from_bytes(admin.clone()) {
+            Ok(admin) => println!("  admin {}{}", marker, admin),
+            Err(_) => println!("  admin {}<invalid>", marker),
+        }
+    }
+    println!("  threshold: {} -> {}", old.threshold(), new.threshold());
+    if old.started_at != new.started_at {
+        match new.started_at {
+            0 => println!("  broadcast stopped"),
+            _ => println!("  broadcast started"),
+        }
+    }
+}
+
+fn run_history(
+    home_path: &Path,
+    key_type: KeyType,
+    cmd: HistoryCommand,
+) -> Result<(), Box<dyn Error>> {
+    let own_station = || -> Result<PeerId, Box<dyn Error>> {
+        let local_key = create_keys(home_path, key_type)?;
+        station_id(home_path, &PeerId::from(local_key.public()))
+    };
+    let history = History::open(home_path);
+    match cmd {
+        HistoryCommand::List { station } => {
+            let station = match station {
+                Some(station) => station,
+                None => own_station()?,
+            };
+            let entries = history.entries(&station)?;
+            if entries.is_empty() {
+                println!("No manifest of {} was accepted yet", station);
+            }
+            let now = unix_millis(SystemTime::now());
+            for (index, entry) in entries.iter().enumerate() {
+                let manifest = entry.manifest()?;
+                println!(
+                    "{} version {} accepted {} ago: {} songs, {} admins",
+                    index,
+                    manifest.version,
+                    age(now.saturating_sub(entry.accepted_at)),
+                    manifest.songs.len(),
+                    manifest.admins.len()
+                );
+                for signer in entry.signers() {
+                    println!("  signed by {}", signer);
+                }
+            }
+        }
+        HistoryCommand::Diff { from, to, station } => {
+            let station = match station {
+                Some(station) => station,
+                None => own_station()?,
+            };
+            let entries = history.entries(&station)?;
+            let old = find_version(&entries, from)?;
+            let new = find_version(&entries, to)?;
+            println!("Version {} to {} of {}", from, to, station);
+            print_diff(&old, &new);
+        }
+        HistoryCommand::Rollback { version } => {
+            let entries = history.entries(&own_station()?)?;
+            let target = find_version(&entries, version)?;
+            let path = station_path(home_path);
+            let mut manifest = Manifest::load(&path)?;
+            let latest = entries
+                .iter()
+                .filter_map(|entry| entry.manifest().ok())
+                .map(|manifest| manifest.version)
+                .max()
+                .unwrap_or(0);
+            let old = manifest.clone();
+            manifest.restore(&target);
+            manifest.rebase(&old, SystemTime::now());
+            manifest.version = cmp::max(manifest.version, latest) + 1;
+            manifest.save(&path)?;
+            println!("Version {} restores version {}", manifest.version, version);
+            print_diff(&old, &manifest);
+            println!("Run `station publish` to publish it");
+        }
+    }
+    Ok(())
+}
+
+/// Returns the manifest of the last entry with `version`.
+fn find_version(entries: &[HistoryEntry], version: u64) -> Result<Manifest, Box<dyn Error>> {
+    for entry in entries.iter().rev() {
+        let manifest = entry.manifest()?;
+        if manifest.version == version {
+            return Ok(manifest);
+        }
+    }
+    Err(format!("Version {} is not in the history", version).into())
+}
+
+/// What the node does besides taking part in the network.
+enum Mode {
+    Run,
+    Publish {
+        signed: SignedManifest,
+        version: u64,
+        once: bool,
+        next: Option<Compat<Delay>>,
+    },
+    Peers(Compat<Delay>),
+    /// Fetches the update proposed for a station and signs it if `approve` is its version.
+    Review {
+        station: PeerId,
+        approve: Option<u64>,
+        next: Option<Compat<Delay>>,
+        /// Key of the record whose publication we wait for before exiting.
+        waiting: Option<record::Key>,
+    },
+}
+
+/// The station we listen to.
+struct Tuning {
+    station: PeerId,
+    /// Songs we are looking for providers of or downloading.
+    wanted: HashSet<SongHash>,
+    refresh: Compat<Delay>,
+}
+
+/// State of the node that lives next to the swarm.
+struct Node {
+    home_path: PathBuf,
+    local_key: identity::Keypair,
+    /// Id of the station of this node, the peer id of its first key.
+    station_id: PeerId,
+    mode: Mode,
+    /// Records of the rotations of our key, kept alive in the DHT.
+    successors: Vec<SignedSuccessor>,
+    next_successors: Option<Compat<Delay>>,
+    tuning: Option<Tuning>,
+    player: Option<Player>,
+}
+
+fn run_node(opt: Params, home_path: PathBuf) -> Result<(), Box<dyn Error>> {
+    // First, so that stdout is free of logs if the audio goes there.
+    let sink_options = SinkOptions {
+        hls_segment: Duration::from_secs(opt.hls_segment),
+        hls_window: opt.hls_window,
+    };
+    let mut outputs = opt.output.clone();
+    outputs.sort_by_key(|output| *output != SinkSpec::Stdout);
+    let sinks = outputs
+        .iter()
+        .map(|output| output.open(&sink_options))
+        .collect::<io::Result<Vec<_>>>()?;
+    let port = opt.port.unwrap_or(0);
     // // TODO: argument that
     println!("Using home path: {}", home_path.display());
-    let local_key = create_keys(&home_path).unwrap();
-    let local_public = local_key.public();
+    let local_key = create_keys(&home_path, opt.key_type)?;
     let local_peer_id = PeerId::from(local_key.public());
     println!("Local peer id: {}", local_peer_id);
+    let station_id = station_id(&home_path, &local_peer_id)?;
+    if station_id != local_peer_id {
+        println!("Station id: {}", station_id);
+    }
+    let successors = successor::load_all(&home_path)?;
+
+    let mode = match opt.command.clone() {
+        Some(Command::Station(StationCommand::Publish { start, once })) => {
+            let path = station_path(&home_path);
+            let mut manifest = Manifest::load(&path)?;
+            if start {
+                manifest.start_broadcast(SystemTime::now());
+                manifest.save(&path)?;
+            }
+            Mode::Publish {
+                signed: manifest.sign(&local_key)?,
+                version: manifest.version,
+                once,
+                next: Some(Delay::new(SETTLE_DELAY).compat()),
+            }
+        }
+        Some(Command::Peers { wait }) => Mode::Peers(Delay::new(Duration::from_secs(wait)).compat()),
+        Some(Command::Station(StationCommand::Review { station })) => Mode::Review {
+            station,
+            approve: None,
+            next: Some(Delay::new(SETTLE_DELAY).compat()),
+            waiting: None,
+        },
+        Some(Command::Station(StationCommand::Approve { station, version })) => Mode::Review {
+            station,
+            approve: Some(version),
+            next: Some(Delay::new(SETTLE_DELAY).compat()),
+            waiting: None,
+        },
+        _ => Mode::Run,
+    };
+
+    let records = opt.record_config();
+    let enable_mdns = opt.mdns_enabled();
+    let idle_timeout = opt.idle_timeout();
     // Create a transport.
-    let transport = libp2p::build_development_transport(local_key);
+    let transport = libp2p::build_development_transport(local_key.clone());
     let mut swarm = {
         let user_agent = format!(
             "{} ({})",
             "radiopeer",
             opt.nodename.unwrap_or("robot".to_owned())
         );
-        let behaviour = Behaviour::new(user_agent, local_public.clone());
+        let chunk_store = ChunkStore::open(&home_path)?;
+        let behaviour = Behaviour::new(
+            user_agent,
+            local_key.clone(),
+            Arc::new(chunk_store),
+            DiskStore::open(&home_path, local_peer_id.clone())?,
+            records,
+            AddressBook::open(&home_path),
+            Reputation::open(&home_path),
+            History::open(&home_path),
+            enable_mdns,
+            idle_timeout,
+        );
         // behaviour.kademlia.bootstrap();
-        Swarm::new(transport, behaviour, local_peer_id)
+        Swarm::new(transport, behaviour, local_peer_id.clone())
+    };
+    let mut node = Node {
+        home_path,
+        local_key,
+        station_id,
+        mode,
+        next_successors: if successors.is_empty() {
+            None
+        } else {
+            Some(Delay::new(SETTLE_DELAY).compat())
+        },
+        successors,
+        tuning: None,
+        player: if sinks.is_empty() {
+            None
+        } else {
+            Some(Player::new(sinks))
+        },
     };
-    // Read full lines from stdin
+    if let Some(Command::Tune { station }) = opt.command {
+        node.tune(&mut swarm, station, SETTLE_DELAY);
+    }
+    // Read commands from stdin, see `console::HELP`.
     let stdin = tokio_stdin_stdout::stdin(0);
-    let mut framed_stdin = FramedRead::new(stdin, LinesCodec::new());
+    let mut framed_stdin = Some(FramedRead::new(stdin, LinesCodec::new()));
     let addr = format!("/ip4/0.0.0.0/tcp/{}", port);
 
     // Format: /ip4/<ip>/tcp/<port>/p2p/<hash>
@@ -66,39 +542,51 @@ fn main() {
                 println!("Connecting to bootnode: {} {}", addr, peer_id);
                 swarm.add_self_reported_address(&peer_id, addr);
             }
-            Err(_) => panic!("Not a valid bootnode address: {}", bootnode),
+            Err(_) => return Err(format!("Not a valid bootnode address: {}", bootnode).into()),
         }
     }
 
-    Swarm::listen_on(&mut swarm, addr.parse().unwrap()).unwrap();
-    // match port {
-    //     Some(port) => {
-    //         // let po = ;
-    //         // let addr = "/ip4/0.0.0.0/tcp/".to_string() + port.parse::<u16>().unwrap().to_string();
-    //         let addr = format!("/ip4/0.0.0.0/tcp/{}", port.parse::<u16>().unwrap());
-    //         Swarm::listen_on(&mut swarm, addr.parse().unwrap()).unwrap();
-    //         // swarm.add_self_reported_address(&args[2].parse().unwrap(), args[1].parse().unwrap());
-    //     }
-    //     None => {} // Swarm::listen_on(&mut swarm, "/ip4/0.0.0.0/tcp/0".parse().unwrap()).unwrap();
-    //                // println!("ADDING PEERS!");
-    //                // swarm.add_self_reported_address(&args[2].parse().unwrap(), args[1].parse().unwrap());
-    // }
+    Swarm::listen_on(&mut swarm, addr.parse()?)?;
     // Kick it off
     let mut listening = false;
     tokio::run(futures::future::poll_fn(move || -> Result<_, ()> {
-        loop {
-            match framed_stdin.poll().expect("Error while polling stdin") {
+        while let Some(stdin) = framed_stdin.as_mut() {
+            match stdin.poll().expect("Error while polling stdin") {
                 Async::Ready(Some(line)) => {
-                    // swarm.floodsub.publish(&floodsub_topic, line.as_bytes())
-                    println!("{:?}", line);
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let res = match line.parse::<ConsoleCommand>() {
+                        // Dialing is the one command that needs the swarm rather than the behaviour.
+                        Ok(ConsoleCommand::Dial(addr)) => {
+                            let addr = dial_address(&mut swarm, addr);
+                            match Swarm::dial_addr(&mut swarm, addr.clone()) {
+                                Ok(()) => {
+                                    println!("Dialing {}", addr);
+                                    Ok(())
+                                }
+                                Err(e) => Err(format!("Cannot dial {}: {:?}", addr, e).into()),
+                            }
+                        }
+                        Ok(command) => node.run_command(&mut swarm, command),
+                        Err(e) => Err(e.into()),
+                    };
+                    if let Err(e) = res {
+                        println!("Error: {}", e);
+                    }
                 }
-                Async::Ready(None) => panic!("Stdin closed"),
+                // Keep running without a console, e.g. as a service.
+                Async::Ready(None) => framed_stdin = None,
                 Async::NotReady => break,
             };
         }
+        node.poll(&mut swarm);
         loop {
             match swarm.poll().expect("Error while polling swarm") {
-                Async::Ready(Some(event)) => println!("{:?}", event),
+                Async::Ready(Some(event)) => {
+                    println!("{:?}", event);
+                    node.handle_event(&mut swarm, event);
+                }
                 Async::Ready(None) | Async::NotReady => {
                     if !listening {
                         if let Some(a) = Swarm::listeners(&swarm).next() {
@@ -110,6 +598,593 @@ fn main() {
                 }
             }
         }
+        node.poll_player(&swarm);
         Ok(Async::NotReady)
     }));
+    Ok(())
+}
+
+/// Returns the address to dial for `addr`, remembering the peer if the address
+/// ends with its id.
+fn dial_address<S>(swarm: &mut Behaviour<S>, addr: Multiaddr) -> Multiaddr {
+    match parse_addr(addr.clone()) {
+        Ok((peer_id, addr)) => {
+            swarm.add_self_reported_address(&peer_id, addr.clone());
+            addr
+        }
+        Err(_) => addr,
+    }
+}
+
+/// Returns true when `timer` fired, registering the task to be woken up otherwise.
+fn fired(timer: &mut Compat<Delay>) -> bool {
+    match timer.poll() {
+        Ok(Async::Ready(())) => true,
+        Ok(Async::NotReady) => false,
+        Err(err) => {
+            println!("Timer errored: {:?}", err);
+            false
+        }
+    }
+}
+
+fn exit(code: i32) -> ! {
+    use std::io::Write;
+    let _ = io::stdout().flush();
+    process::exit(code)
+}
+
+/// Formats a number of milliseconds in the largest unit that fits.
+fn age(millis: u64) -> String {
+    let secs = millis / 1000;
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+/// Formats a duration as minutes and seconds.
+fn clock(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+impl Node {
+    /// Starts listening to `station` instead of the one we were tuned to, fetching
+    /// its manifest after `delay`.
+    fn tune<S>(&mut self, swarm: &mut Behaviour<S>, station: PeerId, delay: Duration) {
+        if let Some(old) = self.tuning.take() {
+            if old.station != station {
+                swarm.unsubscribe_station(&old.station);
+            }
+        }
+        swarm.subscribe_station(&station);
+        match self.player.as_mut() {
+            Some(player) => player.tune(Some(station.clone())),
+            None => println!("No --output given, the station is only downloaded"),
+        }
+        self.tuning = Some(Tuning {
+            station,
+            wanted: HashSet::new(),
+            refresh: Delay::new(delay).compat(),
+        });
+    }
+
+    /// Whether `key` is the DHT key of the record of one of our key rotations.
+    fn is_successor_key(&self, key: &record::Key) -> bool {
+        self.successors.iter().any(|signed| match signed.verify() {
+            Ok((old, _)) => successor::successor_key(&old) == *key,
+            Err(_) => false,
+        })
+    }
+
+    /// Runs the timers.
+    fn poll<S>(&mut self, swarm: &mut Behaviour<S>) {
+        // Before the manifest, which may be signed with a key the successors lead to.
+        if self.next_successors.as_mut().is_some_and(fired) {
+            for signed in &self.successors {
+                if let Err(e) = swarm.publish_successor(signed) {
+                    println!("Cannot publish a successor record: {}", e);
+                }
+            }
+            let ttl = swarm.record_config().manifest.ttl;
+            self.next_successors = ttl.map(|ttl| Delay::new(ttl / 2).compat());
+        }
+        match &mut self.mode {
+            Mode::Run => {}
+            Mode::Publish {
+                signed,
+                version,
+                next,
+                ..
+            } => {
+                if next.as_mut().is_some_and(fired) {
+                    *next = None;
+                    // Hear about and merge the updates of the other admins.
+                    swarm.subscribe_station(&self.station_id);
+                    swarm.fetch_manifest(&self.station_id);
+                    match swarm.publish_manifest(&self.station_id, signed) {
+                        Ok(()) => {}
+                        // Published once the other admins signed it too.
+                        Err(ManifestError::BelowThreshold { .. }) => {
+                            match swarm.propose_manifest(&self.station_id, signed) {
+                                Ok((approvals, threshold)) => {
+                                    println!(
+                                        "Proposed manifest version {}, signed by {} of the {} admins required",
+                                        version, approvals, threshold
+                                    );
+                                }
+                                Err(e) => {
+                                    println!("Cannot propose the station manifest: {}", e);
+                                    exit(1);
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            println!("Cannot publish the station manifest: {}", e);
+                            exit(1);
+                        }
+                    }
+                }
+            }
+            Mode::Review { station, next, .. } => {
+                // The proposal is judged against the manifest we hold, fetched first.
+                if next.as_mut().is_some_and(fired) {
+                    *next = None;
+                    swarm.fetch_manifest(station);
+                }
+            }
+            Mode::Peers(deadline) => {
+                if fired(deadline) {
+                    for peer_id in swarm.known_peers() {
+                        println!("{}", peer_id);
+                    }
+                    exit(0);
+                }
+            }
+        }
+        if let Some(Tuning {
+            station, refresh, ..
+        }) = self.tuning.as_mut()
+        {
+            while fired(refresh) {
+                swarm.fetch_manifest(station);
+                *refresh = Delay::new(MANIFEST_REFRESH).compat();
+            }
+        }
+    }
+
+    fn poll_player<S>(&mut self, swarm: &Behaviour<S>) {
+        if let Some(player) = self.player.as_mut() {
+            let manifest = player.station().and_then(|station| swarm.manifest(station));
+            player.poll(manifest, swarm.chunk_store(), swarm.network_time());
+        }
+    }
+
+    fn handle_event<S>(&mut self, swarm: &mut Behaviour<S>, event: AllEvents) {
+        let own_key = station_key(&self.station_id);
+        let (proposals, reviewed) = match &self.mode {
+            Mode::Publish { .. } => (vec![proposal_key(&self.station_id)], None),
+            Mode::Review { station, .. } => (vec![proposal_key(station)], Some(station_key(station))),
+            _ => (Vec::new(), None),
+        };
+        let is_proposal_key = |key: &record::Key| proposals.contains(key);
+        match &event {
+            // Reported by `handle_review_event`.
+            AllEvents::DiscoveryOut(DiscoveryOutT::ValueNotFound(key))
+            | AllEvents::DiscoveryOut(DiscoveryOutT::ValuePut(key))
+            | AllEvents::DiscoveryOut(DiscoveryOutT::ValuePutFailed(key))
+                if reviewed.as_ref() == Some(key) => {}
+            AllEvents::DiscoveryOut(DiscoveryOutT::SuccessorFound(old, new)) => {
+                println!("{} rotated its key to {}", old, new);
+            }
+            AllEvents::DiscoveryOut(DiscoveryOutT::ValueFound(records)) => {
+                for (key, value) in records {
+                    println!(
+                        "{} = {}",
+                        String::from_utf8_lossy(key.as_ref()),
+                        String::from_utf8_lossy(value)
+                    );
+                }
+            }
+            AllEvents::DiscoveryOut(DiscoveryOutT::ValueNotFound(key)) => {
+                println!("No value found for {}", String::from_utf8_lossy(key.as_ref()));
+            }
+            AllEvents::DiscoveryOut(DiscoveryOutT::ValuePut(key)) if is_proposal_key(key) => {
+                println!("Published the proposal");
+            }
+            AllEvents::DiscoveryOut(DiscoveryOutT::ValuePutFailed(key)) if is_proposal_key(key) => {
+                println!("Cannot publish the proposal");
+            }
+            AllEvents::DiscoveryOut(DiscoveryOutT::ValuePut(key)) if self.is_successor_key(key) => {
+                println!("Published a successor record");
+            }
+            AllEvents::DiscoveryOut(DiscoveryOutT::ValuePutFailed(key)) if self.is_successor_key(key) => {
+                println!("Cannot publish a successor record");
+            }
+            AllEvents::DiscoveryOut(DiscoveryOutT::ValuePut(key)) if *key != own_key => {
+                println!("Stored {}", String::from_utf8_lossy(key.as_ref()));
+            }
+            AllEvents::DiscoveryOut(DiscoveryOutT::ValuePutFailed(key)) if *key != own_key => {
+                println!("Cannot store {}", String::from_utf8_lossy(key.as_ref()));
+            }
+            _ => {}
+        }
+        if let Mode::Review { .. } = self.mode {
+            self.handle_review_event(swarm, &event);
+        }
+        if let Mode::Publish {
+            signed,
+            version,
+            once,
+            next,
+        } = &mut self.mode
+        {
+            match &event {
+                AllEvents::Broadcast {
+                    station,
+                    message: BroadcastMessage::Control(ControlMessage::ProposalUpdated { .. }),
+                    ..
+                } if *station == self.station_id => {
+                    swarm.fetch_proposal(station);
+                }
+                AllEvents::Broadcast {
+                    station,
+                    message: BroadcastMessage::Control(ControlMessage::ManifestUpdated { .. }),
+                    ..
+                } if *station == self.station_id => {
+                    swarm.fetch_manifest(station);
+                }
+                AllEvents::DiscoveryOut(DiscoveryOutT::ManifestFound(station, held))
+                    if *station == self.station_id =>
+                {
+                    match merge_station(&self.home_path, &self.local_key, held) {
+                        Ok(Some((merged, merged_version))) => {
+                            println!(
+                                "Merged the updates of the other admins into manifest version {}",
+                                merged_version
+                            );
+                            *signed = merged;
+                            *version = merged_version;
+                            *next = Some(Delay::new(Duration::from_secs(0)).compat());
+                        }
+                        Ok(None) => {}
+                        Err(e) => println!("Cannot merge the station manifest: {}", e),
+                    }
+                }
+                AllEvents::DiscoveryOut(DiscoveryOutT::ProposalFound(station, approvals, threshold))
+                    if *station == self.station_id =>
+                {
+                    println!(
+                        "Manifest proposal signed by {} of the {} admins required",
+                        approvals, threshold
+                    );
+                    let proposal = swarm.proposal(station).cloned();
+                    let complete = proposal.filter(|proposal| {
+                        approvals >= threshold
+                            && proposal.open().map(|(_, m)| m.version).ok() == Some(*version)
+                    });
+                    if let Some(proposal) = complete {
+                        *signed = proposal;
+                        if let Err(e) = swarm.publish_manifest(&self.station_id, signed) {
+                            println!("Cannot publish the station manifest: {}", e);
+                        }
+                    }
+                }
+                AllEvents::DiscoveryOut(DiscoveryOutT::ValuePut(key)) if *key == own_key => {
+                    println!("Published manifest version {}", version);
+                    let message = BroadcastMessage::Control(ControlMessage::ManifestUpdated {
+                        version: *version,
+                    });
+                    if let Err(e) = swarm.broadcast(&self.station_id, &message) {
+                        println!("Cannot announce the new manifest: {}", e);
+                    }
+                    if *once {
+                        exit(0);
+                    }
+                    // Publish it again before it expires from the DHT.
+                    let ttl = swarm.record_config().manifest.ttl;
+                    *next = ttl.map(|ttl| Delay::new(ttl / 2).compat());
+                }
+                AllEvents::DiscoveryOut(DiscoveryOutT::ValuePutFailed(key)) if *key == own_key => {
+                    println!("Cannot publish manifest version {}", version);
+                    if *once {
+                        exit(1);
+                    }
+                    *next = Some(Delay::new(PUBLISH_RETRY).compat());
+                }
+                _ => {}
+            }
+        }
+        let Tuning {
+            station, wanted, ..
+        } = match self.tuning.as_mut() {
+            Some(tuning) => tuning,
+            None => return,
+        };
+        match event {
+            AllEvents::DiscoveryOut(DiscoveryOutT::ManifestFound(found, manifest))
+                if found == *station =>
+            {
+                for Track { song, .. } in manifest.songs.iter() {
+                    if !swarm.chunk_store().is_complete(song) && wanted.insert(song.clone()) {
+                        swarm.find_providers(song);
+                    }
+                }
+            }
+            AllEvents::DiscoveryOut(DiscoveryOutT::ProvidersFound(song, providers)) => {
+                if !wanted.contains(&song) {
+                    return;
+                }
+                if providers.is_empty() {
+                    // Looked up again on the next manifest refresh.
+                    wanted.remove(&song);
+                } else {
+                    swarm.fetch_song(song, providers);
+                }
+            }
+            AllEvents::Exchange(ExchangeEvent::SongUnavailable(song)) => {
+                wanted.remove(&song);
+            }
+            AllEvents::Broadcast {
+                station: from,
+                message: BroadcastMessage::Control(ControlMessage::ManifestUpdated { version }),
+                ..
+            } if from == *station => {
+                let held = swarm.manifest(station).map_or(0, |m| m.version);
+                if version > held {
+                    swarm.fetch_manifest(station);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Follows a `station review` or `station approve` through the lookups of the
+    /// manifest and the proposal, and the publication of our signature.
+    fn handle_review_event<S>(&mut self, swarm: &mut Behaviour<S>, event: &AllEvents) {
+        let (station, approve, waiting) = match &mut self.mode {
+            Mode::Review {
+                station,
+                approve,
+                waiting,
+                ..
+            } => (station.clone(), *approve, waiting),
+            _ => return,
+        };
+        match event {
+            AllEvents::DiscoveryOut(DiscoveryOutT::ManifestFound(found, _))
+            | AllEvents::DiscoveryOut(DiscoveryOutT::ManifestRejected(found, _))
+                if *found == station && waiting.is_none() =>
+            {
+                swarm.fetch_proposal(&station);
+            }
+            AllEvents::DiscoveryOut(DiscoveryOutT::ValueNotFound(key))
+                if *key == station_key(&station) && waiting.is_none() =>
+            {
+                swarm.fetch_proposal(&station);
+            }
+            AllEvents::DiscoveryOut(DiscoveryOutT::ProposalNotFound(found))
+                if *found == station && waiting.is_none() =>
+            {
+                println!("No update proposed for {}", station);
+                exit(1);
+            }
+            AllEvents::DiscoveryOut(DiscoveryOutT::ProposalFound(found, approvals, threshold))
+                if *found == station && waiting.is_none() =>
+            {
+                let proposal = swarm.proposal(&station).cloned().expect("just found");
+                let manifest = match print_proposal(&station, swarm.manifest(&station), &proposal) {
+                    Ok(manifest) => manifest,
+                    Err(e) => {
+                        println!("Cannot read the proposal: {}", e);
+                        exit(1);
+                    }
+                };
+                println!("Signed by {} of the {} admins required", approvals, threshold);
+                let version = match approve {
+                    Some(version) => version,
+                    None => exit(0),
+                };
+                if manifest.version != version {
+                    println!("The proposal is version {}, not {}", manifest.version, version);
+                    exit(1);
+                }
+                let mut signed = proposal;
+                let res = signed
+                    .cosign(&self.local_key)
+                    .and_then(|_| swarm.propose_manifest(&station, &signed));
+                let (approvals, threshold) = match res {
+                    Ok(res) => res,
+                    Err(e) => {
+                        println!("Cannot approve the proposal: {}", e);
+                        exit(1);
+                    }
+                };
+                println!("Approved, signed by {} of the {} admins required", approvals, threshold);
+                *waiting = Some(proposal_key(&station));
+                if approvals >= threshold {
+                    let complete = swarm.proposal(&station).cloned().expect("just proposed");
+                    match swarm.publish_manifest(&station, &complete) {
+                        Ok(()) => *waiting = Some(station_key(&station)),
+                        Err(e) => println!("Cannot publish the station manifest: {}", e),
+                    }
+                }
+            }
+            AllEvents::DiscoveryOut(DiscoveryOutT::ValuePut(key))
+                if Some(key) == waiting.as_ref() =>
+            {
+                if *key == station_key(&station) {
+                    let version = swarm.manifest(&station).map_or(0, |m| m.version);
+                    println!("Published manifest version {}", version);
+                    let message =
+                        BroadcastMessage::Control(ControlMessage::ManifestUpdated { version });
+                    if let Err(e) = swarm.broadcast(&station, &message) {
+                        println!("Cannot announce the new manifest: {}", e);
+                    }
+                }
+                exit(0);
+            }
+            AllEvents::DiscoveryOut(DiscoveryOutT::ValuePutFailed(key))
+                if Some(key) == waiting.as_ref() =>
+            {
+                exit(1);
+            }
+            _ => {}
+        }
+    }
+
+    /// Runs a command typed on the console; results of DHT queries are printed
+    /// when their events come in.
+    fn run_command<S>(
+        &mut self,
+        swarm: &mut Behaviour<S>,
+        command: ConsoleCommand,
+    ) -> Result<(), Box<dyn Error>> {
+        match command {
+            ConsoleCommand::Put { key, value } => {
+                swarm.put_value(record::Key::new(&key), value.into_bytes());
+            }
+            ConsoleCommand::Get { key } => {
+                // The result is printed from the `ValueFound` event.
+                let quorum = swarm.record_config().value.get_quorum;
+                swarm.get_value(&record::Key::new(&key), quorum);
+            }
+            ConsoleCommand::Peers => {
+                let peers: Vec<PeerId> = swarm.known_peers().cloned().collect();
+                if peers.is_empty() {
+                    println!("No peers known yet");
+                }
+                for peer_id in peers {
+                    let mut details = Vec::new();
+                    if let Some(rtt) = swarm.rtt(&peer_id) {
+                        details.push(format!("rtt {} ms", rtt.as_millis()));
+                    }
+                    if let Some(liveness) = swarm.liveness(&peer_id) {
+                        details.push(format!("idle {} s", liveness.idle_for().as_secs()));
+                    }
+                    if let Some(estimate) = swarm.clock_estimate(&peer_id) {
+                        details.push(format!("clock offset {} ms", estimate.offset_micros / 1000));
+                    }
+                    let score = swarm.score(&peer_id);
+                    if score < 0.0 {
+                        details.push(format!("score {:.0}", score));
+                    }
+                    println!("{} {}", peer_id, details.join(", "));
+                }
+            }
+            ConsoleCommand::Dial(_) => unreachable!("dialing is done on the swarm"),
+            ConsoleCommand::Tune(station) => {
+                self.tune(swarm, station.clone(), Duration::from_secs(0));
+                println!("Tuned to {}", station);
+            }
+            ConsoleCommand::Ban(peer_id) => {
+                if swarm.ban_peer(&peer_id)? {
+                    println!("Banned {}", peer_id);
+                } else {
+                    println!("{} is already banned", peer_id);
+                }
+            }
+            ConsoleCommand::Unban(peer_id) => {
+                if swarm.unban_peer(&peer_id)? {
+                    println!("Unbanned {}", peer_id);
+                } else {
+                    println!("{} is not banned", peer_id);
+                }
+            }
+            ConsoleCommand::Bans => {
+                let banned = swarm.banned_peers();
+                if banned.is_empty() {
+                    println!("No peers banned");
+                }
+                for (peer_id, ban) in banned {
+                    match ban {
+                        Ban::Manual => println!("{} banned by hand", peer_id),
+                        Ban::Until(until) => println!(
+                            "{} banned for {} more minutes",
+                            peer_id,
+                            until.saturating_duration_since(Instant::now()).as_secs() / 60 + 1
+                        ),
+                    }
+                }
+            }
+            ConsoleCommand::Skip => self.skip(swarm)?,
+            ConsoleCommand::NowPlaying => self.now_playing(swarm)?,
+            ConsoleCommand::Help => println!("{}", HELP),
+        }
+        Ok(())
+    }
+
+    /// Skips the current track of our station and publishes the new manifest.
+    ///
+    /// A skip has to take effect right away, so it is refused on stations that
+    /// need the signatures of several admins, rather than proposed to them.
+    fn skip<S>(&mut self, swarm: &mut Behaviour<S>) -> Result<(), Box<dyn Error>> {
+        let (signed, version) = match &mut self.mode {
+            Mode::Publish {
+                signed, version, ..
+            } => (signed, version),
+            _ => return Err("Only a node publishing its station can skip, see `station publish`".into()),
+        };
+        let path = station_path(&self.home_path);
+        let mut manifest = Manifest::load(&path)?;
+        // The manifest the network holds decides how many signatures an update needs.
+        let threshold = swarm
+            .manifest(&self.station_id)
+            .map_or(manifest.threshold(), |held| held.threshold());
+        if threshold > 1 {
+            return Err(format!(
+                "The station requires {} signatures, a skip cannot wait for them",
+                threshold
+            )
+            .into());
+        }
+        let position = manifest
+            .skip(swarm.network_time())
+            .ok_or("The station is off air")?;
+        let skipped = manifest.sign(&self.local_key)?;
+        swarm.publish_manifest(&self.station_id, &skipped)?;
+        // Saved only once accepted, so the file never differs from what we publish.
+        manifest.save(&path)?;
+        *signed = skipped;
+        *version = manifest.version;
+        let message = BroadcastMessage::Control(ControlMessage::Skip);
+        if let Err(e) = swarm.broadcast(&self.station_id, &message) {
+            println!("Cannot announce the skip: {}", e);
+        }
+        println!(
+            "Skipped to track {}, publishing manifest version {}",
+            position.track, version
+        );
+        Ok(())
+    }
+
+    /// Prints the track the station we listen to, or else our own, is playing.
+    fn now_playing<S>(&self, swarm: &Behaviour<S>) -> Result<(), Box<dyn Error>> {
+        let station = match (&self.tuning, &self.mode) {
+            (Some(tuning), _) => &tuning.station,
+            (None, Mode::Publish { .. }) => &self.station_id,
+            _ => return Err("Not tuned to a station".into()),
+        };
+        let manifest = swarm
+            .manifest(station)
+            .ok_or_else(|| format!("No manifest of {} yet", station))?;
+        match swarm.playback_position(station) {
+            Some(position) => {
+                let duration = manifest.songs.get(position.track).map_or(0, |t| t.duration_ms);
+                println!(
+                    "{}: track {} {} [{} / {}]",
+                    station,
+                    position.track,
+                    manifest.title(position.track).unwrap_or_default(),
+                    clock(position.offset),
+                    clock(Duration::from_millis(duration))
+                );
+            }
+            None => println!("{} is off air", station),
+        }
+        Ok(())
+    }
 }
@@ -1,19 +1,839 @@
+use crate::crdt::{Dot, OrSet, Sequence};
+use crate::store::write_atomic;
+use crate::successor::successor_key;
+use libp2p::core::{identity, PeerId, PublicKey};
+use libp2p::kad::record;
+use libp2p::multihash;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::cmp;
+use std::collections::HashMap;
+use std::path::Path;
+use std::{fmt, fs, io};
 
-type SongHash = Vec<u8>;
-type PeerID = Vec<u8>;
+pub type SongHash = Vec<u8>;
+pub type PeerID = Vec<u8>;
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Manifest {
+/// Manifest of a station.
+///
+/// The playlist and the admins are CRDTs, so the edits of admins who updated
+/// the station concurrently are merged rather than lost; see `merge`. They are
+/// edited through the methods of `Manifest`, which keep the edits ordered.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Manifest {
+  // Incremented by the admins on every update; merges keep the highest
+  pub version: u64,
   // admins PeerIds
-  admins: HashSet<PeerID>,
-  // Songs
-  songs: Vec<SongHash>,
+  pub admins: OrSet<PeerID>,
+  // Playlist
+  pub songs: Sequence<Track>,
+  // When the broadcast started, in milliseconds since the Unix epoch; 0 when off air
+  pub started_at: u64,
+  // Admins that must sign every update; 0 and 1 both mean any single admin
+  pub threshold: u32,
 
   // Not to serialize
   #[serde(skip_serializing, skip_deserializing)]
-  music_track: usize,
+  pub music_track: usize,
   #[serde(skip_serializing, skip_deserializing)]
-  seconds_in_music: u32,
+  pub seconds_in_music: u32,
+}
+
+/// A song of the playlist.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Track {
+  pub song: SongHash,
+  /// Duration in milliseconds.
+  pub duration_ms: u64,
+  pub title: String,
+}
+
+/// A `Manifest` as it is stored in the DHT: the serialized manifest together with
+/// the public key of the admin that signed it and the signature over those bytes.
+///
+/// The signature covers the exact bytes in `payload`, so peers never have to
+/// re-serialize the manifest (and agree on the order of `admins`) to verify it.
+/// Stations with a `threshold` need the signatures of further admins over the
+/// same bytes, collected in `cosignatures`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedManifest {
+  /// Bincode encoding of the `Manifest`.
+  pub payload: Vec<u8>,
+  /// Protobuf encoding of the signer's public key.
+  pub signer: Vec<u8>,
+  pub signature: Vec<u8>,
+  pub cosignatures: Vec<Cosignature>,
+}
+
+/// The signature of one more admin over the payload of a `SignedManifest`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Cosignature {
+  /// Protobuf encoding of the signer's public key.
+  pub signer: Vec<u8>,
+  pub signature: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum ManifestError {
+  Io(io::Error),
+  /// The manifest or its envelope could not be decoded.
+  Decode(bincode::Error),
+  /// The public key of the signer could not be decoded.
+  InvalidSigner,
+  /// The signature does not match the payload and signer.
+  InvalidSignature,
+  /// The signer is not one of the admins allowed to update the station.
+  NotAnAdmin(PeerId),
+  /// The manifest is older than the one we already hold.
+  Outdated { held: u64, received: u64 },
+  /// Fewer admins signed the manifest than the station requires.
+  BelowThreshold { approvals: usize, threshold: usize },
+  /// The manifest requires more signatures than it has admins.
+  ThresholdTooHigh { threshold: usize, admins: usize },
+  /// Signing the manifest with the local key failed.
+  Signing(identity::error::SigningError),
+}
+
+impl fmt::Display for ManifestError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ManifestError::Io(err) => write!(f, "Cannot access manifest: {}", err),
+      ManifestError::Decode(err) => write!(f, "Cannot decode manifest: {}", err),
+      ManifestError::InvalidSigner => write!(f, "Manifest signer key is invalid"),
+      ManifestError::InvalidSignature => write!(f, "Manifest signature does not verify"),
+      ManifestError::NotAnAdmin(peer_id) => write!(f, "Manifest signer {} is not an admin", peer_id),
+      ManifestError::Outdated { held, received } => write!(
+        f,
+        "Manifest version {} is older than the held version {}",
+        received, held
+      ),
+      ManifestError::BelowThreshold {
+        approvals,
+        threshold,
+      } => write!(
+        f,
+        "Manifest is signed by {} of the {} admins required",
+        approvals, threshold
+      ),
+      ManifestError::ThresholdTooHigh { threshold, admins } => write!(
+        f,
+        "Manifest requires {} signatures but has {} admins",
+        threshold, admins
+      ),
+      ManifestError::Signing(err) => write!(f, "Cannot sign manifest: {}", err),
+    }
+  }
+}
+
+impl std::error::Error for ManifestError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      ManifestError::Io(err) => Some(err),
+      ManifestError::Decode(err) => Some(err),
+      ManifestError::Signing(err) => Some(err),
+      _ => None,
+    }
+  }
+}
+
+impl From<io::Error> for ManifestError {
+  fn from(err: io::Error) -> ManifestError {
+    ManifestError::Io(err)
+  }
+}
+
+impl From<bincode::Error> for ManifestError {
+  fn from(err: bincode::Error) -> ManifestError {
+    ManifestError::Decode(err)
+  }
+}
+
+impl From<identity::error::SigningError> for ManifestError {
+  fn from(err: identity::error::SigningError) -> ManifestError {
+    ManifestError::Signing(err)
+  }
+}
+
+/// Returns the DHT key under which the update of `station` waiting for the
+/// signatures of its admins is published.
+pub fn proposal_key(station: &PeerId) -> record::Key {
+  let mut input = b"/radiopeer/proposal/".to_vec();
+  input.extend_from_slice(station.as_bytes());
+  let hash = multihash::encode(multihash::Hash::SHA2256, &input).expect("SHA2-256 is supported");
+  record::Key::from(hash)
+}
+
+/// Returns the DHT key under which the manifest of the station created by `station` is published.
+pub fn station_key(station: &PeerId) -> record::Key {
+  let mut input = b"/radiopeer/station/".to_vec();
+  input.extend_from_slice(station.as_bytes());
+  let hash = multihash::encode(multihash::Hash::SHA2256, &input).expect("SHA2-256 is supported");
+  record::Key::from(hash)
+}
+
+impl Manifest {
+  /// Creates the first version of a station manifest, administered by `admin`.
+  pub fn new(admin: &PeerId) -> Self {
+    let mut manifest = Manifest {
+      version: 1,
+      ..Default::default()
+    };
+    manifest.add_admin(admin);
+    manifest
+  }
+
+  /// Reads a manifest saved with `save`.
+  pub fn load(path: &Path) -> Result<Self, ManifestError> {
+    Ok(bincode::deserialize(&fs::read(path)?)?)
+  }
+
+  pub fn save(&self, path: &Path) -> Result<(), ManifestError> {
+    write_atomic(path, &bincode::serialize(self)?)?;
+    Ok(())
+  }
+
+  /// Returns a dot for the next edit, after every edit of the manifest.
+  fn next_dot(&self) -> Dot {
+    Dot::after(cmp::max(self.songs.clock(), self.admins.clock()))
+  }
+
+  /// Appends a song to the playlist; on air, follow with `rebase`.
+  pub fn add_song(&mut self, song: SongHash, duration_ms: u64, title: String) {
+    let dot = self.next_dot();
+    let track = Track {
+      song,
+      duration_ms,
+      title,
+    };
+    self.songs.push(track, dot);
+  }
+
+  /// Removes the song at `index` from the playlist; on air, follow with `rebase`.
+  pub fn remove_song(&mut self, index: usize) -> Option<SongHash> {
+    self.songs.remove(index).map(|track| track.song)
+  }
+
+  /// Returns the title of the song at `index`, or its hash if it has none.
+  pub fn title(&self, index: usize) -> Option<String> {
+    let track = self.songs.get(index)?;
+    match track.title.as_str() {
+      "" => Some(multihash::to_hex(&track.song)),
+      title => Some(title.to_string()),
+    }
+  }
+
+  pub fn is_admin(&self, peer_id: &PeerId) -> bool {
+    self.admins.contains(peer_id.as_bytes())
+  }
+
+  /// Adds an admin, returning false if it was one already.
+  pub fn add_admin(&mut self, peer_id: &PeerId) -> bool {
+    let dot = self.next_dot();
+    self.admins.insert(peer_id.as_bytes().to_vec(), dot)
+  }
+
+  /// Removes an admin, returning false if it was not one.
+  pub fn remove_admin(&mut self, peer_id: &PeerId) -> bool {
+    self.admins.remove(peer_id.as_bytes())
+  }
+
+  /// Edits the playlist, admins and settings to match `target`, an earlier
+  /// version of the station.
+  ///
+  /// The changes are new edits, so they win over the versions that came after
+  /// `target` when merged with them.
+  pub fn restore(&mut self, target: &Manifest) {
+    if !self.songs.iter().eq(target.songs.iter()) {
+      while self.songs.remove(0).is_some() {}
+      for track in target.songs.iter() {
+        let dot = self.next_dot();
+        self.songs.push(track.clone(), dot);
+      }
+    }
+    let removed: Vec<PeerID> = self
+      .admins
+      .iter()
+      .filter(|admin| !target.admins.contains(*admin))
+      .cloned()
+      .collect();
+    for admin in removed {
+      self.admins.remove(&admin);
+    }
+    for admin in target.admins.iter() {
+      if !self.admins.contains(admin) {
+        let dot = self.next_dot();
+        self.admins.insert(admin.clone(), dot);
+      }
+    }
+    self.started_at = target.started_at;
+    self.threshold = target.threshold;
+  }
+
+  /// Merges the edits of `other`, a manifest of the same station.
+  ///
+  /// The playlists and admin sets are merged, and the other fields are taken
+  /// from the higher version, the highest values on a tie. Merging is
+  /// commutative, so every peer that merged the same manifests holds the same one.
+  pub fn merge(&mut self, other: &Manifest) {
+    self.songs.merge(&other.songs);
+    self.admins.merge(&other.admins);
+    if other.version > self.version {
+      self.started_at = other.started_at;
+      self.threshold = other.threshold;
+    } else if other.version == self.version {
+      self.started_at = cmp::max(self.started_at, other.started_at);
+      self.threshold = cmp::max(self.threshold, other.threshold);
+    }
+    self.version = cmp::max(self.version, other.version);
+  }
+
+  /// Whether merging `other` would not change this manifest.
+  pub fn includes(&self, other: &Manifest) -> bool {
+    let mut merged = self.clone();
+    merged.merge(other);
+    merged.version == self.version
+      && merged.started_at == self.started_at
+      && merged.threshold == self.threshold
+      && merged.songs == self.songs
+      && merged.admins == self.admins
+  }
+
+  /// Returns how many admins must sign an update of the station.
+  pub fn threshold(&self) -> usize {
+    cmp::max(self.threshold, 1) as usize
+  }
+
+  /// Signs the manifest with `keypair`, which must belong to one of the admins.
+  ///
+  /// Other admins add their signatures with `SignedManifest::cosign` until the
+  /// threshold of the station is met.
+  pub fn sign(&self, keypair: &identity::Keypair) -> Result<SignedManifest, ManifestError> {
+    let peer_id = keypair.public().into_peer_id();
+    if !self.is_admin(&peer_id) {
+      return Err(ManifestError::NotAnAdmin(peer_id));
+    }
+    // Nobody could ever update the station again.
+    if self.threshold() > self.admins.len() {
+      return Err(ManifestError::ThresholdTooHigh {
+        threshold: self.threshold(),
+        admins: self.admins.len(),
+      });
+    }
+    let payload = bincode::serialize(self)?;
+    let signature = keypair.sign(&payload)?;
+    Ok(SignedManifest {
+      payload,
+      signer: keypair.public().into_protobuf_encoding(),
+      signature,
+      cosignatures: Vec::new(),
+    })
+  }
+}
+
+impl SignedManifest {
+  pub fn from_bytes(bytes: &[u8]) -> Result<Self, ManifestError> {
+    Ok(bincode::deserialize(bytes)?)
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    bincode::serialize(self).expect("Serializing a SignedManifest cannot fail")
+  }
+
+  /// Returns the `PeerId` of the key that signed the manifest.
+  pub fn signer(&self) -> Result<PeerId, ManifestError> {
+    PublicKey::from_protobuf_encoding(&self.signer)
+      .map(PublicKey::into_peer_id)
+      .map_err(|_| ManifestError::InvalidSigner)
+  }
+
+  /// Checks the signature and decodes the manifest, returning it with its signer.
+  ///
+  /// Whether the signer may update the station is left to the caller.
+  pub fn open(&self) -> Result<(PeerId, Manifest), ManifestError> {
+    let key =
+      PublicKey::from_protobuf_encoding(&self.signer).map_err(|_| ManifestError::InvalidSigner)?;
+    if !key.verify(&self.payload, &self.signature) {
+      return Err(ManifestError::InvalidSignature);
+    }
+    let manifest: Manifest = bincode::deserialize(&self.payload)?;
+    Ok((key.into_peer_id(), manifest))
+  }
+
+  /// Adds the signature of `keypair`, which must belong to one of the admins of
+  /// the signed manifest. Returns false if it had signed already.
+  pub fn cosign(&mut self, keypair: &identity::Keypair) -> Result<bool, ManifestError> {
+    let peer_id = keypair.public().into_peer_id();
+    let (_, manifest) = self.open()?;
+    if !manifest.is_admin(&peer_id) {
+      return Err(ManifestError::NotAnAdmin(peer_id));
+    }
+    if self.signers()?.contains(&peer_id) {
+      return Ok(false);
+    }
+    self.cosignatures.push(Cosignature {
+      signer: keypair.public().into_protobuf_encoding(),
+      signature: keypair.sign(&self.payload)?,
+    });
+    Ok(true)
+  }
+
+  /// Adds the signatures of `other` over the same payload, returning false if
+  /// the payloads differ. Call `signers` to check the signatures added.
+  pub fn merge(&mut self, other: &SignedManifest) -> bool {
+    if self.payload != other.payload {
+      return false;
+    }
+    let primary = Cosignature {
+      signer: other.signer.clone(),
+      signature: other.signature.clone(),
+    };
+    for cosignature in std::iter::once(&primary).chain(&other.cosignatures) {
+      let known = cosignature.signer == self.signer
+        || self.cosignatures.iter().any(|c| c.signer == cosignature.signer);
+      if !known {
+        self.cosignatures.push(cosignature.clone());
+      }
+    }
+    true
+  }
+
+  /// Checks every signature, returning the peers that signed, the primary signer first.
+  pub fn signers(&self) -> Result<Vec<PeerId>, ManifestError> {
+    let (signer, _) = self.open()?;
+    let mut signers = vec![signer];
+    for cosignature in &self.cosignatures {
+      let key = PublicKey::from_protobuf_encoding(&cosignature.signer)
+        .map_err(|_| ManifestError::InvalidSigner)?;
+      if !key.verify(&self.payload, &cosignature.signature) {
+        return Err(ManifestError::InvalidSignature);
+      }
+      let peer_id = key.into_peer_id();
+      if !signers.contains(&peer_id) {
+        signers.push(peer_id);
+      }
+    }
+    Ok(signers)
+  }
+
+  /// Checks the signature and decodes the manifest.
+  ///
+  /// The signer must be in `admins` if given, otherwise it must be in the admin
+  /// set of the manifest itself.
+  pub fn verify(&self, admins: Option<&OrSet<PeerID>>) -> Result<Manifest, ManifestError> {
+    let (signer, manifest) = self.open()?;
+    let admins = admins.unwrap_or(&manifest.admins);
+    if !admins.contains(signer.as_bytes()) {
+      return Err(ManifestError::NotAnAdmin(signer));
+    }
+    Ok(manifest)
+  }
+}
+
+/// Longest chain of key rotations followed from an admin.
+const MAX_ROTATIONS: usize = 16;
+
+/// The latest verified manifest of every station this node follows.
+#[derive(Default)]
+pub struct Stations {
+  // Station (creator) PeerId by DHT key
+  keys: HashMap<record::Key, PeerId>,
+  held: HashMap<PeerId, Manifest>,
+  // Rotated key by DHT key of its successor record
+  successor_keys: HashMap<record::Key, PeerId>,
+  // New key of every rotated key, from verified successor records
+  successors: HashMap<PeerId, PeerId>,
+  // Station PeerId by DHT key of its proposal
+  proposal_keys: HashMap<record::Key, PeerId>,
+  // Updates waiting for more signatures, by station
+  proposals: HashMap<PeerId, SignedManifest>,
+}
+
+impl Stations {
+  /// Starts following `station`, returning the DHT key its manifest lives under.
+  pub fn follow(&mut self, station: PeerId) -> record::Key {
+    let key = station_key(&station);
+    self.keys.insert(key.clone(), station);
+    key
+  }
+
+  /// Returns the station whose manifest is published under `key`, if we follow it.
+  pub fn station_of(&self, key: &record::Key) -> Option<&PeerId> {
+    self.keys.get(key)
+  }
+
+  pub fn get(&self, station: &PeerId) -> Option<&Manifest> {
+    self.held.get(station)
+  }
+
+  /// Returns the stations we follow.
+  pub fn followed(&self) -> impl Iterator<Item = &PeerId> {
+    self.keys.values()
+  }
+
+  /// Starts watching for a successor of `peer_id`, returning the DHT key of its successor record.
+  pub fn watch_successor(&mut self, peer_id: PeerId) -> record::Key {
+    let key = successor_key(&peer_id);
+    self.successor_keys.insert(key.clone(), peer_id);
+    key
+  }
+
+  /// Returns the key whose successor record is published under `key`, if we watch it.
+  pub fn rotated_of(&self, key: &record::Key) -> Option<&PeerId> {
+    self.successor_keys.get(key)
+  }
+
+  /// Records that the key of `old` was rotated to `new`, from a verified
+  /// `SignedSuccessor`. Returns false if it was known already.
+  pub fn add_successor(&mut self, old: PeerId, new: PeerId) -> bool {
+    self.successors.insert(old, new.clone()).as_ref() != Some(&new)
+  }
+
+  /// Returns `peer_id` followed by the keys it was rotated to, oldest first.
+  pub fn rotations(&self, peer_id: &PeerId) -> Vec<PeerId> {
+    let mut chain = vec![peer_id.clone()];
+    while let Some(next) = self.successors.get(chain.last().expect("never empty")) {
+      if chain.contains(next) || chain.len() > MAX_ROTATIONS {
+        break;
+      }
+      chain.push(next.clone());
+    }
+    chain
+  }
+
+  /// Whether `peer_id` may speak for `station`: as one of the admins of the
+  /// manifest we hold, or as the station itself before we hold one, keys they
+  /// were rotated to included.
+  pub fn authorizes(&self, station: &PeerId, peer_id: &PeerId) -> bool {
+    match self.held.get(station) {
+      Some(manifest) => self.is_admin(&manifest.admins, peer_id),
+      None => self.rotations(station).contains(peer_id),
+    }
+  }
+
+  /// Starts watching the proposals of `station`, returning the DHT key they live under.
+  pub fn watch_proposal(&mut self, station: PeerId) -> record::Key {
+    let key = proposal_key(&station);
+    self.proposal_keys.insert(key.clone(), station);
+    key
+  }
+
+  /// Returns the station whose proposal is published under `key`, if we watch it.
+  pub fn proposal_of(&self, key: &record::Key) -> Option<&PeerId> {
+    self.proposal_keys.get(key)
+  }
+
+  /// Returns the update of `station` waiting for signatures, if we know of one.
+  pub fn proposal(&self, station: &PeerId) -> Option<&SignedManifest> {
+    self.proposals.get(station)
+  }
+
+  /// Keeps `signed` as the update proposed for `station`, merging the signatures
+  /// if we know the same proposal already, and returns its approvals and the
+  /// threshold it must meet.
+  ///
+  /// A proposal must be signed by at least one admin and be newer than the
+  /// manifest we hold. Of two different proposals, the newer one wins, then
+  /// the one with more approvals.
+  pub fn propose(
+    &mut self,
+    station: &PeerId,
+    signed: &SignedManifest,
+  ) -> Result<(usize, usize), ManifestError> {
+    let (approvals, _) = self.approvals(station, signed)?;
+    if approvals == 0 {
+      return Err(ManifestError::NotAnAdmin(signed.signer()?));
+    }
+    let (_, manifest) = signed.open()?;
+    if let Some(held) = self.held.get(station) {
+      if manifest.version <= held.version {
+        return Err(ManifestError::Outdated {
+          held: held.version,
+          received: manifest.version,
+        });
+      }
+    }
+    let mut merged = match self.proposals.get(station) {
+      Some(current) => current.clone(),
+      None => signed.clone(),
+    };
+    if !merged.merge(signed) {
+      let (_, current) = merged.open()?;
+      let (current_approvals, _) = self.approvals(station, &merged)?;
+      let newer = (manifest.version, approvals) > (current.version, current_approvals);
+      if !newer {
+        return Err(ManifestError::Outdated {
+          held: current.version,
+          received: manifest.version,
+        });
+      }
+      merged = signed.clone();
+    }
+    let res = self.approvals(station, &merged)?;
+    self.proposals.insert(station.clone(), merged);
+    Ok(res)
+  }
+
+  /// Counts the admins of `station` that signed `signed`, returning it with the
+  /// number of admins that must sign.
+  ///
+  /// The admins and threshold are those of the manifest we hold, so an update
+  /// cannot lower the bar it has to pass. Before we hold one they are those of
+  /// the manifest itself, which must include the station creator and be signed
+  /// by it, so nobody else can put a first manifest under the station key. An
+  /// admin signing with a key it was rotated to is counted once.
+  fn approvals(
+    &self,
+    station: &PeerId,
+    signed: &SignedManifest,
+  ) -> Result<(usize, usize), ManifestError> {
+    let signers = signed.signers()?;
+    let (_, manifest) = signed.open()?;
+    let governing = match self.held.get(station) {
+      Some(held) => held,
+      None => {
+        let rotations = self.rotations(station);
+        if !rotations.iter().any(|p| manifest.is_admin(p))
+          || !rotations.iter().any(|p| signers.contains(p))
+        {
+          return Err(ManifestError::NotAnAdmin(station.clone()));
+        }
+        &manifest
+      }
+    };
+    let approvals = governing
+      .admins
+      .iter()
+      .filter_map(|admin| PeerId::from_bytes(admin.clone()).ok())
+      .filter(|admin| self.rotations(admin).iter().any(|key| signers.contains(key)))
+      .count();
+    Ok((approvals, governing.threshold()))
+  }
+
+  /// Whether `peer_id` is one of `admins` or the successor of one.
+  fn is_admin(&self, admins: &OrSet<PeerID>, peer_id: &PeerId) -> bool {
+    admins.contains(peer_id.as_bytes())
+      || admins.iter().any(|admin| match PeerId::from_bytes(admin.clone()) {
+        Ok(admin) => self.rotations(&admin).contains(peer_id),
+        Err(_) => false,
+      })
+  }
+
+  /// Verifies a manifest fetched for `station` and merges it into the one we hold
+  /// if it is acceptable.
+  ///
+  /// Once we hold a manifest for the station, updates must be signed by as many
+  /// of its admins as its threshold requires. Concurrent updates are merged, so
+  /// peers that accepted the same updates hold the same manifest in any order.
+  /// The first manifest we see is verified against its own admins and threshold,
+  /// and the station creator must be among its admins and signers. An admin that rotated its
+  /// key counts as the successor key as well.
+  pub fn accept(
+    &mut self,
+    station: &PeerId,
+    signed: &SignedManifest,
+  ) -> Result<&Manifest, ManifestError> {
+    let (signer, manifest) = signed.open()?;
+    let (approvals, threshold) = self.approvals(station, signed)?;
+    if approvals == 0 {
+      return Err(ManifestError::NotAnAdmin(signer));
+    }
+    if approvals < threshold {
+      return Err(ManifestError::BelowThreshold {
+        approvals,
+        threshold,
+      });
+    }
+    let manifest = match self.held.get(station) {
+      // An older manifest is still merged if it has edits we missed.
+      Some(held) if manifest.version < held.version && held.includes(&manifest) => {
+        return Err(ManifestError::Outdated {
+          held: held.version,
+          received: manifest.version,
+        });
+      }
+      Some(held) => {
+        let mut merged = held.clone();
+        merged.merge(&manifest);
+        merged
+      }
+      None => manifest,
+    };
+    // The proposal made it, or was overtaken.
+    let proposed = self.proposals.get(station).and_then(|p| p.open().ok());
+    if let Some((_, proposal)) = proposed {
+      if proposal.version <= manifest.version {
+        self.proposals.remove(station);
+      }
+    }
+    self.held.insert(station.clone(), manifest);
+    Ok(&self.held[station])
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use identity::Keypair;
+
+  fn signed_by(manifest: &Manifest, keypairs: &[&Keypair]) -> SignedManifest {
+    let mut signed = manifest.sign(keypairs[0]).unwrap();
+    for keypair in &keypairs[1..] {
+      signed.cosign(keypair).unwrap();
+    }
+    signed
+  }
+
+  #[test]
+  fn first_manifest_must_be_signed_by_the_creator() {
+    let creator = Keypair::generate_ed25519();
+    let attacker = Keypair::generate_ed25519();
+    let station = creator.public().into_peer_id();
+    let mut forged = Manifest::new(&station);
+    forged.add_admin(&attacker.public().into_peer_id());
+    let mut stations = Stations::default();
+    match stations.accept(&station, &signed_by(&forged, &[&attacker])) {
+      Err(ManifestError::NotAnAdmin(peer_id)) => assert_eq!(peer_id, station),
+      res => panic!("{:?}", res.map(|m| m.version)),
+    }
+    assert!(stations.get(&station).is_none());
+    let manifest = Manifest::new(&station);
+    stations.accept(&station, &signed_by(&manifest, &[&creator])).unwrap();
+    assert!(stations.authorizes(&station, &station));
+    assert!(!stations.authorizes(&station, &attacker.public().into_peer_id()));
+  }
+
+  #[test]
+  fn first_manifest_may_be_signed_by_a_rotated_key() {
+    let creator = Keypair::generate_ed25519();
+    let rotated = Keypair::generate_ed25519();
+    let station = creator.public().into_peer_id();
+    let mut manifest = Manifest::new(&station);
+    manifest.add_admin(&rotated.public().into_peer_id());
+    let signed = signed_by(&manifest, &[&rotated]);
+    let mut stations = Stations::default();
+    assert!(stations.accept(&station, &signed).is_err());
+    stations.add_successor(station.clone(), rotated.public().into_peer_id());
+    stations.accept(&station, &signed).unwrap();
+  }
+
+  /// A station of two admins, the creator first, that needs both to sign, as
+  /// held by a fresh `Stations`.
+  fn two_of_two() -> (Keypair, Keypair, PeerId, Manifest, Stations) {
+    let creator = Keypair::generate_ed25519();
+    let other = Keypair::generate_ed25519();
+    let station = creator.public().into_peer_id();
+    let mut manifest = Manifest::new(&station);
+    manifest.add_admin(&other.public().into_peer_id());
+    manifest.threshold = 2;
+    let mut stations = Stations::default();
+    stations
+      .accept(&station, &signed_by(&manifest, &[&creator, &other]))
+      .unwrap();
+    (creator, other, station, manifest, stations)
+  }
+
+  fn below_threshold<T>(res: Result<T, ManifestError>) -> (usize, usize) {
+    match res {
+      Err(ManifestError::BelowThreshold {
+        approvals,
+        threshold,
+      }) => (approvals, threshold),
+      Err(e) => panic!("{}", e),
+      Ok(_) => panic!("accepted"),
+    }
+  }
+
+  #[test]
+  fn updates_need_the_threshold_of_approvals() {
+    let (creator, other, station, mut manifest, mut stations) = two_of_two();
+    manifest.add_song(vec![1], 1000, "song".to_string());
+    manifest.version = 2;
+    let mut signed = signed_by(&manifest, &[&creator]);
+    assert_eq!(below_threshold(stations.accept(&station, &signed)), (1, 2));
+    assert_eq!(stations.get(&station).unwrap().version, 1);
+    assert!(signed.cosign(&other).unwrap());
+    assert!(!signed.cosign(&other).unwrap());
+    let held = stations.accept(&station, &signed).unwrap();
+    assert_eq!(held.version, 2);
+    assert_eq!(held.songs.len(), 1);
+  }
+
+  #[test]
+  fn proposals_collect_cosignatures() {
+    let (creator, other, station, mut manifest, mut stations) = two_of_two();
+    manifest.version = 2;
+    let first = signed_by(&manifest, &[&creator]);
+    let second = signed_by(&manifest, &[&other]);
+    assert_eq!(stations.propose(&station, &first).unwrap(), (1, 2));
+    assert_eq!(stations.propose(&station, &second).unwrap(), (2, 2));
+    let proposal = stations.proposal(&station).unwrap().clone();
+    assert_eq!(proposal.signers().unwrap().len(), 2);
+    stations.accept(&station, &proposal).unwrap();
+    assert!(stations.proposal(&station).is_none());
+    // Nothing newer than the held manifest is proposed any more.
+    match stations.propose(&station, &first) {
+      Err(ManifestError::Outdated { held: 2, received: 2 }) => {}
+      res => panic!("{:?}", res),
+    }
+  }
+
+  #[test]
+  fn an_admin_is_counted_once() {
+    let (creator, _, station, mut manifest, mut stations) = two_of_two();
+    manifest.version = 2;
+    let mut signed = signed_by(&manifest, &[&creator]);
+    assert!(!signed.cosign(&creator).unwrap());
+    // The same signature twice.
+    signed.cosignatures.push(Cosignature {
+      signer: signed.signer.clone(),
+      signature: signed.signature.clone(),
+    });
+    assert_eq!(below_threshold(stations.accept(&station, &signed)), (1, 2));
+    // The creator again, with the key it rotated to.
+    let rotated = Keypair::generate_ed25519();
+    stations.add_successor(station.clone(), rotated.public().into_peer_id());
+    manifest.add_admin(&rotated.public().into_peer_id());
+    let mut signed = signed_by(&manifest, &[&creator]);
+    signed.cosign(&rotated).unwrap();
+    assert_eq!(below_threshold(stations.accept(&station, &signed)), (1, 2));
+  }
+
+  #[test]
+  fn updates_cannot_lower_their_own_threshold() {
+    let (creator, _, station, mut manifest, mut stations) = two_of_two();
+    manifest.threshold = 1;
+    manifest.version = 2;
+    let signed = signed_by(&manifest, &[&creator]);
+    assert_eq!(below_threshold(stations.accept(&station, &signed)), (1, 2));
+    // As a proposal it still waits for the other admin.
+    assert_eq!(stations.propose(&station, &signed).unwrap(), (1, 2));
+    assert_eq!(stations.get(&station).unwrap().threshold(), 2);
+  }
+
+  #[test]
+  fn older_manifests_are_merged_only_with_missed_edits() {
+    let creator = Keypair::generate_ed25519();
+    let station = creator.public().into_peer_id();
+    let mut base = Manifest::new(&station);
+    base.add_song(vec![1], 1000, "one".to_string());
+    let mut newer = base.clone();
+    newer.add_song(vec![2], 1000, "two".to_string());
+    newer.version = 3;
+    let mut stations = Stations::default();
+    stations.accept(&station, &signed_by(&newer, &[&creator])).unwrap();
+    match stations.accept(&station, &signed_by(&base, &[&creator])) {
+      Err(ManifestError::Outdated { held: 3, received: 1 }) => {}
+      res => panic!("{:?}", res.map(|m| m.version)),
+    }
+    // A concurrent edit made on version 1 that we never saw.
+    let mut concurrent = base.clone();
+    concurrent.add_song(vec![3], 1000, "three".to_string());
+    concurrent.version = 2;
+    let held = stations
+      .accept(&station, &signed_by(&concurrent, &[&creator]))
+      .unwrap();
+    assert_eq!(held.version, 3);
+    let songs: Vec<_> = held.songs.iter().map(|track| track.song.clone()).collect();
+    assert_eq!(songs.len(), 3);
+    assert!(songs.contains(&vec![3]));
+  }
 }
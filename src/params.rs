@@ -1,8 +1,12 @@
+use crate::keystore::KeyType;
+use crate::query::{RecordConfig, RecordOptions};
+use crate::sink::SinkSpec;
+use libp2p::kad::Quorum;
 use libp2p::{multiaddr, Multiaddr, PeerId};
-use structopt::{
-  clap::{arg_enum, App, AppSettings, Arg, SubCommand},
-  StructOpt,
-};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::time::Duration;
+use structopt::StructOpt;
 
 #[derive(Debug, StructOpt, Clone)]
 #[structopt(name = "radiopeer", about = "P2P radio")]
@@ -15,6 +19,240 @@ pub struct Params {
   pub bootnodes: Vec<String>,
   #[structopt(long = "nodename", value_name = "NAME")]
   pub nodename: Option<String>,
+  /// Where to play the station we are tuned to: wav:<DIR>, stdout, fifo:<PATH>,
+  /// http:<ADDR> or hls:<DIR>. Can be given several times. With stdout, the
+  /// node logs to stderr.
+  #[structopt(long = "output", value_name = "SINK")]
+  pub output: Vec<SinkSpec>,
+  /// Duration of the HLS segments, in seconds.
+  #[structopt(long = "hls-segment", value_name = "SECONDS", default_value = "6")]
+  pub hls_segment: u64,
+  /// Number of segments in the HLS playlist.
+  #[structopt(long = "hls-window", value_name = "SEGMENTS", default_value = "6")]
+  pub hls_window: usize,
+  /// Replicas that must store a station manifest: one, majority, all or a number.
+  #[structopt(
+    long = "manifest-put-quorum",
+    value_name = "QUORUM",
+    default_value = "majority",
+    parse(try_from_str = parse_quorum)
+  )]
+  pub manifest_put_quorum: Quorum,
+  /// Manifests to collect when looking up a station.
+  #[structopt(
+    long = "manifest-get-quorum",
+    value_name = "QUORUM",
+    default_value = "one",
+    parse(try_from_str = parse_quorum)
+  )]
+  pub manifest_get_quorum: Quorum,
+  /// How long station manifests live in the DHT, in seconds; 0 for the Kademlia default.
+  #[structopt(long = "manifest-ttl", value_name = "SECONDS", default_value = "129600")]
+  pub manifest_ttl: u64,
+  /// Replicas that must store a value put from the console.
+  #[structopt(
+    long = "value-put-quorum",
+    value_name = "QUORUM",
+    default_value = "one",
+    parse(try_from_str = parse_quorum)
+  )]
+  pub value_put_quorum: Quorum,
+  /// Values to collect when looking up a key from the console.
+  #[structopt(
+    long = "value-get-quorum",
+    value_name = "QUORUM",
+    default_value = "one",
+    parse(try_from_str = parse_quorum)
+  )]
+  pub value_get_quorum: Quorum,
+  /// How long values put from the console live in the DHT, in seconds; 0 for the Kademlia default.
+  #[structopt(long = "value-ttl", value_name = "SECONDS", default_value = "3600")]
+  pub value_ttl: u64,
+  /// Find peers on the local network with mDNS; this is the default.
+  #[structopt(long = "mdns", overrides_with = "no-mdns")]
+  pub mdns: bool,
+  /// Do not look for peers on the local network.
+  #[structopt(long = "no-mdns", overrides_with = "mdns")]
+  pub no_mdns: bool,
+  /// Disconnect peers that sent us nothing for this long, in seconds; 0 to keep them.
+  ///
+  /// A few connections are always kept, however idle.
+  #[structopt(long = "idle-timeout", value_name = "SECONDS", default_value = "300")]
+  pub idle_timeout: u64,
+  /// Type of the key generated for a new node or by `key rotate`: secp256k1 or ed25519.
+  #[structopt(long = "key-type", value_name = "TYPE", default_value = "secp256k1")]
+  pub key_type: KeyType,
+  /// What to do; without a command the node just joins the network.
+  #[structopt(subcommand)]
+  pub command: Option<Command>,
+}
+
+impl Params {
+  /// Whether to find peers on the local network; the last of `--mdns` and `--no-mdns` wins.
+  pub fn mdns_enabled(&self) -> bool {
+    !self.no_mdns
+  }
+
+  /// How long a peer may stay idle before it is disconnected, if at all.
+  pub fn idle_timeout(&self) -> Option<Duration> {
+    Some(Duration::from_secs(self.idle_timeout)).filter(|timeout| *timeout > Duration::from_secs(0))
+  }
+
+  /// DHT options for every kind of record, from the command line.
+  pub fn record_config(&self) -> RecordConfig {
+    let ttl = |secs| Some(Duration::from_secs(secs)).filter(|ttl| *ttl > Duration::from_secs(0));
+    RecordConfig {
+      manifest: RecordOptions {
+        put_quorum: self.manifest_put_quorum,
+        get_quorum: self.manifest_get_quorum,
+        ttl: ttl(self.manifest_ttl),
+      },
+      value: RecordOptions {
+        put_quorum: self.value_put_quorum,
+        get_quorum: self.value_get_quorum,
+        ttl: ttl(self.value_ttl),
+      },
+    }
+  }
+}
+
+#[derive(Debug, StructOpt, Clone)]
+pub enum Command {
+  /// Manage the station of this node.
+  Station(StationCommand),
+  /// Listen to a station, playing it to the outputs.
+  Tune {
+    #[structopt(value_name = "STATION_ID")]
+    station: PeerId,
+  },
+  /// Join the network, print the peers found and exit.
+  Peers {
+    /// How long to look for peers, in seconds.
+    #[structopt(long = "wait", value_name = "SECONDS", default_value = "10")]
+    wait: u64,
+  },
+  /// Manage the key of this node, which is also the id of its station.
+  Key(KeyCommand),
+  /// Inspect the manifests this node accepted, and restore an earlier one.
+  History(HistoryCommand),
+}
+
+#[derive(Debug, StructOpt, Clone)]
+pub enum StationCommand {
+  /// Create the station of this node, with an empty playlist.
+  Create {
+    /// Replace the existing station manifest.
+    #[structopt(long = "force")]
+    force: bool,
+  },
+  /// Add an audio file to the chunk store and to the end of the playlist.
+  AddSong {
+    #[structopt(value_name = "FILE", parse(from_os_str))]
+    file: PathBuf,
+    /// Title shown to the listeners, the file name by default.
+    #[structopt(long = "title", value_name = "TITLE")]
+    title: Option<String>,
+  },
+  /// Add a co-admin to the station.
+  AddAdmin {
+    #[structopt(value_name = "PEER_ID")]
+    peer: PeerId,
+  },
+  /// Remove an admin from the station.
+  RemoveAdmin {
+    #[structopt(value_name = "PEER_ID")]
+    peer: PeerId,
+  },
+  /// Require the signatures of this many admins for every update of the station.
+  Threshold {
+    #[structopt(value_name = "M")]
+    threshold: u32,
+  },
+  /// Sign the station manifest and publish it to the DHT, then keep serving the songs.
+  ///
+  /// If the station needs more signatures, the manifest is proposed to the other
+  /// admins instead and published once they approved it.
+  Publish {
+    /// Start the broadcast now, from the first song.
+    #[structopt(long = "start")]
+    start: bool,
+    /// Exit once the manifest is published, with a non-zero code if it failed.
+    #[structopt(long = "once")]
+    once: bool,
+  },
+  /// Print the update proposed for a station we administer, and exit.
+  Review {
+    #[structopt(value_name = "STATION_ID")]
+    station: PeerId,
+  },
+  /// Sign the update proposed for a station we administer, and exit.
+  ///
+  /// The update is published as the new manifest once enough admins signed it.
+  Approve {
+    #[structopt(value_name = "STATION_ID")]
+    station: PeerId,
+    /// Version of the proposal to sign, as printed by `station review`.
+    #[structopt(long = "version", value_name = "VERSION")]
+    version: u64,
+  },
+}
+
+#[derive(Debug, StructOpt, Clone)]
+pub enum KeyCommand {
+  /// Print the peer id of this node.
+  Show,
+  /// Encrypt the key file with a passphrase, or change its passphrase.
+  ///
+  /// The passphrase is read from RADIOPEER_PASSPHRASE or asked on the terminal.
+  Encrypt,
+  /// Store the key file without encryption.
+  Decrypt,
+  /// Write the secret key as hex, to FILE or stdout.
+  Export {
+    #[structopt(value_name = "FILE", parse(from_os_str))]
+    file: Option<PathBuf>,
+  },
+  /// Replace the key of this node with a new one of `--key-type`, keeping its station.
+  ///
+  /// The old key signs a successor record naming the new one, which is published
+  /// to the DHT so the listeners and co-admins follow the station to the new key.
+  Rotate,
+  /// Replace the key of this node with the secret key of `--key-type` in FILE,
+  /// as hex or raw bytes.
+  Import {
+    #[structopt(value_name = "FILE", parse(from_os_str))]
+    file: PathBuf,
+    /// Overwrite the existing key; its peer id and station are lost.
+    #[structopt(long = "force")]
+    force: bool,
+  },
+}
+
+#[derive(Debug, StructOpt, Clone)]
+pub enum HistoryCommand {
+  /// List the manifest versions accepted for a station, oldest first.
+  List {
+    /// Station to list, the station of this node by default.
+    #[structopt(long = "station", value_name = "STATION_ID")]
+    station: Option<PeerId>,
+  },
+  /// Print the changes between two versions of a station.
+  Diff {
+    #[structopt(value_name = "FROM")]
+    from: u64,
+    #[structopt(value_name = "TO")]
+    to: u64,
+    /// Station to compare, the station of this node by default.
+    #[structopt(long = "station", value_name = "STATION_ID")]
+    station: Option<PeerId>,
+  },
+  /// Restore the playlist, admins and threshold of an earlier version of our station.
+  ///
+  /// The result is a new version of the station manifest, to publish with `station publish`.
+  Rollback {
+    #[structopt(value_name = "VERSION")]
+    version: u64,
+  },
 }
 
 use std::fmt;
@@ -54,6 +292,19 @@ impl From<multiaddr::Error> for ParseErr {
   }
 }
 
+/// Parses a quorum: `one`, `majority`, `all` or a number of peers.
+pub fn parse_quorum(s: &str) -> Result<Quorum, String> {
+  match s.to_ascii_lowercase().as_str() {
+    "one" => Ok(Quorum::One),
+    "majority" => Ok(Quorum::Majority),
+    "all" => Ok(Quorum::All),
+    n => n
+      .parse::<NonZeroUsize>()
+      .map(Quorum::N)
+      .map_err(|_| format!("{:?} is not one, majority, all or a positive number", s)),
+  }
+}
+
 pub fn parse_str_addr(addr_str: &str) -> Result<(PeerId, Multiaddr), ParseErr> {
   let addr: Multiaddr = addr_str.parse()?;
   parse_addr(addr)
@@ -0,0 +1,248 @@
+use crate::crdt::Dot;
+use crate::manifest::Manifest;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Where in the playlist of a station the broadcast is at a given time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlaybackPosition {
+  /// Index of the track in the playlist of the manifest.
+  pub track: usize,
+  /// How far into the track playback is.
+  pub offset: Duration,
+  /// How many times the whole playlist has been played before.
+  pub cycle: u64,
+}
+
+/// Converts a wall-clock time into milliseconds since the Unix epoch.
+pub fn unix_millis(time: SystemTime) -> u64 {
+  time
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_millis() as u64)
+    .unwrap_or(0)
+}
+
+impl Manifest {
+  fn durations(&self) -> impl Iterator<Item = u64> + '_ {
+    self.songs.iter().map(|track| track.duration_ms)
+  }
+
+  /// Total duration of the playlist in milliseconds.
+  pub fn playlist_duration(&self) -> u64 {
+    self.durations().sum()
+  }
+
+  /// Computes the playback position at `now`.
+  ///
+  /// The position only depends on `started_at` and the track durations, so every
+  /// listener that agrees on the time agrees on the position, no matter when it
+  /// joined. The playlist loops once it reaches the end. Returns `None` when the
+  /// station is off air or has nothing playable.
+  pub fn position_at(&self, now: SystemTime) -> Option<PlaybackPosition> {
+    let total = self.playlist_duration();
+    if self.started_at == 0 || total == 0 {
+      return None;
+    }
+    let elapsed = unix_millis(now).checked_sub(self.started_at)?;
+    let cycle = elapsed / total;
+    let mut remaining = elapsed % total;
+    for (track, duration) in self.durations().enumerate() {
+      if remaining < duration {
+        return Some(PlaybackPosition {
+          track,
+          offset: Duration::from_millis(remaining),
+          cycle,
+        });
+      }
+      remaining -= duration;
+    }
+    None
+  }
+
+  /// How long the station had been on air when the track of `position` started.
+  pub fn track_start(&self, position: &PlaybackPosition) -> Duration {
+    let before: u64 = self.durations().take(position.track).sum();
+    Duration::from_millis(position.cycle * self.playlist_duration() + before)
+  }
+
+  /// Updates `music_track` and `seconds_in_music` to the position at `now`.
+  pub fn sync_position(&mut self, now: SystemTime) -> Option<PlaybackPosition> {
+    let position = self.position_at(now)?;
+    self.music_track = position.track;
+    self.seconds_in_music = position.offset.as_secs() as u32;
+    Some(position)
+  }
+
+  /// Starts the broadcast at `now`, from the first track.
+  pub fn start_broadcast(&mut self, now: SystemTime) {
+    self.started_at = unix_millis(now);
+    self.version += 1;
+  }
+
+  /// Stops the broadcast.
+  pub fn stop_broadcast(&mut self) {
+    self.started_at = 0;
+    self.version += 1;
+  }
+
+  /// Moves the broadcast after an edit of the playlist, so the track playing at
+  /// `now` according to `before`, the manifest before the edit, goes on from the
+  /// same offset. If that track was removed, the next track left starts at `now`.
+  ///
+  /// The position depends on the duration of the whole playlist, so without this
+  /// every listener would jump elsewhere once the edit is published.
+  pub fn rebase(&mut self, before: &Manifest, now: SystemTime) {
+    let position = match before.position_at(now) {
+      Some(position) if self.started_at != 0 => position,
+      _ => return,
+    };
+    let total = self.playlist_duration();
+    if total == 0 {
+      return;
+    }
+    let old = before.songs.dots();
+    let new = self.songs.dots();
+    let find = |dot: &Dot| new.iter().position(|d| d == dot);
+    // `restore` adds the tracks again, with new dots.
+    let playing = before.songs.get(position.track);
+    let same = find(&old[position.track])
+      .or_else(|| self.songs.iter().position(|track| Some(track) == playing));
+    let (track, offset) = match same {
+      Some(track) => (track, position.offset.as_millis() as u64),
+      None => (old[position.track..].iter().find_map(find).unwrap_or(0), 0),
+    };
+    let before_track: u64 = self.durations().take(track).sum();
+    let elapsed = position.cycle * total + before_track + offset;
+    // Never 0, which would take the station off air.
+    self.started_at = unix_millis(now).saturating_sub(elapsed).max(1);
+  }
+
+  /// Moves the broadcast so the track after the current one starts at `now`.
+  ///
+  /// Shifting `started_at` keeps the position derivable from the manifest alone,
+  /// so the skip reaches every listener once the new version is published.
+  pub fn skip(&mut self, now: SystemTime) -> Option<PlaybackPosition> {
+    let position = self.position_at(now)?;
+    let duration = self.durations().nth(position.track).unwrap_or(0);
+    let left = duration - position.offset.as_millis() as u64;
+    self.started_at = self.started_at.saturating_sub(left);
+    self.version += 1;
+    self.sync_position(now)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use libp2p::PeerId;
+
+  const STARTED_AT: u64 = 1_000_000;
+
+  fn at(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+  }
+
+  fn manifest() -> Manifest {
+    let mut manifest = Manifest::new(&PeerId::random());
+    for (i, duration) in [1000, 2000, 3000].iter().enumerate() {
+      manifest.add_song(vec![i as u8], *duration, format!("song {}", i));
+    }
+    manifest.started_at = STARTED_AT;
+    manifest
+  }
+
+  fn position(track: usize, offset: u64, cycle: u64) -> Option<PlaybackPosition> {
+    Some(PlaybackPosition {
+      track,
+      offset: Duration::from_millis(offset),
+      cycle,
+    })
+  }
+
+  #[test]
+  fn position_loops_over_the_playlist() {
+    let manifest = manifest();
+    assert_eq!(manifest.position_at(at(STARTED_AT)), position(0, 0, 0));
+    assert_eq!(manifest.position_at(at(STARTED_AT + 999)), position(0, 999, 0));
+    assert_eq!(manifest.position_at(at(STARTED_AT + 1000)), position(1, 0, 0));
+    assert_eq!(manifest.position_at(at(STARTED_AT + 5999)), position(2, 2999, 0));
+    assert_eq!(manifest.position_at(at(STARTED_AT + 6000)), position(0, 0, 1));
+    assert_eq!(manifest.position_at(at(STARTED_AT + 13_500)), position(1, 500, 2));
+  }
+
+  #[test]
+  fn no_position_off_air_or_before_the_start() {
+    let mut manifest = manifest();
+    assert_eq!(manifest.position_at(at(STARTED_AT - 1)), None);
+    manifest.started_at = 0;
+    assert_eq!(manifest.position_at(at(STARTED_AT)), None);
+    let mut empty = Manifest::new(&PeerId::random());
+    empty.started_at = STARTED_AT;
+    assert_eq!(empty.position_at(at(STARTED_AT)), None);
+  }
+
+  #[test]
+  fn track_start_adds_up_the_tracks_before() {
+    let manifest = manifest();
+    for &elapsed in &[0, 999, 1000, 4500, 6000, 13_500] {
+      let position = manifest.position_at(at(STARTED_AT + elapsed)).unwrap();
+      let start = manifest.track_start(&position);
+      assert_eq!(start + position.offset, Duration::from_millis(elapsed));
+    }
+    let position = manifest.position_at(at(STARTED_AT + 13_500)).unwrap();
+    assert_eq!(manifest.track_start(&position), Duration::from_millis(13_000));
+  }
+
+  #[test]
+  fn edits_on_air_keep_the_current_track() {
+    let before = manifest();
+    // Into the third song.
+    let now = at(STARTED_AT + 6000 + 4500);
+    let playing = before.position_at(now).unwrap();
+    assert_eq!(playing, position(2, 1500, 1).unwrap());
+    let mut edited = before.clone();
+    edited.add_song(vec![3], 4000, "song 3".to_string());
+    edited.remove_song(0);
+    edited.rebase(&before, now);
+    let moved = edited.position_at(now).unwrap();
+    assert_eq!(edited.songs.get(moved.track).unwrap().song, vec![2]);
+    assert_eq!(moved.offset, playing.offset);
+    // Playback goes on from there.
+    let later = edited.position_at(at(STARTED_AT + 6000 + 6000)).unwrap();
+    assert_eq!(edited.songs.get(later.track).unwrap().song, vec![3]);
+    assert_eq!(later.offset, Duration::from_millis(0));
+  }
+
+  #[test]
+  fn removing_the_current_track_starts_the_next() {
+    let before = manifest();
+    let now = at(STARTED_AT + 1500);
+    let mut edited = before.clone();
+    edited.remove_song(1);
+    edited.rebase(&before, now);
+    let moved = edited.position_at(now).unwrap();
+    assert_eq!(edited.songs.get(moved.track).unwrap().song, vec![2]);
+    assert_eq!(moved.offset, Duration::from_millis(0));
+    // Off air, nothing moves.
+    let mut off_air = before.clone();
+    off_air.started_at = 0;
+    let mut edited = off_air.clone();
+    edited.remove_song(1);
+    edited.rebase(&off_air, now);
+    assert_eq!(edited.started_at, 0);
+  }
+
+  #[test]
+  fn restoring_keeps_the_current_track() {
+    let mut before = manifest();
+    before.add_song(vec![3], 4000, "song 3".to_string());
+    // Into the second cycle of the longer playlist, in the second song.
+    let now = at(STARTED_AT + 10_000 + 2500);
+    let target = manifest();
+    let mut restored = before.clone();
+    restored.restore(&target);
+    restored.rebase(&before, now);
+    let moved = restored.position_at(now).unwrap();
+    assert_eq!(restored.songs.get(moved.track).unwrap().song, vec![1]);
+    assert_eq!(moved.offset, Duration::from_millis(1500));
+  }
+}
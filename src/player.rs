@@ -0,0 +1,188 @@
+use crate::decode::{DecodeError, Decoder};
+use crate::manifest::{Manifest, SongHash};
+use crate::sink::AudioSink;
+use crate::store::ChunkStore;
+use futures::prelude::*;
+use futures03::{compat::Compat, TryFutureExt as _};
+use futures_timer::Delay;
+use libp2p::core::PeerId;
+use std::time::{Duration, SystemTime};
+
+/// How often the player wakes up to write audio.
+const TICK: Duration = Duration::from_millis(100);
+/// How far ahead of the playback position audio is written to the sink.
+const LEAD: Duration = Duration::from_millis(500);
+/// Drift from the station position after which the decoder seeks back in sync.
+const MAX_DRIFT: Duration = Duration::from_secs(1);
+
+/// Plays the station we are tuned to into a set of `AudioSink`s.
+///
+/// The player holds no playback state of its own: on every tick it asks the
+/// manifest where the station is at the network time and decodes up to there, so
+/// skips, manifest updates and clock corrections are followed by seeking.
+pub struct Player {
+  sinks: Vec<Box<dyn AudioSink + Send>>,
+  station: Option<PeerId>,
+  playing: Option<Playing>,
+  /// Song that could not be opened, so we do not retry it on every tick.
+  failed: Option<SongHash>,
+  tick: Compat<Delay>,
+}
+
+struct Playing {
+  track: usize,
+  song: SongHash,
+  decoder: Decoder,
+  /// How long the station had been on air when the track started.
+  start: Duration,
+}
+
+impl Player {
+  pub fn new(sinks: Vec<Box<dyn AudioSink + Send>>) -> Self {
+    Player {
+      sinks,
+      station: None,
+      playing: None,
+      failed: None,
+      tick: Delay::new(TICK).compat(),
+    }
+  }
+
+  /// Starts playing `station`, or stops playing with `None`.
+  pub fn tune(&mut self, station: Option<PeerId>) {
+    self.station = station;
+    self.playing = None;
+    self.failed = None;
+  }
+
+  pub fn station(&self) -> Option<&PeerId> {
+    self.station.as_ref()
+  }
+
+  /// Writes the audio of the station up to `now`, plus a small lead.
+  ///
+  /// Must be called from within a task; it schedules the task to be woken up at
+  /// the next tick.
+  pub fn poll(&mut self, manifest: Option<&Manifest>, store: &ChunkStore, now: SystemTime) {
+    loop {
+      match self.tick.poll() {
+        Ok(Async::NotReady) => break,
+        Ok(Async::Ready(_)) => self.tick = Delay::new(TICK).compat(),
+        Err(err) => {
+          println!("Player timer errored: {:?}", err);
+          break;
+        }
+      }
+    }
+    if let Some(manifest) = manifest.filter(|_| self.station.is_some()) {
+      self.play(manifest, store, now);
+    }
+    for sink in &mut self.sinks {
+      if let Err(e) = sink.flush() {
+        println!("Cannot flush the audio output: {}", e);
+      }
+    }
+  }
+
+  fn play(&mut self, manifest: &Manifest, store: &ChunkStore, now: SystemTime) {
+    let position = match manifest.position_at(now) {
+      Some(position) => position,
+      None => {
+        self.playing = None;
+        return;
+      }
+    };
+    let song = match manifest.songs.get(position.track) {
+      Some(track) => &track.song,
+      None => return,
+    };
+    let start = manifest.track_start(&position);
+    match self.open(position.track, song, start, store) {
+      Ok(true) => {
+        let title = manifest.title(position.track).unwrap_or_default();
+        for sink in &mut self.sinks {
+          sink.set_title(&title);
+        }
+      }
+      Ok(false) => {}
+      Err(e) => {
+        println!("Cannot play song {}: {}", libp2p::multihash::to_hex(song), e);
+        self.failed = Some(song.clone());
+        self.playing = None;
+        return;
+      }
+    }
+    if let Err(e) = self.write_until(position.offset) {
+      println!("Cannot play song {}: {}", libp2p::multihash::to_hex(song), e);
+      self.playing = None;
+    }
+  }
+
+  /// Makes sure the decoder plays `song` as track `track` of the playlist.
+  ///
+  /// Returns true if the track was just opened.
+  fn open(
+    &mut self,
+    track: usize,
+    song: &SongHash,
+    start: Duration,
+    store: &ChunkStore,
+  ) -> Result<bool, DecodeError> {
+    let current = self.playing.as_ref().map(|p| (p.track, &p.song));
+    if current == Some((track, song)) || self.failed.as_ref() == Some(song) {
+      return Ok(false);
+    }
+    self.playing = None;
+    // Not downloaded in full yet, try again on the next tick.
+    if !store.is_complete(song) {
+      return Ok(false);
+    }
+    self.playing = Some(Playing {
+      track,
+      song: song.clone(),
+      decoder: Decoder::from_store(store, song)?,
+      start,
+    });
+    for sink in &mut self.sinks {
+      sink.seek(start);
+    }
+    self.failed = None;
+    Ok(true)
+  }
+
+  fn write_until(&mut self, offset: Duration) -> Result<(), DecodeError> {
+    let playing = match self.playing.as_mut() {
+      Some(playing) => playing,
+      None => return Ok(()),
+    };
+    let decoder = &mut playing.decoder;
+    let at = decoder.position();
+    if at + MAX_DRIFT < offset || at > offset + LEAD + MAX_DRIFT {
+      match decoder.seek(offset) {
+        Ok(()) => {
+          for sink in &mut self.sinks {
+            sink.seek(playing.start + offset);
+          }
+        }
+        // The song is shorter than the manifest says, wait for the next track.
+        Err(DecodeError::SeekOutOfRange(_)) => return Ok(()),
+        Err(e) => return Err(e),
+      }
+    }
+    let format = decoder.format();
+    while decoder.position() < offset + LEAD {
+      match decoder.next_frames()? {
+        Some(samples) => {
+          for sink in &mut self.sinks {
+            if let Err(e) = sink.write(format, &samples) {
+              println!("Cannot write to the audio output: {}", e);
+            }
+          }
+        }
+        // The track is over, the next one starts on a later tick.
+        None => break,
+      }
+    }
+    Ok(())
+  }
+}
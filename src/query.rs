@@ -0,0 +1,229 @@
+use futures::prelude::*;
+use futures::sync::oneshot;
+use libp2p::kad::{record, GetRecordError, GetRecordResult, Quorum, Record};
+use std::collections::HashMap;
+use std::time::Duration;
+use std::{error, fmt};
+
+/// How the records of one kind are stored in and looked up from the DHT.
+///
+/// The publisher of every record we put is our own peer id: libp2p-kad stamps it
+/// on the record and does not let it be chosen.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordOptions {
+  /// Replicas that must store the record for a put to succeed.
+  pub put_quorum: Quorum,
+  /// Records to collect before a lookup finishes.
+  pub get_quorum: Quorum,
+  /// How long the record lives, in our store and on the replicas. With `None`
+  /// we keep it until it is removed and the replicas apply the Kademlia default.
+  pub ttl: Option<Duration>,
+}
+
+/// `RecordOptions` for every kind of record we put in the DHT.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordConfig {
+  /// Station manifests, which should outlive their publisher going offline.
+  pub manifest: RecordOptions,
+  /// Records put with `Behaviour::put_value`, e.g. from the console.
+  pub value: RecordOptions,
+}
+
+impl Default for RecordConfig {
+  fn default() -> Self {
+    RecordConfig {
+      manifest: RecordOptions {
+        put_quorum: Quorum::Majority,
+        get_quorum: Quorum::One,
+        ttl: Some(Duration::from_secs(36 * 60 * 60)),
+      },
+      value: RecordOptions {
+        put_quorum: Quorum::One,
+        get_quorum: Quorum::One,
+        ttl: Some(Duration::from_secs(60 * 60)),
+      },
+    }
+  }
+}
+
+/// Resolves to the records found by `Behaviour::get_value`.
+///
+/// Queries for a key that is being looked up already, by another query or by
+/// `Behaviour` itself, share that lookup and resolve with its result; see
+/// `PendingQueries`.
+///
+/// The swarm has to be polled for the query to make progress. Dropping the
+/// handle does not cancel the lookup.
+pub struct ValueQuery {
+  key: record::Key,
+  receiver: oneshot::Receiver<GetRecordResult>,
+}
+
+impl ValueQuery {
+  pub fn key(&self) -> &record::Key {
+    &self.key
+  }
+}
+
+impl Future for ValueQuery {
+  type Item = Vec<Record>;
+  type Error = ValueQueryError;
+
+  fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+    match self.receiver.poll() {
+      Ok(Async::Ready(Ok(ok))) => Ok(Async::Ready(ok.records)),
+      Ok(Async::Ready(Err(e))) => Err(ValueQueryError::Failed(e)),
+      Ok(Async::NotReady) => Ok(Async::NotReady),
+      Err(oneshot::Canceled) => Err(ValueQueryError::Dropped),
+    }
+  }
+}
+
+#[derive(Debug)]
+pub enum ValueQueryError {
+  /// No record was found, or fewer than the quorum before the lookup timed out.
+  ///
+  /// `GetRecordError::QuorumFailed` and `Timeout` still carry the records found.
+  Failed(GetRecordError),
+  /// The behaviour was dropped before the lookup finished.
+  Dropped,
+}
+
+impl fmt::Display for ValueQueryError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ValueQueryError::Failed(GetRecordError::NotFound { .. }) => write!(f, "Record not found"),
+      ValueQueryError::Failed(GetRecordError::QuorumFailed { records, quorum, .. }) => write!(
+        f,
+        "Found {} records, fewer than the quorum of {}",
+        records.len(),
+        quorum
+      ),
+      ValueQueryError::Failed(GetRecordError::Timeout { records, .. }) => {
+        write!(f, "Timed out after finding {} records", records.len())
+      }
+      ValueQueryError::Dropped => write!(f, "The query was dropped"),
+    }
+  }
+}
+
+impl error::Error for ValueQueryError {}
+
+/// The DHT lookups running and the `ValueQuery`s waiting for them to finish.
+///
+/// Kademlia does not identify its lookups, only the key of their result, so at
+/// most one lookup runs per key: a result is then always that of the lookup
+/// started for its key, and goes to every query waiting on it. Kademlia always
+/// reports a result, on success with at least one record, so lookups end.
+#[derive(Default)]
+pub struct PendingQueries {
+  /// Queries waiting on the running lookups, by key.
+  lookups: HashMap<record::Key, Vec<oneshot::Sender<GetRecordResult>>>,
+}
+
+impl PendingQueries {
+  /// Records that `key` has to be looked up, returning false if a lookup is
+  /// running already and no other must be started.
+  pub fn start(&mut self, key: &record::Key) -> bool {
+    if self.lookups.contains_key(key) {
+      return false;
+    }
+    self.lookups.insert(key.clone(), Vec::new());
+    true
+  }
+
+  /// Returns a handle on the result of the lookup of `key`, which must have
+  /// been started with `start`.
+  pub fn value(&mut self, key: record::Key) -> ValueQuery {
+    let (sender, receiver) = oneshot::channel();
+    self.lookups.entry(key.clone()).or_default().push(sender);
+    ValueQuery { key, receiver }
+  }
+
+  /// Ends the lookup of the key of `result`, resolving every query waiting on it.
+  pub fn resolve_value(&mut self, result: &GetRecordResult) {
+    let key = match result {
+      Ok(ok) => match ok.records.first() {
+        Some(record) => &record.key,
+        None => return,
+      },
+      Err(e) => e.key(),
+    };
+    for sender in self.lookups.remove(key).into_iter().flatten() {
+      // The handle may have been dropped, nobody is waiting then.
+      let _ = sender.send(result.clone());
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use libp2p::kad::GetRecordOk;
+
+  fn found(key: &record::Key, value: &[u8]) -> GetRecordResult {
+    Ok(GetRecordOk {
+      records: vec![Record::new(key.clone(), value.to_vec())],
+    })
+  }
+
+  fn not_found(key: &record::Key) -> GetRecordResult {
+    Err(GetRecordError::NotFound {
+      key: key.clone(),
+      closest_peers: Vec::new(),
+    })
+  }
+
+  #[test]
+  fn concurrent_queries_share_one_lookup() {
+    let key = record::Key::new(b"key");
+    let mut queries = PendingQueries::default();
+    assert!(queries.start(&key));
+    let first = queries.value(key.clone());
+    assert!(!queries.start(&key));
+    let second = queries.value(key.clone());
+    let dropped = queries.value(key.clone());
+    drop(dropped);
+    queries.resolve_value(&found(&key, b"value"));
+    assert_eq!(first.wait().unwrap()[0].value, b"value".to_vec());
+    assert_eq!(second.wait().unwrap()[0].value, b"value".to_vec());
+    // The next query starts a lookup of its own.
+    assert!(queries.start(&key));
+    let third = queries.value(key.clone());
+    queries.resolve_value(&not_found(&key));
+    match third.wait() {
+      Err(ValueQueryError::Failed(GetRecordError::NotFound { .. })) => {}
+      res => panic!("{:?}", res.map(|records| records.len())),
+    }
+  }
+
+  #[test]
+  fn results_only_resolve_queries_for_their_key() {
+    let key = record::Key::new(b"key");
+    let other = record::Key::new(b"other");
+    let mut queries = PendingQueries::default();
+    assert!(queries.start(&key));
+    let query = queries.value(key.clone());
+    assert!(queries.start(&other));
+    queries.resolve_value(&found(&other, b"value"));
+    assert!(!queries.start(&key));
+    queries.resolve_value(&not_found(&key));
+    match query.wait() {
+      Err(ValueQueryError::Failed(GetRecordError::NotFound { .. })) => {}
+      res => panic!("{:?}", res.map(|records| records.len())),
+    }
+  }
+
+  #[test]
+  fn dropped_behaviour_fails_the_queries() {
+    let key = record::Key::new(b"key");
+    let mut queries = PendingQueries::default();
+    queries.start(&key);
+    let query = queries.value(key);
+    drop(queries);
+    match query.wait() {
+      Err(ValueQueryError::Dropped) => {}
+      res => panic!("{:?}", res.map(|records| records.len())),
+    }
+  }
+}
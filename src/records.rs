@@ -0,0 +1,344 @@
+use crate::playback::unix_millis;
+use crate::store::{sha256, write_atomic};
+use libp2p::kad::record::store::{self, MemoryStore, RecordStore};
+use libp2p::kad::record::{Key, ProviderRecord, Record};
+use libp2p::{multihash, PeerId};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// A Kademlia record store that keeps the records and provider records on disk,
+/// so they survive a restart of the node.
+///
+/// The records live in a `MemoryStore`, which enforces its limits of 1024
+/// records of at most 65 KiB and 1024 provided keys, and every change is
+/// written through to one file per key. Files are named after the SHA-256 of
+/// the key, since keys can be longer than a file name. Expiration times are stored as wall clock time and records that expired
+/// while the node was down are dropped when the store is opened.
+pub struct DiskStore {
+  inner: MemoryStore,
+  records_path: PathBuf,
+  providers_path: PathBuf,
+  /// Keys with provider records, which `MemoryStore` cannot list.
+  provider_keys: HashSet<Key>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredRecord {
+  key: Vec<u8>,
+  value: Vec<u8>,
+  publisher: Option<Vec<u8>>,
+  /// Milliseconds since the Unix epoch.
+  expires: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredProvider {
+  provider: Vec<u8>,
+  expires: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredProviders {
+  key: Vec<u8>,
+  providers: Vec<StoredProvider>,
+}
+
+impl DiskStore {
+  /// Opens the store under `home_path`, loading the records that have not expired.
+  ///
+  /// Records beyond the limits are dropped.
+  pub fn open(home_path: &Path, local_id: PeerId) -> io::Result<Self> {
+    let records_path = home_path.join("records");
+    let providers_path = home_path.join("providers");
+    fs::create_dir_all(&records_path)?;
+    fs::create_dir_all(&providers_path)?;
+    let mut store = DiskStore {
+      inner: MemoryStore::new(local_id),
+      records_path,
+      providers_path,
+      provider_keys: HashSet::new(),
+    };
+    store.load()?;
+    Ok(store)
+  }
+
+  fn load(&mut self) -> io::Result<()> {
+    for path in files(&self.records_path)? {
+      let record = fs::read(&path).ok().and_then(|data| decode_record(&data));
+      match record {
+        Some(record) => {
+          if let Err(e) = self.inner.put(record) {
+            println!("Dropping DHT record {}: {:?}", path.display(), e);
+            remove_file(&path);
+          }
+        }
+        // Expired, or not readable; either way of no use.
+        None => remove_file(&path),
+      }
+    }
+    for path in files(&self.providers_path)? {
+      let records = fs::read(&path).ok().and_then(|data| decode_providers(&data));
+      let records = match records {
+        Some(records) if !records.is_empty() => records,
+        _ => {
+          remove_file(&path);
+          continue;
+        }
+      };
+      for record in records {
+        let key = record.key.clone();
+        match self.inner.add_provider(record) {
+          Ok(()) => {
+            self.provider_keys.insert(key);
+          }
+          Err(e) => println!("Dropping DHT provider record {}: {:?}", path.display(), e),
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Removes the records and provider records that expired.
+  pub fn remove_expired(&mut self) {
+    let now = Instant::now();
+    let expired: Vec<Key> = self
+      .inner
+      .records()
+      .filter(|r| r.is_expired(now))
+      .map(|r| r.key.clone())
+      .collect();
+    for key in expired {
+      self.remove(&key);
+    }
+    let keys: Vec<Key> = self.provider_keys.iter().cloned().collect();
+    for key in keys {
+      let providers = self.inner.providers(&key);
+      let mut changed = providers.is_empty();
+      for record in providers {
+        if record.is_expired(now) {
+          self.inner.remove_provider(&key, &record.provider);
+          changed = true;
+        }
+      }
+      // Rewriting every file on every sweep would cost a write and fsync per key.
+      if changed {
+        self.save_providers(&key);
+      }
+    }
+  }
+
+  fn record_path(&self, key: &Key) -> PathBuf {
+    self.records_path.join(multihash::to_hex(&sha256(key.as_ref())))
+  }
+
+  fn providers_path(&self, key: &Key) -> PathBuf {
+    self.providers_path.join(multihash::to_hex(&sha256(key.as_ref())))
+  }
+
+  /// Writes the record held for `key` to disk, or removes its file if there is none.
+  fn save_record(&self, key: &Key) {
+    let path = self.record_path(key);
+    match self.inner.get(key) {
+      Some(record) => {
+        if let Err(e) = write_atomic(&path, &encode_record(&record)) {
+          println!("Cannot save DHT record {}: {}", path.display(), e);
+        }
+      }
+      None => remove_file(&path),
+    }
+  }
+
+  /// Writes the provider records held for `key` to disk, or removes their file if there are none.
+  fn save_providers(&mut self, key: &Key) {
+    let path = self.providers_path(key);
+    let records = self.inner.providers(key);
+    if records.is_empty() {
+      self.provider_keys.remove(key);
+      remove_file(&path);
+      return;
+    }
+    self.provider_keys.insert(key.clone());
+    if let Err(e) = write_atomic(&path, &encode_providers(key, &records)) {
+      println!("Cannot save DHT provider records {}: {}", path.display(), e);
+    }
+  }
+}
+
+impl<'a> RecordStore<'a> for DiskStore {
+  type RecordsIter = <MemoryStore as RecordStore<'a>>::RecordsIter;
+  type ProvidedIter = <MemoryStore as RecordStore<'a>>::ProvidedIter;
+
+  fn get(&'a self, k: &Key) -> Option<Cow<'a, Record>> {
+    self.inner.get(k)
+  }
+
+  fn put(&'a mut self, r: Record) -> store::Result<()> {
+    let key = r.key.clone();
+    self.inner.put(r)?;
+    self.save_record(&key);
+    Ok(())
+  }
+
+  fn remove(&'a mut self, k: &Key) {
+    self.inner.remove(k);
+    self.save_record(k);
+  }
+
+  fn records(&'a self) -> Self::RecordsIter {
+    self.inner.records()
+  }
+
+  fn add_provider(&'a mut self, record: ProviderRecord) -> store::Result<()> {
+    let key = record.key.clone();
+    self.inner.add_provider(record)?;
+    self.save_providers(&key);
+    Ok(())
+  }
+
+  fn providers(&'a self, key: &Key) -> Vec<ProviderRecord> {
+    self.inner.providers(key)
+  }
+
+  fn provided(&'a self) -> Self::ProvidedIter {
+    self.inner.provided()
+  }
+
+  fn remove_provider(&'a mut self, k: &Key, p: &PeerId) {
+    self.inner.remove_provider(k, p);
+    self.save_providers(k);
+  }
+}
+
+/// Lists the files of a directory, leaving out those of interrupted writes.
+fn files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+  let mut files = Vec::new();
+  for entry in fs::read_dir(dir)? {
+    let path = entry?.path();
+    if path.extension().is_some_and(|ext| ext == "tmp") {
+      remove_file(&path);
+    } else if path.is_file() {
+      files.push(path);
+    }
+  }
+  Ok(files)
+}
+
+fn remove_file(path: &Path) {
+  match fs::remove_file(path) {
+    Ok(()) => {}
+    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+    Err(e) => println!("Cannot remove {}: {}", path.display(), e),
+  }
+}
+
+/// Converts a monotonic deadline to milliseconds since the Unix epoch.
+fn to_unix_millis(instant: Instant) -> u64 {
+  unix_millis(SystemTime::now() + instant.saturating_duration_since(Instant::now()))
+}
+
+/// Converts milliseconds since the Unix epoch back to a monotonic deadline, or
+/// `None` if it has passed.
+fn from_unix_millis(millis: u64) -> Option<Instant> {
+  let at = UNIX_EPOCH + Duration::from_millis(millis);
+  let left = at.duration_since(SystemTime::now()).ok()?;
+  Some(Instant::now() + left)
+}
+
+fn encode_record(record: &Record) -> Vec<u8> {
+  let stored = StoredRecord {
+    key: record.key.to_vec(),
+    value: record.value.clone(),
+    publisher: record.publisher.as_ref().map(|p| p.as_bytes().to_vec()),
+    expires: record.expires.map(to_unix_millis),
+  };
+  bincode::serialize(&stored).expect("Records can always be serialized")
+}
+
+/// Decodes a record, returning `None` if it is invalid or expired.
+fn decode_record(data: &[u8]) -> Option<Record> {
+  let stored: StoredRecord = bincode::deserialize(data).ok()?;
+  let expires = match stored.expires {
+    Some(millis) => Some(from_unix_millis(millis)?),
+    None => None,
+  };
+  let publisher = match stored.publisher {
+    Some(bytes) => Some(PeerId::from_bytes(bytes).ok()?),
+    None => None,
+  };
+  Some(Record {
+    key: Key::from(stored.key),
+    value: stored.value,
+    publisher,
+    expires,
+  })
+}
+
+fn encode_providers(key: &Key, records: &[ProviderRecord]) -> Vec<u8> {
+  let stored = StoredProviders {
+    key: key.to_vec(),
+    providers: records
+      .iter()
+      .map(|r| StoredProvider {
+        provider: r.provider.as_bytes().to_vec(),
+        expires: r.expires.map(to_unix_millis),
+      })
+      .collect(),
+  };
+  bincode::serialize(&stored).expect("Provider records can always be serialized")
+}
+
+/// Decodes the provider records of a key, leaving out those that are invalid or expired.
+fn decode_providers(data: &[u8]) -> Option<Vec<ProviderRecord>> {
+  let stored: StoredProviders = bincode::deserialize(data).ok()?;
+  let key = Key::from(stored.key);
+  let records = stored
+    .providers
+    .into_iter()
+    .filter_map(|p| {
+      let expires = match p.expires {
+        Some(millis) => Some(from_unix_millis(millis)?),
+        None => None,
+      };
+      Some(ProviderRecord {
+        key: key.clone(),
+        provider: PeerId::from_bytes(p.provider).ok()?,
+        expires,
+      })
+    })
+    .collect();
+  Some(records)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn sweep_rewrites_only_changed_providers() {
+    let home = std::env::temp_dir().join(format!("radiopeer-records-{}", std::process::id()));
+    let mut store = DiskStore::open(&home, PeerId::random()).unwrap();
+    let kept = Key::new(&"kept");
+    let expiring = Key::new(&"expiring");
+    store
+      .add_provider(ProviderRecord::new(kept.clone(), PeerId::random()))
+      .unwrap();
+    let mut record = ProviderRecord::new(expiring.clone(), PeerId::random());
+    store.add_provider(record.clone()).unwrap();
+    record.provider = PeerId::random();
+    record.expires = Some(Instant::now());
+    store.add_provider(record).unwrap();
+    // Removed behind the store's back: a sweep that rewrote it would bring it back.
+    fs::remove_file(store.providers_path(&kept)).unwrap();
+    store.remove_expired();
+    assert!(!store.providers_path(&kept).exists());
+    assert_eq!(store.providers(&expiring).len(), 1);
+    let data = fs::read(store.providers_path(&expiring)).unwrap();
+    assert_eq!(decode_providers(&data).unwrap().len(), 1);
+    let _ = fs::remove_dir_all(home);
+  }
+}
@@ -0,0 +1,303 @@
+use crate::store::write_atomic;
+use libp2p::PeerId;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Peers whose score falls below this are banned for `BAN_DURATION`.
+const BAN_THRESHOLD: f64 = -100.0;
+const BAN_DURATION: Duration = Duration::from_secs(60 * 60);
+/// Points a negative score recovers every minute.
+const RECOVERY_PER_MINUTE: f64 = 1.0;
+
+/// Something a peer did that costs it reputation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehaviour {
+  /// Sent a chunk or song index that does not match its hash.
+  BadBlock,
+  /// Sent a station manifest whose signature does not verify.
+  InvalidSignature,
+  /// Did not answer a request or a ping in time.
+  Timeout,
+  /// Sent something the protocol does not allow.
+  ProtocolError,
+}
+
+impl Misbehaviour {
+  fn penalty(self) -> f64 {
+    match self {
+      Misbehaviour::BadBlock => 40.0,
+      Misbehaviour::InvalidSignature => 50.0,
+      Misbehaviour::Timeout => 5.0,
+      Misbehaviour::ProtocolError => 10.0,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ban {
+  /// Banned from the console, until unbanned.
+  Manual,
+  /// Banned for misbehaving, until the given time.
+  Until(Instant),
+}
+
+struct Score {
+  value: f64,
+  updated: Instant,
+}
+
+impl Score {
+  /// Returns the score with the recovery since the last update applied.
+  fn current(&self) -> f64 {
+    let minutes = self.updated.elapsed().as_secs_f64() / 60.0;
+    (self.value + minutes * RECOVERY_PER_MINUTE).min(0.0)
+  }
+}
+
+/// Scores the peers on how they behave and bans the ones that misbehave.
+///
+/// Every peer starts at zero and loses points for each `Misbehaviour`, which it
+/// slowly recovers. Below `BAN_THRESHOLD` a peer is banned for `BAN_DURATION`
+/// and starts afresh afterwards. Scores and temporary bans are kept in memory;
+/// peers banned by hand are kept in the home directory, one peer id per line.
+pub struct Reputation {
+  path: PathBuf,
+  scores: HashMap<PeerId, Score>,
+  banned_until: HashMap<PeerId, Instant>,
+  manual_bans: HashSet<PeerId>,
+}
+
+impl Reputation {
+  /// Opens the ban list of the node in `home_path`.
+  ///
+  /// Lines that are not peer ids are skipped, so the file can be edited by hand.
+  pub fn open(home_path: &Path) -> Self {
+    let path = home_path.join("banned");
+    let manual_bans = match fs::read_to_string(&path) {
+      Ok(data) => data
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| match line.parse() {
+          Ok(peer_id) => Some(peer_id),
+          Err(_) => {
+            println!("Skipping {:?} in {}: not a peer id", line, path.display());
+            None
+          }
+        })
+        .collect(),
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+      Err(e) => {
+        println!("Cannot read the ban list {}: {}", path.display(), e);
+        HashSet::new()
+      }
+    };
+    Reputation {
+      path,
+      scores: HashMap::new(),
+      banned_until: HashMap::new(),
+      manual_bans,
+    }
+  }
+
+  /// Returns the score of `peer_id`: zero for a peer in good standing, negative otherwise.
+  pub fn score(&self, peer_id: &PeerId) -> f64 {
+    self.scores.get(peer_id).map_or(0.0, Score::current)
+  }
+
+  /// Lowers the score of `peer_id`, returning true if this got it banned.
+  pub fn report(&mut self, peer_id: &PeerId, misbehaviour: Misbehaviour) -> bool {
+    if self.is_banned(peer_id) {
+      return false;
+    }
+    let now = Instant::now();
+    let score = self.score(peer_id) - misbehaviour.penalty();
+    if score >= BAN_THRESHOLD {
+      self.scores.insert(
+        peer_id.clone(),
+        Score {
+          value: score,
+          updated: now,
+        },
+      );
+      return false;
+    }
+    self.scores.remove(peer_id);
+    self.banned_until.insert(peer_id.clone(), now + BAN_DURATION);
+    true
+  }
+
+  pub fn is_banned(&self, peer_id: &PeerId) -> bool {
+    self.ban(peer_id).is_some()
+  }
+
+  pub fn ban(&self, peer_id: &PeerId) -> Option<Ban> {
+    if self.manual_bans.contains(peer_id) {
+      return Some(Ban::Manual);
+    }
+    match self.banned_until.get(peer_id) {
+      Some(until) if *until > Instant::now() => Some(Ban::Until(*until)),
+      _ => None,
+    }
+  }
+
+  /// Returns the banned peers, by hand and for misbehaving.
+  pub fn banned(&self) -> Vec<(PeerId, Ban)> {
+    let manual = self.manual_bans.iter().map(|p| (p.clone(), Ban::Manual));
+    let now = Instant::now();
+    let temporary = self
+      .banned_until
+      .iter()
+      .filter(|(p, until)| **until > now && !self.manual_bans.contains(p))
+      .map(|(p, until)| (p.clone(), Ban::Until(*until)));
+    manual.chain(temporary).collect()
+  }
+
+  /// Bans `peer_id` until `unban` is called, and saves the ban list.
+  ///
+  /// Returns false if it was already banned by hand.
+  pub fn ban_manually(&mut self, peer_id: &PeerId) -> io::Result<bool> {
+    if !self.manual_bans.insert(peer_id.clone()) {
+      return Ok(false);
+    }
+    self.save()?;
+    Ok(true)
+  }
+
+  /// Lifts any ban on `peer_id` and clears its score, saving the ban list if it changed.
+  ///
+  /// Returns false if it was not banned.
+  pub fn unban(&mut self, peer_id: &PeerId) -> io::Result<bool> {
+    self.scores.remove(peer_id);
+    let temporary = self.banned_until.remove(peer_id).is_some();
+    if self.manual_bans.remove(peer_id) {
+      self.save()?;
+      return Ok(true);
+    }
+    Ok(temporary)
+  }
+
+  /// Forgets the bans that are over and the scores that recovered.
+  pub fn remove_expired(&mut self) {
+    let now = Instant::now();
+    self.banned_until.retain(|_, until| *until > now);
+    self.scores.retain(|_, score| score.current() < 0.0);
+  }
+
+  fn save(&self) -> io::Result<()> {
+    let mut peers: Vec<String> = self.manual_bans.iter().map(PeerId::to_base58).collect();
+    peers.sort();
+    let mut data = String::from("# Peers banned by hand, one peer id per line.\n");
+    for peer in peers {
+      data.push_str(&peer);
+      data.push('\n');
+    }
+    write_atomic(&self.path, data.as_bytes())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  struct Home(PathBuf);
+
+  impl Home {
+    fn new(name: &str) -> Self {
+      let path = std::env::temp_dir().join(format!("radiopeer-{}-{}", name, std::process::id()));
+      let _ = fs::remove_dir_all(&path);
+      fs::create_dir_all(&path).unwrap();
+      Home(path)
+    }
+  }
+
+  impl Drop for Home {
+    fn drop(&mut self) {
+      let _ = fs::remove_dir_all(&self.0);
+    }
+  }
+
+  fn ago(duration: Duration) -> Instant {
+    Instant::now().checked_sub(duration).unwrap()
+  }
+
+  #[test]
+  fn peers_are_banned_below_the_threshold() {
+    let home = Home::new("reputation-threshold");
+    let mut reputation = Reputation::open(&home.0);
+    let peer_id = PeerId::random();
+    assert!(!reputation.report(&peer_id, Misbehaviour::BadBlock));
+    assert!(!reputation.report(&peer_id, Misbehaviour::BadBlock));
+    assert!(reputation.score(&peer_id) < -79.9);
+    assert!(!reputation.is_banned(&peer_id));
+    assert!(reputation.report(&peer_id, Misbehaviour::BadBlock));
+    match reputation.ban(&peer_id) {
+      Some(Ban::Until(until)) => assert!(until > Instant::now()),
+      ban => panic!("{:?}", ban),
+    }
+    // Banned peers are not scored further, and start afresh.
+    assert!(!reputation.report(&peer_id, Misbehaviour::BadBlock));
+    assert_eq!(reputation.score(&peer_id), 0.0);
+    assert_eq!(reputation.banned().len(), 1);
+    assert!(reputation.unban(&peer_id).unwrap());
+    assert!(!reputation.is_banned(&peer_id));
+    assert!(!reputation.unban(&peer_id).unwrap());
+  }
+
+  #[test]
+  fn scores_and_bans_expire() {
+    let home = Home::new("reputation-recovery");
+    let mut reputation = Reputation::open(&home.0);
+    let recovering = PeerId::random();
+    reputation.report(&recovering, Misbehaviour::InvalidSignature);
+    reputation.scores.get_mut(&recovering).unwrap().updated = ago(Duration::from_secs(10 * 60));
+    let score = reputation.score(&recovering);
+    assert!((score + 40.0).abs() < 0.1, "{}", score);
+    let recovered = PeerId::random();
+    reputation.report(&recovered, Misbehaviour::Timeout);
+    reputation.scores.get_mut(&recovered).unwrap().updated = ago(Duration::from_secs(6 * 60));
+    assert_eq!(reputation.score(&recovered), 0.0);
+    let banned = PeerId::random();
+    for _ in 0..3 {
+      reputation.report(&banned, Misbehaviour::BadBlock);
+    }
+    reputation.banned_until.insert(banned.clone(), ago(Duration::from_secs(1)));
+    assert!(!reputation.is_banned(&banned));
+    reputation.remove_expired();
+    assert!(reputation.scores.contains_key(&recovering));
+    assert!(!reputation.scores.contains_key(&recovered));
+    assert!(reputation.banned_until.is_empty());
+    assert!(reputation.banned().is_empty());
+  }
+
+  #[test]
+  fn manual_bans_are_kept_across_restarts() {
+    let home = Home::new("reputation-manual");
+    let peer_id = PeerId::random();
+    let mut reputation = Reputation::open(&home.0);
+    assert!(reputation.ban_manually(&peer_id).unwrap());
+    assert!(!reputation.ban_manually(&peer_id).unwrap());
+    // Misbehaving does not replace a manual ban.
+    for _ in 0..3 {
+      reputation.report(&peer_id, Misbehaviour::BadBlock);
+    }
+    let mut reputation = Reputation::open(&home.0);
+    assert_eq!(reputation.ban(&peer_id), Some(Ban::Manual));
+    assert_eq!(reputation.banned(), vec![(peer_id.clone(), Ban::Manual)]);
+    assert!(reputation.unban(&peer_id).unwrap());
+    assert!(!Reputation::open(&home.0).is_banned(&peer_id));
+  }
+
+  #[test]
+  fn ban_list_lines_that_are_not_peers_are_skipped() {
+    let home = Home::new("reputation-file");
+    let peer_id = PeerId::random();
+    let data = format!("# banned\n\nnot a peer\n  {}  \n", peer_id.to_base58());
+    fs::write(home.0.join("banned"), data).unwrap();
+    let reputation = Reputation::open(&home.0);
+    assert_eq!(reputation.banned(), vec![(peer_id, Ban::Manual)]);
+  }
+}
@@ -0,0 +1,368 @@
+use crate::decode::PcmFormat;
+use crate::hls::HlsSink;
+use crate::icecast::IcecastSink;
+use crate::playback::unix_millis;
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use std::{error, fmt};
+
+/// How much audio goes in one WAV file before a new one is started.
+const WAV_ROLL: Duration = Duration::from_secs(60 * 60);
+/// How many WAV files are kept, the oldest are deleted first.
+const WAV_KEEP: usize = 24;
+const WAV_HEADER_SIZE: u32 = 44;
+
+/// Destination of the decoded audio of the station we listen to.
+///
+/// Samples are interleaved 16-bit PCM in `format`. Sinks are live outputs: when
+/// a consumer cannot keep up, dropping audio is better than stalling the node.
+pub trait AudioSink {
+  fn write(&mut self, format: PcmFormat, samples: &[i16]) -> io::Result<()>;
+
+  /// Called when a new track starts, for the sinks that can show what is playing.
+  fn set_title(&mut self, _title: &str) {}
+
+  /// Called before samples that do not simply follow the previous ones, e.g. on
+  /// tuning in or after a skip, with how long the station has been on air at
+  /// the next sample.
+  fn seek(&mut self, _station_time: Duration) {}
+
+  /// Called on every tick of the player, even when nothing is written.
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+fn to_le_bytes(samples: &[i16]) -> Vec<u8> {
+  samples.iter().flat_map(|s| s.to_le_bytes().to_vec()).collect()
+}
+
+/// Writes the audio to WAV files in a directory, starting a new file every hour
+/// or whenever the format changes.
+///
+/// The header is kept up to date after every write, so the current file can be
+/// read while it grows.
+pub struct WavFileSink {
+  dir: PathBuf,
+  current: Option<WavFile>,
+}
+
+struct WavFile {
+  file: File,
+  format: PcmFormat,
+  frames: u64,
+}
+
+impl WavFileSink {
+  pub fn new(dir: &Path) -> io::Result<Self> {
+    fs::create_dir_all(dir)?;
+    Ok(WavFileSink {
+      dir: dir.to_path_buf(),
+      current: None,
+    })
+  }
+
+  fn roll(&mut self, format: PcmFormat) -> io::Result<&mut WavFile> {
+    let name = format!("{}.wav", unix_millis(SystemTime::now()));
+    let mut file = File::create(self.dir.join(name))?;
+    file.write_all(&wav_header(format, 0))?;
+    self.current = Some(WavFile {
+      file,
+      format,
+      frames: 0,
+    });
+    self.prune()?;
+    Ok(self.current.as_mut().expect("a file was just created"))
+  }
+
+  /// Deletes the oldest files beyond `WAV_KEEP`.
+  fn prune(&self) -> io::Result<()> {
+    let mut files: Vec<PathBuf> = fs::read_dir(&self.dir)?
+      .filter_map(|entry| entry.ok().map(|e| e.path()))
+      .filter(|path| path.extension().is_some_and(|ext| ext == "wav"))
+      .collect();
+    // Names are creation times, so they sort by age.
+    files.sort();
+    let excess = files.len().saturating_sub(WAV_KEEP);
+    for path in &files[..excess] {
+      fs::remove_file(path)?;
+    }
+    Ok(())
+  }
+}
+
+impl AudioSink for WavFileSink {
+  fn write(&mut self, format: PcmFormat, samples: &[i16]) -> io::Result<()> {
+    let full = |wav: &WavFile| wav.format != format || wav.format.duration_of(wav.frames) >= WAV_ROLL;
+    let wav = match self.current.as_mut() {
+      Some(wav) if !full(wav) => wav,
+      _ => self.roll(format)?,
+    };
+    wav.file.seek(SeekFrom::End(0))?;
+    wav.file.write_all(&to_le_bytes(samples))?;
+    wav.frames += (samples.len() / usize::from(format.channels)) as u64;
+    let data_size = wav.frames * u64::from(format.channels) * 2;
+    wav.file.seek(SeekFrom::Start(0))?;
+    wav.file.write_all(&wav_header(format, data_size as u32))
+  }
+}
+
+/// Canonical 44 byte header of a 16-bit PCM WAV file with `data_size` bytes of samples.
+fn wav_header(format: PcmFormat, data_size: u32) -> Vec<u8> {
+  let block_align = format.channels * 2;
+  let byte_rate = format.sample_rate * u32::from(block_align);
+  let mut header = Vec::with_capacity(WAV_HEADER_SIZE as usize);
+  header.extend_from_slice(b"RIFF");
+  header.extend_from_slice(&(WAV_HEADER_SIZE - 8 + data_size).to_le_bytes());
+  header.extend_from_slice(b"WAVEfmt ");
+  header.extend_from_slice(&16u32.to_le_bytes());
+  // PCM
+  header.extend_from_slice(&1u16.to_le_bytes());
+  header.extend_from_slice(&format.channels.to_le_bytes());
+  header.extend_from_slice(&format.sample_rate.to_le_bytes());
+  header.extend_from_slice(&byte_rate.to_le_bytes());
+  header.extend_from_slice(&block_align.to_le_bytes());
+  header.extend_from_slice(&16u16.to_le_bytes());
+  header.extend_from_slice(b"data");
+  header.extend_from_slice(&data_size.to_le_bytes());
+  header
+}
+
+/// Writes raw interleaved signed 16-bit little-endian PCM to stdout.
+///
+/// The sink takes stdout over: opening it keeps a duplicate of stdout for the
+/// audio alone and points stdout at stderr, so whatever the node prints goes to
+/// stderr. It must be opened before anything is printed.
+#[cfg(unix)]
+pub struct StdoutSink {
+  out: File,
+  format: Option<PcmFormat>,
+}
+
+#[cfg(unix)]
+impl StdoutSink {
+  /// Takes stdout over for the audio; it can only be taken once.
+  pub fn take() -> io::Result<Self> {
+    use std::os::unix::io::FromRawFd;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    static TAKEN: AtomicBool = AtomicBool::new(false);
+    if TAKEN.swap(true, Ordering::SeqCst) {
+      return Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        "stdout is already an output",
+      ));
+    }
+    io::stdout().flush()?;
+    let fd = unsafe { libc::dup(libc::STDOUT_FILENO) };
+    if fd < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    let out = unsafe { File::from_raw_fd(fd) };
+    if unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(StdoutSink { out, format: None })
+  }
+}
+
+#[cfg(unix)]
+impl AudioSink for StdoutSink {
+  fn write(&mut self, format: PcmFormat, samples: &[i16]) -> io::Result<()> {
+    if self.format != Some(format) {
+      eprintln!(
+        "Writing s16le PCM at {} Hz, {} channels to stdout",
+        format.sample_rate, format.channels
+      );
+      self.format = Some(format);
+    }
+    self.out.write_all(&to_le_bytes(samples))
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.out.flush()
+  }
+}
+
+/// Writes raw interleaved signed 16-bit little-endian PCM to a named pipe.
+///
+/// The pipe is created if it does not exist. Audio is dropped while no reader
+/// has the pipe open, or when the reader falls behind and the pipe is full, so a
+/// slow consumer never blocks the node. Blocks are either dropped whole or
+/// written whole, so the reader never loses track of sample boundaries.
+#[cfg(unix)]
+pub struct FifoSink {
+  path: PathBuf,
+  pipe: Option<File>,
+  /// Tail of a block the pipe only took part of.
+  pending: Vec<u8>,
+}
+
+#[cfg(unix)]
+impl FifoSink {
+  pub fn new(path: &Path) -> io::Result<Self> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::FileTypeExt;
+    match fs::metadata(path) {
+      Ok(meta) if meta.file_type().is_fifo() => {}
+      Ok(_) => {
+        return Err(io::Error::new(
+          io::ErrorKind::AlreadyExists,
+          format!("{} exists and is not a FIFO", path.display()),
+        ))
+      }
+      Err(_) => {
+        let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())
+          .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        if unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) } != 0 {
+          return Err(io::Error::last_os_error());
+        }
+      }
+    }
+    Ok(FifoSink {
+      path: path.to_path_buf(),
+      pipe: None,
+      pending: Vec::new(),
+    })
+  }
+
+  /// Opens the write end without blocking; fails while nobody reads the pipe.
+  fn open(&self) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new()
+      .write(true)
+      .custom_flags(libc::O_NONBLOCK)
+      .open(&self.path)
+  }
+}
+
+#[cfg(unix)]
+impl AudioSink for FifoSink {
+  fn write(&mut self, _: PcmFormat, samples: &[i16]) -> io::Result<()> {
+    if self.pipe.is_none() {
+      match self.open() {
+        Ok(pipe) => self.pipe = Some(pipe),
+        // No reader yet.
+        Err(ref e) if e.raw_os_error() == Some(libc::ENXIO) => return Ok(()),
+        Err(e) => return Err(e),
+      }
+    }
+    let pipe = self.pipe.as_mut().expect("the pipe was just opened");
+    if self.pending.is_empty() {
+      self.pending = to_le_bytes(samples);
+    }
+    // Otherwise the new block is dropped, the pending tail goes first.
+    while !self.pending.is_empty() {
+      match pipe.write(&self.pending) {
+        Ok(n) => {
+          self.pending.drain(..n);
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => {
+          // The reader went away, reopen once there is a new one.
+          self.pipe = None;
+          self.pending.clear();
+          break;
+        }
+        Err(e) => return Err(e),
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Which sink to play to, as given on the command line.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SinkSpec {
+  /// `wav:<DIR>`
+  WavFile(PathBuf),
+  /// `stdout`
+  Stdout,
+  /// `fifo:<PATH>`
+  Fifo(PathBuf),
+  /// `http:<ADDR>`
+  Http(SocketAddr),
+  /// `hls:<DIR>`
+  Hls(PathBuf),
+}
+
+/// Settings of the sinks, besides where they write to.
+#[derive(Debug, Clone)]
+pub struct SinkOptions {
+  pub hls_segment: Duration,
+  pub hls_window: usize,
+}
+
+#[derive(Debug)]
+pub struct SinkSpecError(String);
+
+impl fmt::Display for SinkSpecError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "Invalid output {:?}, expected wav:<DIR>, stdout, fifo:<PATH>, http:<ADDR> or hls:<DIR>",
+      self.0
+    )
+  }
+}
+
+impl error::Error for SinkSpecError {}
+
+impl FromStr for SinkSpec {
+  type Err = SinkSpecError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (kind, path) = match s.find(':') {
+      Some(i) => (&s[..i], Some(&s[i + 1..])),
+      None => (s, None),
+    };
+    match (kind, path) {
+      ("stdout", None) => Ok(SinkSpec::Stdout),
+      ("wav", Some(dir)) if !dir.is_empty() => Ok(SinkSpec::WavFile(dir.into())),
+      ("fifo", Some(path)) if !path.is_empty() => Ok(SinkSpec::Fifo(path.into())),
+      ("hls", Some(dir)) if !dir.is_empty() => Ok(SinkSpec::Hls(dir.into())),
+      ("http", Some(addr)) => addr
+        .parse()
+        .map(SinkSpec::Http)
+        .map_err(|_| SinkSpecError(s.to_string())),
+      _ => Err(SinkSpecError(s.to_string())),
+    }
+  }
+}
+
+impl SinkSpec {
+  /// Opens the sink; `stdout` must be opened before anything is printed, see `StdoutSink`.
+  pub fn open(&self, options: &SinkOptions) -> io::Result<Box<dyn AudioSink + Send>> {
+    Ok(match self {
+      SinkSpec::WavFile(dir) => Box::new(WavFileSink::new(dir)?),
+      #[cfg(unix)]
+      SinkSpec::Stdout => Box::new(StdoutSink::take()?),
+      #[cfg(not(unix))]
+      SinkSpec::Stdout => {
+        return Err(io::Error::new(
+          io::ErrorKind::Other,
+          "stdout output is only supported on Unix",
+        ))
+      }
+      SinkSpec::Http(addr) => Box::new(IcecastSink::bind(*addr)?),
+      SinkSpec::Hls(dir) => Box::new(HlsSink::new(
+        dir,
+        options.hls_segment,
+        options.hls_window,
+      )?),
+      #[cfg(unix)]
+      SinkSpec::Fifo(path) => Box::new(FifoSink::new(path)?),
+      #[cfg(not(unix))]
+      SinkSpec::Fifo(_) => {
+        return Err(io::Error::new(
+          io::ErrorKind::Other,
+          "FIFO output is only supported on Unix",
+        ))
+      }
+    })
+  }
+}
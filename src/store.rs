@@ -0,0 +1,415 @@
+use crate::manifest::SongHash;
+use libp2p::multihash;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+
+/// Size of the chunks songs are split into. The last chunk of a song may be shorter.
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Multihash of the contents of a single chunk.
+pub type ChunkHash = Vec<u8>;
+
+#[derive(Debug)]
+pub enum StoreError {
+  Io(io::Error),
+  /// A chunk on disk or received from the network does not match its hash.
+  Corrupted(ChunkHash),
+  /// The chunks of a song do not hash to its Merkle root.
+  InvalidIndex(SongHash),
+  /// The song is not in the store.
+  UnknownSong(SongHash),
+  /// The chunk is not in the store.
+  MissingChunk(ChunkHash),
+  /// The index of a song could not be decoded.
+  Decode(bincode::Error),
+}
+
+impl fmt::Display for StoreError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      StoreError::Io(err) => write!(f, "{}", err),
+      StoreError::Corrupted(hash) => write!(f, "Chunk {} is corrupted", multihash::to_hex(hash)),
+      StoreError::InvalidIndex(root) => write!(
+        f,
+        "Chunks do not match the Merkle root {}",
+        multihash::to_hex(root)
+      ),
+      StoreError::UnknownSong(root) => write!(f, "Song {} is not stored", multihash::to_hex(root)),
+      StoreError::MissingChunk(hash) => {
+        write!(f, "Chunk {} is not stored", multihash::to_hex(hash))
+      }
+      StoreError::Decode(err) => write!(f, "Cannot decode song index: {}", err),
+    }
+  }
+}
+
+impl std::error::Error for StoreError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      StoreError::Io(err) => Some(err),
+      StoreError::Decode(err) => Some(err),
+      _ => None,
+    }
+  }
+}
+
+impl From<io::Error> for StoreError {
+  fn from(err: io::Error) -> StoreError {
+    StoreError::Io(err)
+  }
+}
+
+impl From<bincode::Error> for StoreError {
+  fn from(err: bincode::Error) -> StoreError {
+    StoreError::Decode(err)
+  }
+}
+
+pub(crate) fn sha256(data: &[u8]) -> Vec<u8> {
+  multihash::encode(multihash::Hash::SHA2256, data)
+    .expect("SHA2-256 is supported")
+    .into_bytes()
+}
+
+/// Hashes a chunk into its `ChunkHash`.
+pub fn chunk_hash(chunk: &[u8]) -> ChunkHash {
+  sha256(chunk)
+}
+
+/// Computes the Merkle root of a song from the hashes of its chunks, in order.
+///
+/// Leaves and interior nodes are hashed apart, as in RFC 6962: a leaf is the
+/// hash of `0x00` and the chunk hash, a parent the hash of `0x01` and its two
+/// children. Nodes are paired left to right and an odd node at the end of a
+/// level is promoted unchanged. The root is the hash of the number of chunks,
+/// as 8 big-endian bytes, followed by the root of the tree, so an index cannot
+/// pass for another of a different length.
+pub fn merkle_root(chunks: &[ChunkHash]) -> SongHash {
+  let mut level: Vec<Vec<u8>> = chunks
+    .iter()
+    .map(|chunk| {
+      let mut input = vec![0x00];
+      input.extend_from_slice(chunk);
+      sha256(&input)
+    })
+    .collect();
+  while level.len() > 1 {
+    level = level
+      .chunks(2)
+      .map(|pair| match pair {
+        [left, right] => {
+          let mut input = vec![0x01];
+          input.extend_from_slice(left);
+          input.extend_from_slice(right);
+          sha256(&input)
+        }
+        [single] => single.clone(),
+        _ => unreachable!(),
+      })
+      .collect();
+  }
+  let mut input = (chunks.len() as u64).to_be_bytes().to_vec();
+  if let Some(tree) = level.pop() {
+    input.extend_from_slice(&tree);
+  }
+  sha256(&input)
+}
+
+/// Content-addressed store for song chunks, kept under the radiopeer home directory.
+///
+/// Chunks live in `blocks/<hex hash>` and the ordered list of chunks of every
+/// song in `songs/<hex root>`.
+pub struct ChunkStore {
+  blocks_path: PathBuf,
+  songs_path: PathBuf,
+}
+
+impl ChunkStore {
+  pub fn open(home_path: &Path) -> Result<Self, StoreError> {
+    let blocks_path = home_path.join("blocks");
+    let songs_path = home_path.join("songs");
+    fs::create_dir_all(&blocks_path)?;
+    fs::create_dir_all(&songs_path)?;
+    Ok(ChunkStore {
+      blocks_path,
+      songs_path,
+    })
+  }
+
+  fn chunk_path(&self, hash: &[u8]) -> PathBuf {
+    self.blocks_path.join(multihash::to_hex(hash))
+  }
+
+  fn index_path(&self, root: &[u8]) -> PathBuf {
+    self.songs_path.join(multihash::to_hex(root))
+  }
+
+  /// Splits the file at `path` into chunks, stores them and returns the song hash.
+  pub fn add_file(&self, path: &Path) -> Result<SongHash, StoreError> {
+    let mut file = File::open(path)?;
+    let mut chunks = Vec::new();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+      let len = read_full(&mut file, &mut buffer)?;
+      if len == 0 {
+        break;
+      }
+      chunks.push(self.put_chunk_unchecked(&buffer[..len])?);
+      if len < CHUNK_SIZE {
+        break;
+      }
+    }
+    let root = merkle_root(&chunks);
+    self.write_index(&root, &chunks)?;
+    Ok(root)
+  }
+
+  /// Stores an in-memory song and returns its hash.
+  pub fn add_bytes(&self, data: &[u8]) -> Result<SongHash, StoreError> {
+    let chunks = data
+      .chunks(CHUNK_SIZE)
+      .map(|chunk| self.put_chunk_unchecked(chunk))
+      .collect::<Result<Vec<_>, _>>()?;
+    let root = merkle_root(&chunks);
+    self.write_index(&root, &chunks)?;
+    Ok(root)
+  }
+
+  fn put_chunk_unchecked(&self, chunk: &[u8]) -> Result<ChunkHash, StoreError> {
+    let hash = chunk_hash(chunk);
+    let path = self.chunk_path(&hash);
+    if !path.exists() {
+      write_atomic(&path, chunk)?;
+    }
+    Ok(hash)
+  }
+
+  fn write_index(&self, root: &[u8], chunks: &[ChunkHash]) -> Result<(), StoreError> {
+    write_atomic(&self.index_path(root), &bincode::serialize(chunks)?)?;
+    Ok(())
+  }
+
+  /// Stores a chunk received from elsewhere, rejecting it if it does not match `hash`.
+  pub fn put_chunk(&self, hash: &[u8], chunk: &[u8]) -> Result<(), StoreError> {
+    if chunk_hash(chunk) != hash {
+      return Err(StoreError::Corrupted(hash.to_vec()));
+    }
+    self.put_chunk_unchecked(chunk)?;
+    Ok(())
+  }
+
+  /// Records the chunk list of a song, rejecting it if it does not hash to `root`.
+  pub fn put_index(&self, root: &[u8], chunks: &[ChunkHash]) -> Result<(), StoreError> {
+    if merkle_root(chunks) != root {
+      return Err(StoreError::InvalidIndex(root.to_vec()));
+    }
+    self.write_index(root, chunks)
+  }
+
+  pub fn has_chunk(&self, hash: &[u8]) -> bool {
+    self.chunk_path(hash).exists()
+  }
+
+  /// Whether the index of the song is stored, though its chunks may still be missing.
+  pub fn has_song(&self, root: &[u8]) -> bool {
+    self.index_path(root).exists()
+  }
+
+  /// Whether the index and every chunk of the song are stored.
+  pub fn is_complete(&self, root: &[u8]) -> bool {
+    self
+      .chunks(root)
+      .is_ok_and(|chunks| chunks.iter().all(|c| self.has_chunk(c)))
+  }
+
+  /// Returns the ordered chunk hashes of a song.
+  pub fn chunks(&self, root: &[u8]) -> Result<Vec<ChunkHash>, StoreError> {
+    let bytes = match fs::read(self.index_path(root)) {
+      Ok(bytes) => bytes,
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+        return Err(StoreError::UnknownSong(root.to_vec()))
+      }
+      Err(e) => return Err(e.into()),
+    };
+    let chunks: Vec<ChunkHash> = bincode::deserialize(&bytes)?;
+    if merkle_root(&chunks) != root {
+      return Err(StoreError::InvalidIndex(root.to_vec()));
+    }
+    Ok(chunks)
+  }
+
+  /// Returns the songs whose chunks are all in the store.
+  pub fn complete_songs(&self) -> Result<Vec<SongHash>, StoreError> {
+    let mut songs = Vec::new();
+    for entry in fs::read_dir(&self.songs_path)? {
+      let name = entry?.file_name();
+      let root = match name.to_str().and_then(from_hex) {
+        Some(root) => root,
+        None => continue,
+      };
+      if self.is_complete(&root) {
+        songs.push(root);
+      }
+    }
+    Ok(songs)
+  }
+
+  /// Reads a chunk, verifying it against its hash.
+  ///
+  /// A corrupted chunk is removed from disk so it can be fetched again.
+  pub fn get_chunk(&self, hash: &[u8]) -> Result<Vec<u8>, StoreError> {
+    let path = self.chunk_path(hash);
+    let chunk = match fs::read(&path) {
+      Ok(chunk) => chunk,
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+        return Err(StoreError::MissingChunk(hash.to_vec()))
+      }
+      Err(e) => return Err(e.into()),
+    };
+    if chunk_hash(&chunk) != hash {
+      let _ = fs::remove_file(&path);
+      return Err(StoreError::Corrupted(hash.to_vec()));
+    }
+    Ok(chunk)
+  }
+
+  /// Reassembles a whole song, verifying every chunk on the way.
+  pub fn read_song(&self, root: &[u8]) -> Result<Vec<u8>, StoreError> {
+    let mut song = Vec::new();
+    for hash in self.chunks(root)? {
+      song.extend_from_slice(&self.get_chunk(&hash)?);
+    }
+    Ok(song)
+  }
+}
+
+/// Reads until `buffer` is full or the reader is exhausted.
+fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+  let mut len = 0;
+  while len < buffer.len() {
+    match reader.read(&mut buffer[len..]) {
+      Ok(0) => break,
+      Ok(n) => len += n,
+      Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+      Err(e) => return Err(e),
+    }
+  }
+  Ok(len)
+}
+
+/// Writes to a temporary file first so a crash never leaves a truncated file behind.
+pub(crate) fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+  let tmp_path = path.with_extension("tmp");
+  {
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+  }
+  fs::rename(tmp_path, path)
+}
+
+pub(crate) fn from_hex(s: &str) -> Option<Vec<u8>> {
+  if !s.len().is_multiple_of(2) {
+    return None;
+  }
+  (0..s.len())
+    .step_by(2)
+    .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn hashes(count: u8) -> Vec<ChunkHash> {
+    (0..count).map(|i| chunk_hash(&[i])).collect()
+  }
+
+  #[test]
+  fn empty_song_has_a_root() {
+    assert_eq!(merkle_root(&[]), sha256(&0u64.to_be_bytes()));
+    assert_ne!(merkle_root(&[]), merkle_root(&hashes(1)));
+  }
+
+  #[test]
+  fn single_chunk_is_not_its_own_root() {
+    let chunks = hashes(1);
+    assert_ne!(merkle_root(&chunks), chunks[0]);
+  }
+
+  #[test]
+  fn odd_counts_promote_the_last_node() {
+    let chunks = hashes(3);
+    let leaf = |hash: &[u8]| {
+      let mut input = vec![0x00];
+      input.extend_from_slice(hash);
+      sha256(&input)
+    };
+    let mut pair = vec![0x01];
+    pair.extend_from_slice(&leaf(&chunks[0]));
+    pair.extend_from_slice(&leaf(&chunks[1]));
+    let mut top = vec![0x01];
+    top.extend_from_slice(&sha256(&pair));
+    top.extend_from_slice(&leaf(&chunks[2]));
+    let mut root = 3u64.to_be_bytes().to_vec();
+    root.extend_from_slice(&sha256(&top));
+    assert_eq!(merkle_root(&chunks), sha256(&root));
+    assert_ne!(merkle_root(&chunks), merkle_root(&hashes(4)));
+    assert_ne!(merkle_root(&hashes(5)), merkle_root(&hashes(4)));
+  }
+
+  #[test]
+  fn order_matters() {
+    let mut chunks = hashes(2);
+    let root = merkle_root(&chunks);
+    chunks.reverse();
+    assert_ne!(merkle_root(&chunks), root);
+  }
+
+  #[test]
+  fn interior_node_cannot_pass_for_a_chunk() {
+    // A forged one-chunk index whose chunk is the concatenation of the two
+    // children of the root, as the tree used to be hashed.
+    let chunks = hashes(2);
+    let root = merkle_root(&chunks);
+    let mut forged = chunks[0].clone();
+    forged.extend_from_slice(&chunks[1]);
+    assert_ne!(merkle_root(&[chunk_hash(&forged)]), root);
+    let mut forged = vec![0x01];
+    forged.extend_from_slice(&chunks[0]);
+    forged.extend_from_slice(&chunks[1]);
+    assert_ne!(merkle_root(&[chunk_hash(&forged)]), root);
+    assert_ne!(merkle_root(std::slice::from_ref(&root)), root);
+  }
+
+  #[test]
+  fn put_index_rejects_a_forged_index() {
+    let home = std::env::temp_dir().join(format!("radiopeer-store-{}", std::process::id()));
+    let store = ChunkStore::open(&home).unwrap();
+    let data = vec![7u8; CHUNK_SIZE + 1];
+    let root = store.add_bytes(&data).unwrap();
+    let chunks = store.chunks(&root).unwrap();
+    let mut forged = chunks[0].clone();
+    forged.extend_from_slice(&chunks[1]);
+    assert!(store.put_index(&root, &[chunk_hash(&forged)]).is_err());
+    assert_eq!(store.chunks(&root).unwrap(), chunks);
+    assert_eq!(store.read_song(&root).unwrap(), data);
+    let _ = fs::remove_dir_all(home);
+  }
+
+  #[test]
+  fn song_with_missing_chunks_is_not_complete() {
+    let home = std::env::temp_dir().join(format!("radiopeer-complete-{}", std::process::id()));
+    let store = ChunkStore::open(&home).unwrap();
+    let root = store.add_bytes(&vec![3u8; 2 * CHUNK_SIZE]).unwrap();
+    assert!(store.is_complete(&root));
+    let chunks = store.chunks(&root).unwrap();
+    fs::remove_file(store.chunk_path(&chunks[1])).unwrap();
+    assert!(store.has_song(&root));
+    assert!(!store.is_complete(&root));
+    let _ = fs::remove_dir_all(home);
+  }
+}
@@ -0,0 +1,262 @@
+use crate::playback::unix_millis;
+use crate::store::write_atomic;
+use libp2p::core::{identity, PeerId, PublicKey};
+use libp2p::kad::record;
+use libp2p::multihash;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use std::{error, fmt, fs, io};
+
+/// The statement that the key of `old` was replaced by the key of `new`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Successor {
+  pub old: Vec<u8>,
+  pub new: Vec<u8>,
+  /// When the key was rotated, in milliseconds since the Unix epoch.
+  pub rotated_at: u64,
+}
+
+/// A `Successor` as it is stored in the DHT, signed by both keys: the old key
+/// hands its place over and the new key proves it is held by the same admin.
+///
+/// Like `SignedManifest`, the signatures cover the exact bytes in `payload`,
+/// after the `CONTEXT` tag so they cannot be taken for the signature of a
+/// manifest or a broadcast made with the same key.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedSuccessor {
+  /// Bincode encoding of the `Successor`.
+  pub payload: Vec<u8>,
+  /// Protobuf encodings of the public keys.
+  pub old_key: Vec<u8>,
+  pub new_key: Vec<u8>,
+  pub old_signature: Vec<u8>,
+  pub new_signature: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum SuccessorError {
+  Io(io::Error),
+  Decode(bincode::Error),
+  /// A public key could not be decoded or does not match the peer id it stands for.
+  InvalidKey,
+  InvalidSignature,
+  Signing(identity::error::SigningError),
+}
+
+impl fmt::Display for SuccessorError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      SuccessorError::Io(err) => write!(f, "Cannot access successor record: {}", err),
+      SuccessorError::Decode(err) => write!(f, "Cannot decode successor record: {}", err),
+      SuccessorError::InvalidKey => write!(f, "Successor record key is invalid"),
+      SuccessorError::InvalidSignature => write!(f, "Successor record signature does not verify"),
+      SuccessorError::Signing(err) => write!(f, "Cannot sign successor record: {}", err),
+    }
+  }
+}
+
+impl error::Error for SuccessorError {
+  fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+    match self {
+      SuccessorError::Io(err) => Some(err),
+      SuccessorError::Decode(err) => Some(err),
+      SuccessorError::Signing(err) => Some(err),
+      _ => None,
+    }
+  }
+}
+
+impl From<io::Error> for SuccessorError {
+  fn from(err: io::Error) -> Self {
+    SuccessorError::Io(err)
+  }
+}
+
+impl From<bincode::Error> for SuccessorError {
+  fn from(err: bincode::Error) -> Self {
+    SuccessorError::Decode(err)
+  }
+}
+
+impl From<identity::error::SigningError> for SuccessorError {
+  fn from(err: identity::error::SigningError) -> Self {
+    SuccessorError::Signing(err)
+  }
+}
+
+/// Prefix of the DHT keys and the signed bytes of successor records.
+const CONTEXT: &[u8] = b"/radiopeer/successor/";
+
+/// Returns the DHT key under which the successor of `old` is published.
+pub fn successor_key(old: &PeerId) -> record::Key {
+  let mut input = CONTEXT.to_vec();
+  input.extend_from_slice(old.as_bytes());
+  let hash = multihash::encode(multihash::Hash::SHA2256, &input).expect("SHA2-256 is supported");
+  record::Key::from(hash)
+}
+
+impl SignedSuccessor {
+  /// Signs the rotation from the `old` key to the `new` one.
+  pub fn new(old: &identity::Keypair, new: &identity::Keypair) -> Result<Self, SuccessorError> {
+    let successor = Successor {
+      old: old.public().into_peer_id().into_bytes(),
+      new: new.public().into_peer_id().into_bytes(),
+      rotated_at: unix_millis(SystemTime::now()),
+    };
+    let payload = bincode::serialize(&successor)?;
+    Ok(SignedSuccessor {
+      old_signature: old.sign(&signed_bytes(&payload))?,
+      new_signature: new.sign(&signed_bytes(&payload))?,
+      payload,
+      old_key: old.public().into_protobuf_encoding(),
+      new_key: new.public().into_protobuf_encoding(),
+    })
+  }
+
+  pub fn from_bytes(bytes: &[u8]) -> Result<Self, SuccessorError> {
+    Ok(bincode::deserialize(bytes)?)
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    bincode::serialize(self).expect("Serializing a SignedSuccessor cannot fail")
+  }
+
+  /// Checks both signatures, returning the old and the new peer id.
+  pub fn verify(&self) -> Result<(PeerId, PeerId), SuccessorError> {
+    let successor: Successor = bincode::deserialize(&self.payload)?;
+    let signed = signed_bytes(&self.payload);
+    let old = verify_key(&self.old_key, &successor.old, &signed, &self.old_signature)?;
+    let new = verify_key(&self.new_key, &successor.new, &signed, &self.new_signature)?;
+    Ok((old, new))
+  }
+
+  /// Keeps the record in `home_path`, so the node can publish it again.
+  pub fn save(&self, home_path: &Path) -> Result<(), SuccessorError> {
+    let (old, _) = self.verify()?;
+    let dir = successors_path(home_path);
+    fs::create_dir_all(&dir)?;
+    write_atomic(&dir.join(old.to_base58()), &self.to_bytes())?;
+    Ok(())
+  }
+}
+
+/// Returns the bytes the keys sign: `payload` after the `CONTEXT` tag.
+fn signed_bytes(payload: &[u8]) -> Vec<u8> {
+  let mut bytes = CONTEXT.to_vec();
+  bytes.extend_from_slice(payload);
+  bytes
+}
+
+fn verify_key(
+  key: &[u8],
+  peer_id: &[u8],
+  payload: &[u8],
+  signature: &[u8],
+) -> Result<PeerId, SuccessorError> {
+  let key = PublicKey::from_protobuf_encoding(key).map_err(|_| SuccessorError::InvalidKey)?;
+  if !key.verify(payload, signature) {
+    return Err(SuccessorError::InvalidSignature);
+  }
+  let key_id = key.into_peer_id();
+  if key_id.as_bytes() != peer_id {
+    return Err(SuccessorError::InvalidKey);
+  }
+  Ok(key_id)
+}
+
+fn successors_path(home_path: &Path) -> PathBuf {
+  home_path.join("successors")
+}
+
+/// Loads the successor records of the keys this node rotated, skipping the invalid ones.
+pub fn load_all(home_path: &Path) -> io::Result<Vec<SignedSuccessor>> {
+  let dir = successors_path(home_path);
+  let entries = match fs::read_dir(&dir) {
+    Ok(entries) => entries,
+    Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+    Err(e) => return Err(e),
+  };
+  let mut successors = Vec::new();
+  for entry in entries {
+    let path = entry?.path();
+    let signed = fs::read(&path)
+      .map_err(SuccessorError::from)
+      .and_then(|data| SignedSuccessor::from_bytes(&data))
+      .and_then(|signed| signed.verify().map(|_| signed));
+    match signed {
+      Ok(signed) => successors.push(signed),
+      Err(e) => println!("Skipping successor record {}: {}", path.display(), e),
+    }
+  }
+  Ok(successors)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use identity::Keypair;
+
+  #[test]
+  fn valid_rotation_verifies() {
+    let old = Keypair::generate_ed25519();
+    let new = Keypair::generate_secp256k1();
+    let signed = SignedSuccessor::new(&old, &new).unwrap();
+    let signed = SignedSuccessor::from_bytes(&signed.to_bytes()).unwrap();
+    let (old_id, new_id) = signed.verify().unwrap();
+    assert_eq!(old_id, old.public().into_peer_id());
+    assert_eq!(new_id, new.public().into_peer_id());
+    // Without the context tag, the payload alone was not signed.
+    let key = old.public();
+    assert!(!key.verify(&signed.payload, &signed.old_signature));
+  }
+
+  #[test]
+  fn keys_must_match_the_peer_ids() {
+    let old = Keypair::generate_ed25519();
+    let new = Keypair::generate_ed25519();
+    let signed = SignedSuccessor::new(&old, &new).unwrap();
+    let mut swapped = signed.clone();
+    std::mem::swap(&mut swapped.old_key, &mut swapped.new_key);
+    std::mem::swap(&mut swapped.old_signature, &mut swapped.new_signature);
+    assert!(matches!(swapped.verify(), Err(SuccessorError::InvalidKey)));
+    let foreign = Keypair::generate_ed25519();
+    let mut forged = signed.clone();
+    forged.new_key = foreign.public().into_protobuf_encoding();
+    forged.new_signature = foreign.sign(&signed_bytes(&signed.payload)).unwrap();
+    assert!(matches!(forged.verify(), Err(SuccessorError::InvalidKey)));
+    let mut garbage = signed;
+    garbage.old_key = vec![1, 2, 3];
+    assert!(matches!(garbage.verify(), Err(SuccessorError::InvalidKey)));
+  }
+
+  #[test]
+  fn corrupted_signature_is_rejected() {
+    let old = Keypair::generate_ed25519();
+    let new = Keypair::generate_ed25519();
+    let mut signed = SignedSuccessor::new(&old, &new).unwrap();
+    signed.old_signature[0] ^= 1;
+    assert!(matches!(signed.verify(), Err(SuccessorError::InvalidSignature)));
+  }
+
+  #[test]
+  fn load_all_skips_invalid_records() {
+    let home = std::env::temp_dir().join(format!("radiopeer-successors-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&home);
+    assert!(load_all(&home).unwrap().is_empty());
+    let old = Keypair::generate_ed25519();
+    let new = Keypair::generate_ed25519();
+    let signed = SignedSuccessor::new(&old, &new).unwrap();
+    signed.save(&home).unwrap();
+    let mut corrupted = signed.clone();
+    corrupted.new_signature[0] ^= 1;
+    assert!(corrupted.save(&home).is_err());
+    let dir = successors_path(&home);
+    fs::write(dir.join("corrupted"), corrupted.to_bytes()).unwrap();
+    fs::write(dir.join("garbage"), b"not a record").unwrap();
+    let loaded = load_all(&home).unwrap();
+    assert_eq!(loaded.len(), 1);
+    assert_eq!(loaded[0].payload, signed.payload);
+    let _ = fs::remove_dir_all(home);
+  }
+}
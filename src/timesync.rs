@@ -0,0 +1,434 @@
+use futures::prelude::*;
+use futures03::{compat::Compat, TryFutureExt as _};
+use futures_timer::Delay;
+use libp2p::core::{upgrade, ConnectedPoint, InboundUpgrade, OutboundUpgrade, PeerId, UpgradeInfo};
+use libp2p::swarm::{
+  NetworkBehaviour, NetworkBehaviourAction, OneShotHandler, PollParameters, ProtocolsHandler,
+};
+use libp2p::tokio_io::{AsyncRead, AsyncWrite};
+use libp2p::Multiaddr;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::marker::PhantomData;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{error, fmt, io, iter};
+
+const PROTOCOL_NAME: &[u8] = b"/radiopeer/timesync/1.0.0";
+const MAX_MESSAGE_SIZE: usize = 64;
+/// How often every connected peer is sampled.
+const SYNC_INTERVAL: Duration = Duration::from_secs(10);
+/// Samples kept per peer for the clock filter.
+const MAX_SAMPLES: usize = 8;
+/// Samples whose round trip is longer than this are too noisy to use.
+const MAX_RTT: Duration = Duration::from_secs(2);
+
+/// A message of the time synchronization protocol. Timestamps are in
+/// microseconds since the Unix epoch, as read from the sender's local clock.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum TimeSyncMessage {
+  /// Asks the remote for its time; `origin` is when the request was sent.
+  Request { origin: i64 },
+  /// Answers a request: `received` and `transmitted` are when the request
+  /// arrived and when the response left, on the responder's clock.
+  Response {
+    origin: i64,
+    received: i64,
+    transmitted: i64,
+  },
+}
+
+/// Implementation of the inbound side of the time synchronization protocol.
+#[derive(Debug, Clone, Default)]
+pub struct TimeSyncConfig {}
+
+impl UpgradeInfo for TimeSyncConfig {
+  type Info = &'static [u8];
+  type InfoIter = iter::Once<Self::Info>;
+
+  fn protocol_info(&self) -> Self::InfoIter {
+    iter::once(PROTOCOL_NAME)
+  }
+}
+
+impl<TSocket> InboundUpgrade<TSocket> for TimeSyncConfig
+where
+  TSocket: AsyncRead + AsyncWrite,
+{
+  type Output = TimeSyncMessage;
+  type Error = TimeSyncDecodeError;
+  type Future = upgrade::ReadOneThen<
+    upgrade::Negotiated<TSocket>,
+    (),
+    fn(Vec<u8>, ()) -> Result<TimeSyncMessage, TimeSyncDecodeError>,
+  >;
+
+  fn upgrade_inbound(self, socket: upgrade::Negotiated<TSocket>, _: Self::Info) -> Self::Future {
+    upgrade::read_one_then(socket, MAX_MESSAGE_SIZE, (), |packet, ()| {
+      Ok(bincode::deserialize(&packet)?)
+    })
+  }
+}
+
+impl UpgradeInfo for TimeSyncMessage {
+  type Info = &'static [u8];
+  type InfoIter = iter::Once<Self::Info>;
+
+  fn protocol_info(&self) -> Self::InfoIter {
+    iter::once(PROTOCOL_NAME)
+  }
+}
+
+impl<TSocket> OutboundUpgrade<TSocket> for TimeSyncMessage
+where
+  TSocket: AsyncWrite + AsyncRead,
+{
+  type Output = ();
+  type Error = io::Error;
+  type Future = upgrade::WriteOne<upgrade::Negotiated<TSocket>>;
+
+  fn upgrade_outbound(self, socket: upgrade::Negotiated<TSocket>, _: Self::Info) -> Self::Future {
+    let bytes = bincode::serialize(&self).expect("Serializing a TimeSyncMessage cannot fail");
+    upgrade::write_one(socket, bytes)
+  }
+}
+
+#[derive(Debug)]
+pub enum TimeSyncDecodeError {
+  /// Error when reading the packet from the socket.
+  ReadError(upgrade::ReadOneError),
+  /// Error when decoding the message.
+  Decode(bincode::Error),
+}
+
+impl From<upgrade::ReadOneError> for TimeSyncDecodeError {
+  fn from(err: upgrade::ReadOneError) -> Self {
+    TimeSyncDecodeError::ReadError(err)
+  }
+}
+
+impl From<bincode::Error> for TimeSyncDecodeError {
+  fn from(err: bincode::Error) -> Self {
+    TimeSyncDecodeError::Decode(err)
+  }
+}
+
+impl fmt::Display for TimeSyncDecodeError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TimeSyncDecodeError::ReadError(err) => write!(f, "Error while reading from socket: {}", err),
+      TimeSyncDecodeError::Decode(err) => write!(f, "Error while decoding message: {}", err),
+    }
+  }
+}
+
+impl error::Error for TimeSyncDecodeError {
+  fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+    match self {
+      TimeSyncDecodeError::ReadError(err) => Some(err),
+      TimeSyncDecodeError::Decode(err) => Some(err),
+    }
+  }
+}
+
+/// Transmission between the `OneShotHandler` and the `TimeSync` behaviour.
+pub enum InnerMessage {
+  /// We received a message from a remote.
+  Rx(TimeSyncMessage),
+  /// We successfully sent a message.
+  Sent,
+}
+
+impl From<TimeSyncMessage> for InnerMessage {
+  fn from(message: TimeSyncMessage) -> InnerMessage {
+    InnerMessage::Rx(message)
+  }
+}
+
+impl From<()> for InnerMessage {
+  fn from(_: ()) -> InnerMessage {
+    InnerMessage::Sent
+  }
+}
+
+/// Estimate of the clock of a remote peer relative to ours.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockEstimate {
+  /// Microseconds to add to our clock to get the remote's clock.
+  pub offset_micros: i64,
+  /// Round-trip time of the sample the offset was taken from.
+  pub rtt: Duration,
+}
+
+/// Event that can be emitted by the time synchronization behaviour.
+#[derive(Debug)]
+pub enum TimeSyncEvent {
+  /// The filtered clock estimate of a peer was updated.
+  Estimate { peer_id: PeerId, estimate: ClockEstimate },
+}
+
+/// Computes a sample from the timestamps of an exchange, `now` being when the
+/// response arrived. Returns `None` if the timestamps are inconsistent, overflow,
+/// or the round trip is negative or too long to be useful.
+fn sample(origin: i64, received: i64, transmitted: i64, now: i64) -> Option<ClockEstimate> {
+  let processing = transmitted.checked_sub(received)?;
+  let rtt = now.checked_sub(origin)?.checked_sub(processing)?;
+  if processing < 0 || rtt < 0 || rtt as u128 > MAX_RTT.as_micros() {
+    return None;
+  }
+  let offset_micros = received
+    .checked_sub(origin)?
+    .checked_add(transmitted.checked_sub(now)?)?
+    / 2;
+  Some(ClockEstimate {
+    offset_micros,
+    rtt: Duration::from_micros(rtt as u64),
+  })
+}
+
+fn now_micros() -> i64 {
+  match SystemTime::now().duration_since(UNIX_EPOCH) {
+    Ok(d) => d.as_micros() as i64,
+    Err(e) => -(e.duration().as_micros() as i64),
+  }
+}
+
+/// Network behaviour that estimates the clock offset to every connected peer.
+///
+/// Each exchange yields the four NTP timestamps, from which we get one sample of
+/// offset and round-trip time. As in NTP's clock filter, the estimate for a peer
+/// is the offset of the sample with the lowest round trip among the last few,
+/// since that is the one least skewed by queuing delays.
+pub struct TimeSync<TSubstream> {
+  events: VecDeque<NetworkBehaviourAction<TimeSyncMessage, TimeSyncEvent>>,
+  connected_peers: Vec<PeerId>,
+  samples: HashMap<PeerId, VecDeque<ClockEstimate>>,
+  estimates: HashMap<PeerId, ClockEstimate>,
+  /// Origin of the request we wait for an answer to, by peer; responses that
+  /// do not echo it are dropped.
+  pending: HashMap<PeerId, i64>,
+  next_sync: Compat<Delay>,
+  marker: PhantomData<TSubstream>,
+}
+
+impl<TSubstream> Default for TimeSync<TSubstream> {
+  fn default() -> Self {
+    TimeSync::new()
+  }
+}
+
+impl<TSubstream> TimeSync<TSubstream> {
+  pub fn new() -> Self {
+    TimeSync {
+      events: VecDeque::new(),
+      connected_peers: Vec::new(),
+      samples: HashMap::new(),
+      estimates: HashMap::new(),
+      pending: HashMap::new(),
+      next_sync: Delay::new(Duration::from_secs(1)).compat(),
+      marker: PhantomData,
+    }
+  }
+
+  /// Returns the current clock estimate for `peer_id`.
+  pub fn estimate(&self, peer_id: &PeerId) -> Option<&ClockEstimate> {
+    self.estimates.get(peer_id)
+  }
+
+  /// Returns the offset of the network clock from our local clock, in microseconds.
+  ///
+  /// This is the median of the offsets of the peers we have an estimate for, so a
+  /// few peers with badly set clocks do not drag everyone along.
+  pub fn network_offset_micros(&self) -> i64 {
+    let mut offsets: Vec<i64> = self.estimates.values().map(|e| e.offset_micros).collect();
+    if offsets.is_empty() {
+      return 0;
+    }
+    // Count ourselves in, with a zero offset.
+    offsets.push(0);
+    offsets.sort();
+    offsets[offsets.len() / 2]
+  }
+
+  /// Returns the time the network agrees on, to be used in place of the local clock.
+  pub fn network_time(&self) -> SystemTime {
+    let offset = self.network_offset_micros();
+    let now = SystemTime::now();
+    if offset >= 0 {
+      now + Duration::from_micros(offset as u64)
+    } else {
+      now - Duration::from_micros(offset.unsigned_abs())
+    }
+  }
+
+  /// Sends a request to `peer_id`, replacing the one we were waiting on, if any.
+  fn request(&mut self, peer_id: PeerId) {
+    let origin = now_micros();
+    self.pending.insert(peer_id.clone(), origin);
+    self.events.push_back(NetworkBehaviourAction::SendEvent {
+      peer_id,
+      event: TimeSyncMessage::Request { origin },
+    });
+  }
+
+  fn add_sample(&mut self, peer_id: PeerId, sample: ClockEstimate) {
+    let samples = self.samples.entry(peer_id.clone()).or_default();
+    samples.push_back(sample);
+    if samples.len() > MAX_SAMPLES {
+      samples.pop_front();
+    }
+    let best = *samples
+      .iter()
+      .min_by_key(|s| s.rtt)
+      .expect("a sample was just pushed");
+    self.estimates.insert(peer_id.clone(), best);
+    self
+      .events
+      .push_back(NetworkBehaviourAction::GenerateEvent(TimeSyncEvent::Estimate {
+        peer_id,
+        estimate: best,
+      }));
+  }
+}
+
+impl<TSubstream> NetworkBehaviour for TimeSync<TSubstream>
+where
+  TSubstream: AsyncRead + AsyncWrite,
+{
+  type ProtocolsHandler = OneShotHandler<TSubstream, TimeSyncConfig, TimeSyncMessage, InnerMessage>;
+  type OutEvent = TimeSyncEvent;
+
+  fn new_handler(&mut self) -> Self::ProtocolsHandler {
+    Default::default()
+  }
+
+  fn addresses_of_peer(&mut self, _: &PeerId) -> Vec<Multiaddr> {
+    Vec::new()
+  }
+
+  fn inject_connected(&mut self, peer_id: PeerId, _: ConnectedPoint) {
+    self.connected_peers.push(peer_id.clone());
+    self.request(peer_id);
+  }
+
+  fn inject_disconnected(&mut self, peer_id: &PeerId, _: ConnectedPoint) {
+    self.connected_peers.retain(|p| p != peer_id);
+    self.samples.remove(peer_id);
+    self.estimates.remove(peer_id);
+    self.pending.remove(peer_id);
+  }
+
+  fn inject_node_event(&mut self, peer_id: PeerId, event: InnerMessage) {
+    let now = now_micros();
+    match event {
+      InnerMessage::Rx(TimeSyncMessage::Request { origin }) => {
+        self.events.push_back(NetworkBehaviourAction::SendEvent {
+          peer_id,
+          event: TimeSyncMessage::Response {
+            origin,
+            received: now,
+            transmitted: now_micros(),
+          },
+        });
+      }
+      InnerMessage::Rx(TimeSyncMessage::Response {
+        origin,
+        received,
+        transmitted,
+      }) => {
+        // Only answers to our last request count, or any peer could set our clock.
+        if self.pending.get(&peer_id) != Some(&origin) {
+          return;
+        }
+        self.pending.remove(&peer_id);
+        if let Some(estimate) = sample(origin, received, transmitted, now) {
+          self.add_sample(peer_id, estimate);
+        }
+      }
+      InnerMessage::Sent => {}
+    }
+  }
+
+  fn poll(
+    &mut self,
+    _: &mut impl PollParameters,
+  ) -> Async<NetworkBehaviourAction<<Self::ProtocolsHandler as ProtocolsHandler>::InEvent, Self::OutEvent>>
+  {
+    loop {
+      match self.next_sync.poll() {
+        Ok(Async::NotReady) => break,
+        Ok(Async::Ready(_)) => {
+          for peer_id in self.connected_peers.clone() {
+            self.request(peer_id);
+          }
+          self.next_sync = Delay::new(SYNC_INTERVAL).compat();
+        }
+        Err(err) => {
+          println!("Time sync timer errored: {:?}", err);
+          break;
+        }
+      }
+    }
+    if let Some(event) = self.events.pop_front() {
+      return Async::Ready(event);
+    }
+    Async::NotReady
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn sample_measures_offset_and_round_trip() {
+    // The remote clock is 1000us ahead, 100us each way and 50us to answer.
+    let estimate = sample(10_000, 11_100, 11_150, 10_250).unwrap();
+    assert_eq!(estimate.offset_micros, 1000);
+    assert_eq!(estimate.rtt, Duration::from_micros(200));
+  }
+
+  #[test]
+  fn sample_rejects_negative_round_trip() {
+    assert!(sample(10_000, 11_000, 12_000, 10_500).is_none());
+    assert!(sample(10_000, 11_100, 11_000, 10_250).is_none());
+  }
+
+  #[test]
+  fn sample_rejects_overflow() {
+    assert!(sample(i64::MIN, 0, 0, i64::MAX).is_none());
+    assert!(sample(0, i64::MAX, i64::MAX, 0).is_none());
+    assert!(sample(0, i64::MIN, 0, 10).is_none());
+    assert!(sample(10, i64::MAX, i64::MAX, 20).is_none());
+  }
+
+  #[test]
+  fn sample_rejects_long_round_trip() {
+    let rtt = MAX_RTT.as_micros() as i64 + 1;
+    assert!(sample(0, 0, 0, rtt).is_none());
+  }
+
+  #[test]
+  fn unsolicited_responses_are_dropped() {
+    let mut timesync = TimeSync::<io::Cursor<Vec<u8>>>::new();
+    let peer_id = PeerId::random();
+    let response = |origin| {
+      InnerMessage::Rx(TimeSyncMessage::Response {
+        origin,
+        received: origin,
+        transmitted: origin,
+      })
+    };
+    timesync.inject_node_event(peer_id.clone(), response(now_micros()));
+    assert!(timesync.estimate(&peer_id).is_none());
+    timesync.request(peer_id.clone());
+    let origin = timesync.pending[&peer_id];
+    timesync.inject_node_event(peer_id.clone(), response(origin - 1));
+    assert!(timesync.estimate(&peer_id).is_none());
+    timesync.inject_node_event(peer_id.clone(), response(origin));
+    assert!(timesync.estimate(&peer_id).is_some());
+    // A replayed answer is dropped too.
+    timesync.estimates.clear();
+    timesync.inject_node_event(peer_id.clone(), response(origin));
+    assert!(timesync.estimate(&peer_id).is_none());
+  }
+}
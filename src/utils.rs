@@ -1,8 +1,12 @@
-use libp2p::identity;
-use libp2p::{multiaddr, Multiaddr, PeerId};
-use std::fs::File;
+use crate::keystore::{self, KeyFile, KeyType, PASSPHRASE_ENV};
+use crate::manifest::Manifest;
+use crate::store::{from_hex, write_atomic};
+use crate::successor::SignedSuccessor;
+use libp2p::{identity, multihash, PeerId};
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::{env, fs, io};
 
 pub fn create_home_dir(path: Option<&str>) -> PathBuf {
   match path {
@@ -23,23 +27,208 @@ pub fn create_home_dir(path: Option<&str>) -> PathBuf {
   }
 }
 
-pub fn create_keys(home_path: &PathBuf) -> Result<identity::Keypair, Box<dyn std::error::Error>> {
-  let mut key_path = PathBuf::from(home_path);
-  key_path.push(".peer_key");
-  let keypair;
-  if key_path.exists() {
-    let mut f = File::open(key_path)?;
-    let mut key_buffer = Vec::new();
-    f.read_to_end(&mut key_buffer)?;
-    let secret = identity::secp256k1::SecretKey::from_bytes(key_buffer)?;
-    keypair = identity::secp256k1::Keypair::from(secret);
+pub fn key_path(home_path: &Path) -> PathBuf {
+  home_path.join(".peer_key")
+}
+
+/// File holding the id of the station of this node, once its key was rotated.
+pub fn station_id_path(home_path: &Path) -> PathBuf {
+  home_path.join("station.id")
+}
+
+/// Loads the key of the node, or generates a key of `key_type` if there is none yet.
+///
+/// An encrypted key is decrypted with the passphrase from `PASSPHRASE_ENV` or
+/// the terminal. New keys are encrypted if `PASSPHRASE_ENV` is set. A key in the
+/// raw format of earlier versions is rewritten as DER.
+pub fn create_keys(
+  home_path: &Path,
+  key_type: KeyType,
+) -> Result<identity::Keypair, Box<dyn std::error::Error>> {
+  load_keys(home_path, key_type).map(|(keypair, _)| keypair)
+}
+
+/// Like `create_keys`, also returning the passphrase the key file is encrypted with.
+fn load_keys(
+  home_path: &Path,
+  key_type: KeyType,
+) -> Result<(identity::Keypair, Option<String>), Box<dyn std::error::Error>> {
+  let key_path = key_path(home_path);
+  if !key_path.exists() {
+    let keypair = key_type.generate();
+    let passphrase = env_passphrase();
+    write_key(&key_path, &keypair, passphrase.as_deref())?;
+    return Ok((keypair, passphrase));
+  }
+  let key_file = KeyFile::decode(&fs::read(&key_path)?)?;
+  let passphrase = if key_file.is_encrypted() {
+    let prompt = format!("Passphrase for {}: ", key_path.display());
+    keystore::passphrase(&prompt, false)?
   } else {
-    let mut key_file = File::create(key_path)?;
-    keypair = identity::secp256k1::Keypair::generate();
-    let secret = keypair.secret();
-    // TODO: use asn1_der to store keys
-    // https://github.com/kizzycode/asn1_der
-    key_file.write_all(&secret.to_bytes())?;
-  }
-  Ok(identity::Keypair::Secp256k1(keypair))
+    None
+  };
+  let raw = matches!(key_file, KeyFile::Raw(_));
+  let keypair = key_file.into_keypair(passphrase.as_deref())?;
+  if raw {
+    let passphrase = env_passphrase();
+    write_key(&key_path, &keypair, passphrase.as_deref())?;
+    println!("Converted {} to the DER key format", key_path.display());
+    return Ok((keypair, passphrase));
+  }
+  Ok((keypair, passphrase))
+}
+
+/// Returns the id of the station of this node: the peer id of its first key,
+/// which the station keeps across key rotations.
+pub fn station_id(home_path: &Path, local_peer_id: &PeerId) -> Result<PeerId, Box<dyn std::error::Error>> {
+  match fs::read_to_string(station_id_path(home_path)) {
+    Ok(id) => Ok(id.trim().parse().map_err(|_| "Invalid station id")?),
+    Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(local_peer_id.clone()),
+    Err(e) => Err(e.into()),
+  }
+}
+
+/// Replaces the key of the node with a new key of `key_type`, returning the old
+/// and the new key.
+///
+/// The successor record signed by both keys is saved first, in the home
+/// directory, for the node to publish. The old key file is kept next to the new
+/// one, which is encrypted with the same passphrase. If this node has a station,
+/// the new key replaces the old one among its admins.
+pub fn rotate_key(
+  home_path: &Path,
+  key_type: KeyType,
+) -> Result<(identity::Keypair, identity::Keypair), Box<dyn std::error::Error>> {
+  let key_path = key_path(home_path);
+  if !key_path.exists() {
+    return Err(format!("{} does not exist", key_path.display()).into());
+  }
+  let (old, passphrase) = load_keys(home_path, key_type)?;
+  let new = key_type.generate();
+  let old_id = old.public().into_peer_id();
+  let new_id = new.public().into_peer_id();
+  SignedSuccessor::new(&old, &new)?.save(home_path)?;
+
+  let station_path = home_path.join("station.manifest");
+  if station_path.exists() {
+    let station_id_path = station_id_path(home_path);
+    if !station_id_path.exists() {
+      write_atomic(&station_id_path, format!("{}\n", old_id).as_bytes())?;
+    }
+    let mut manifest = Manifest::load(&station_path)?;
+    if manifest.remove_admin(&old_id) {
+      manifest.add_admin(&new_id);
+      manifest.version += 1;
+      manifest.save(&station_path)?;
+    }
+  }
+
+  let backup = home_path.join(format!(".peer_key.{}", old_id));
+  write_private(&backup, &fs::read(&key_path)?)?;
+  write_key(&key_path, &new, passphrase.as_deref())?;
+  Ok((old, new))
+}
+
+/// Encrypts the key of the node with `passphrase`, or stores it in the clear with `None`.
+pub fn set_passphrase(
+  home_path: &Path,
+  key_type: KeyType,
+  passphrase: Option<&str>,
+) -> Result<identity::Keypair, Box<dyn std::error::Error>> {
+  let keypair = create_keys(home_path, key_type)?;
+  write_key(&key_path(home_path), &keypair, passphrase)?;
+  Ok(keypair)
+}
+
+fn env_passphrase() -> Option<String> {
+  env::var(PASSPHRASE_ENV).ok().filter(|p| !p.is_empty())
+}
+
+/// Writes the key file, readable by the owner only.
+fn write_key(
+  key_path: &Path,
+  keypair: &identity::Keypair,
+  passphrase: Option<&str>,
+) -> io::Result<()> {
+  write_private(key_path, &keystore::encode(keypair, passphrase))
+}
+
+/// Like `write_atomic`, but the temporary file is created readable by the owner
+/// only, so the key is never exposed to other users, even briefly.
+fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+  let tmp_path = path.with_extension("tmp");
+  // The mode only applies to a new file, so a leftover one must go first.
+  if let Err(e) = fs::remove_file(&tmp_path) {
+    if e.kind() != io::ErrorKind::NotFound {
+      return Err(e);
+    }
+  }
+  {
+    let mut file = fs::OpenOptions::new()
+      .write(true)
+      .create_new(true)
+      .mode(0o600)
+      .open(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+  }
+  fs::rename(tmp_path, path)
+}
+
+/// Returns the secret key of the node as hex, the format `import_key` reads.
+pub fn export_key(home_path: &Path, key_type: KeyType) -> Result<String, Box<dyn std::error::Error>> {
+  let keypair = create_keys(home_path, key_type)?;
+  Ok(multihash::to_hex(&keystore::secret_bytes(&keypair)))
+}
+
+/// Replaces the key of the node with a secret key of `key_type` given as hex or as raw bytes.
+///
+/// The key is encrypted if `PASSPHRASE_ENV` is set.
+///
+/// Refuses to overwrite an existing key unless `force` is set, since the peer id,
+/// and with it the station, is lost with the old key.
+pub fn import_key(
+  home_path: &Path,
+  data: &[u8],
+  key_type: KeyType,
+  force: bool,
+) -> Result<identity::Keypair, Box<dyn std::error::Error>> {
+  let key_path = key_path(home_path);
+  if key_path.exists() && !force {
+    return Err(format!("{} already exists", key_path.display()).into());
+  }
+  let text = std::str::from_utf8(data).ok().map(str::trim);
+  let bytes = match text.and_then(from_hex) {
+    Some(bytes) => bytes,
+    None => data.to_vec(),
+  };
+  let keypair = key_type.keypair(bytes)?;
+  write_key(&key_path, &keypair, env_passphrase().as_deref())?;
+  Ok(keypair)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::os::unix::fs::PermissionsExt;
+
+  fn mode(path: &Path) -> u32 {
+    fs::metadata(path).unwrap().permissions().mode() & 0o777
+  }
+
+  #[test]
+  fn key_files_are_private() {
+    let home = env::temp_dir().join(format!("radiopeer-keys-{}", std::process::id()));
+    fs::create_dir_all(&home).unwrap();
+    // A leftover temporary file with loose permissions is not reused.
+    fs::write(key_path(&home).with_extension("tmp"), b"stale").unwrap();
+    let old = create_keys(&home, KeyType::Ed25519).unwrap();
+    assert_eq!(mode(&key_path(&home)), 0o600);
+    let (rotated, _) = rotate_key(&home, KeyType::Ed25519).unwrap();
+    assert_eq!(rotated.public(), old.public());
+    let backup = home.join(format!(".peer_key.{}", old.public().into_peer_id()));
+    assert_eq!(mode(&backup), 0o600);
+    assert_eq!(mode(&key_path(&home)), 0o600);
+    let _ = fs::remove_dir_all(home);
+  }
 }